@@ -14,6 +14,10 @@ export type download_file_response = { 'found_file' : file_data } |
   { 'not_uploaded_file' : null } |
   { 'not_found_file' : null };
 export type error_with_file_upload = { 'not_requested' : null } |
+  { 'invalid_chunk_length' : null } |
+  { 'storage_error' : string } |
+  { 'suspended' : null } |
+  { 'not_authenticated' : null } |
   { 'already_uploaded' : null };
 export interface file_data {
   'contents' : Uint8Array | number[],
@@ -30,14 +34,24 @@ export interface file_metadata {
 export type file_status = { 'partially_uploaded' : null } |
   { 'pending' : { 'alias' : string, 'requested_at' : bigint } } |
   { 'uploaded' : { 'uploaded_at' : bigint } };
+export type register_file_error = {
+    'sui_ownership_error' : sui_ownership_error
+  } |
+  { 'suspended' : null };
 export interface register_file_request {
   'blob_id' : [] | [string],
   'file_name' : string,
+  'sui_object_id' : [] | [string],
   'requested_at' : bigint,
   'storage_provider' : string,
   'uploaded_at' : [] | [bigint],
 }
 export interface register_file_response { 'file_id' : file_id }
+export type sui_ownership_error = { 'blob_mismatch' : null } |
+  { 'owner_not_linked' : null } |
+  { 'not_a_blob' : null } |
+  { 'rpc_error' : string } |
+  { 'missing_sui_object' : null };
 export interface update_user_request {
   'username' : [] | [string],
   'email' : [] | [string],
@@ -94,10 +108,15 @@ export interface _SERVICE {
   'list_users' : ActorMethod<[], user_list_response>,
   'register_file' : ActorMethod<
     [register_file_request],
-    register_file_response
+    { 'Ok' : register_file_response } |
+      { 'Err' : register_file_error }
   >,
   'update_user_profile' : ActorMethod<[update_user_request], user_response>,
-  'upload_file_atomic' : ActorMethod<[upload_file_atomic_request], file_id>,
+  'upload_file_atomic' : ActorMethod<
+    [upload_file_atomic_request],
+    { 'Ok' : file_id } |
+      { 'Err' : error_with_file_upload }
+  >,
   'upload_file_continue' : ActorMethod<
     [upload_file_continue_request],
     upload_file_response
@@ -57,11 +57,23 @@ export const idlFactory = ({ IDL }) => {
   const register_file_request = IDL.Record({
     'blob_id' : IDL.Opt(IDL.Text),
     'file_name' : IDL.Text,
+    'sui_object_id' : IDL.Opt(IDL.Text),
     'requested_at' : IDL.Nat64,
     'storage_provider' : IDL.Text,
     'uploaded_at' : IDL.Opt(IDL.Nat64),
   });
   const register_file_response = IDL.Record({ 'file_id' : file_id });
+  const sui_ownership_error = IDL.Variant({
+    'blob_mismatch' : IDL.Null,
+    'owner_not_linked' : IDL.Null,
+    'not_a_blob' : IDL.Null,
+    'rpc_error' : IDL.Text,
+    'missing_sui_object' : IDL.Null,
+  });
+  const register_file_error = IDL.Variant({
+    'sui_ownership_error' : sui_ownership_error,
+    'suspended' : IDL.Null,
+  });
   const update_user_request = IDL.Record({
     'username' : IDL.Opt(IDL.Text),
     'email' : IDL.Opt(IDL.Text),
@@ -81,6 +93,10 @@ export const idlFactory = ({ IDL }) => {
   });
   const error_with_file_upload = IDL.Variant({
     'not_requested' : IDL.Null,
+    'invalid_chunk_length' : IDL.Null,
+    'storage_error' : IDL.Text,
+    'suspended' : IDL.Null,
+    'not_authenticated' : IDL.Null,
     'already_uploaded' : IDL.Null,
   });
   const upload_file_response = IDL.Variant({
@@ -107,7 +123,12 @@ export const idlFactory = ({ IDL }) => {
     'list_users' : IDL.Func([], [user_list_response], ['query']),
     'register_file' : IDL.Func(
         [register_file_request],
-        [register_file_response],
+        [
+          IDL.Variant({
+            'Ok' : register_file_response,
+            'Err' : register_file_error,
+          }),
+        ],
         [],
       ),
     'update_user_profile' : IDL.Func(
@@ -117,7 +138,12 @@ export const idlFactory = ({ IDL }) => {
       ),
     'upload_file_atomic' : IDL.Func(
         [upload_file_atomic_request],
-        [file_id],
+        [
+          IDL.Variant({
+            'Ok' : file_id,
+            'Err' : error_with_file_upload,
+          }),
+        ],
         [],
       ),
     'upload_file_continue' : IDL.Func(
@@ -1,17 +1,111 @@
+mod account_deletion;
+mod admin;
+mod bucket;
+mod buckets;
+mod data_export;
 mod delete_file;
+mod download_chunks;
 mod download_file;
+mod download_walrus_chunk;
+mod file_index;
+mod file_metadata;
+mod folders;
+mod http_gateway;
+mod list_files;
+mod upload_chunks;
 mod upload_file_atomic;
 mod upload_file_continue;
+mod upload_session;
 mod register_file;
+mod replication;
+mod search;
+mod sui_ownership;
+mod transfers;
+mod trash;
+mod user_canisters;
 mod user_management;
+mod versions;
 
 // use crate::{FileContent, State, UploadFileContinueRequest};
-pub use delete_file::delete_file;
-pub use download_file::download_file;
-pub use upload_file_atomic::{upload_file_atomic, UploadFileAtomicRequest};
-pub use upload_file_continue::upload_file_continue;
+pub use account_deletion::{
+    get_account_deletion, run_account_erasures, AccountDeletionRecord, DeleteAccountMode, ErasureJob,
+    ERASURE_BATCH_FILES,
+};
+pub use admin::{
+    add_admin, force_delete_file, get_config, get_global_stats, list_admins, list_suspensions, reactivate_user,
+    remove_admin, require_admin, suspend_user, update_config, AdminError, CanisterConfig, GlobalStats, Suspension,
+    UpdateConfigRequest,
+};
+pub use bucket::{
+    bucket_delete_chunk, bucket_get_chunk, bucket_put_chunk, bucket_set_readers, BucketError,
+};
+pub use buckets::{
+    apply_init_args, create_bucket, list_buckets, restore_buckets, run_bucket_maintenance, send_bucket_writes,
+    set_bucket_wasm, set_index_canister, Bucket, BucketInfo, BucketWrite,
+};
+pub(crate) use trash::is_trashed;
+pub(crate) use versions::is_staged_version;
+pub use data_export::{
+    export_file_chunks, export_my_data, import_my_data, DataExport, DataExportError, ExportChunkPage, ExportedFile,
+    ImportResult, ImportedFile, EXPORT_PAGE_BYTES,
+};
+pub use delete_file::{delete_file, reclaim_deleted_chunks, DeletingFile, DELETE_BATCH_CHUNKS};
+pub use download_chunks::{
+    download_chunks, file_manifest, ChunkBatch, DownloadChunksResponse, FileManifest,
+    FileManifestResponse, MAX_DOWNLOAD_BATCH_BYTES,
+};
+pub use download_file::{download_file, download_file_certified, CertifiedDownloadResponse};
+pub use download_walrus_chunk::{
+    download_walrus_chunk, set_walrus_aggregator_url, transform_walrus_response, WALRUS_CHUNK_SIZE,
+};
+pub use file_index::{
+    list_files_v2, FileIndex, FileSortKey, FileStatusFilter, ListFilesError, ListFilesPage,
+    ListFilesRequest, SortOrder, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+pub use file_metadata::{
+    list_files_by_tag, update_file_metadata, UpdateFileMetadataError, UpdateFileMetadataRequest,
+};
+pub use folders::{
+    create_folder, delete_folder, list_folder, move_file, move_folder, rename_folder, Folder,
+    FolderError, FolderListing, FolderLocator, FOLDER_PAGE_SIZE,
+};
+pub use http_gateway::{
+    create_download_link, http_request, http_request_streaming_callback, revoke_download_link,
+    DownloadLinkError, GatewayRequest, GatewayResponse, StreamingCallbackResponse,
+    StreamingCallbackToken,
+};
+pub use list_files::{list_files, list_files_certified, CertifiedFileList};
+pub use upload_chunks::{upload_chunks, upload_chunks_and_store, ChunkUploadResult};
+pub use upload_file_atomic::{upload_file_atomic, upload_file_atomic_and_store, UploadFileAtomicRequest};
+pub use upload_file_continue::{upload_file_continue, upload_file_continue_and_store};
+pub use upload_session::{
+    begin_upload, finalize_upload, BeginUploadRequest, FinalizeUploadRequest, UploadSession,
+    UploadSessionError, MAX_UPLOAD_SIZE, UPLOAD_CHUNK_SIZE,
+};
 pub use crate::api::delete_file::DeleteFileResult;
-pub use register_file::{register_file, RegisterFileRequest, RegisterFileResponse};
+pub use register_file::{register_file, RegisterFileError, RegisterFileRequest, RegisterFileResponse};
+pub use replication::{
+    add_walrus_replica, get_replication_status, run_replication_verifier, set_replication_policy,
+    Replica, ReplicaHealth, ReplicationError, ReplicationInfo, ReplicationPolicy, SetReplicationPolicyRequest,
+};
+pub use search::{search_files, SearchError, SearchIndex, SearchResults, MAX_QUERY_TERMS, SEARCH_PAGE_SIZE};
+pub use sui_ownership::{
+    link_sui_address, set_sui_rpc_url, sui_link_message, transform_sui_response,
+    LinkSuiAddressRequest, SuiLinkError, SuiOwnershipError,
+};
+pub use transfers::{
+    create_transfer, delete_transfer, download_transfer_file, get_transfer, list_transfers, revoke_expired_transfers,
+    CreateTransferRequest, Transfer, TransferError, TransferList,
+};
+pub use trash::{
+    empty_trash, list_trash, purge_expired_trash, restore_file, TrashError, TrashedFile,
+    TRASH_PURGE_BATCH, TRASH_RETENTION_NANOS,
+};
+pub use user_canisters::{
+    allow_dedicated_storage, create_user_profile_with_storage, export_user_canister,
+    delete_removed_canisters, provision_user_canister, restore_user_canisters, top_up_user_canister, UserCanisterError,
+    MAX_USER_CANISTERS,
+};
 pub use user_management::{
     create_user_profile,
     get_user_profile,
@@ -20,3 +114,7 @@ pub use user_management::{
     list_users,
     get_user_stats,
 };
+pub use versions::{
+    begin_version_upload, download_file_version, list_versions, set_version_retention,
+    FileVersion, FileVersions, VersionError, DEFAULT_MAX_OLD_VERSIONS,
+};
@@ -0,0 +1,256 @@
+use crate::State;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Erasing an account can touch thousands of files, more than one message can
+// delete. `delete_user_profile` removes the profile at once and, when asked to
+// erase everything, snapshots what the user owns into a job that a timer works
+// through in batches. Each account keeps a deletion record, completed when the
+// job is done, that the principal can query afterwards.
+
+/// How many files a single run of an erasure job deletes.
+pub const ERASURE_BATCH_FILES: usize = 20;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteAccountMode {
+    /// Only the profile is removed; files, transfers and folders stay.
+    #[serde(rename = "keep_files")]
+    KeepFiles,
+    /// Files (including the trash and old versions), sent transfers, access to
+    /// received transfers, folders, the dedicated canister and linked Sui addresses
+    /// are erased as well.
+    #[serde(rename = "erase_everything")]
+    EraseEverything,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountDeletionRecord {
+    pub principal: Principal,
+    pub mode: DeleteAccountMode,
+    pub requested_at: u64,
+    // Set once everything has been erased.
+    pub completed_at: Option<u64>,
+    pub files_deleted: u64,
+    pub transfers_deleted: u64,
+}
+
+// What is left to erase, snapshot when the deletion was requested so files
+// uploaded by the same principal afterwards are kept.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ErasureJob {
+    files: Vec<u64>,
+    transfers: Vec<u64>,
+}
+
+/// Records the deletion of `principal`'s account and, for `EraseEverything`,
+/// starts erasing their data. The profile itself is removed by the caller.
+pub(crate) fn start_account_deletion(state: &mut State, principal: Principal, mode: DeleteAccountMode) {
+    let now = crate::get_time();
+    let mut record = AccountDeletionRecord {
+        principal,
+        mode,
+        requested_at: now,
+        completed_at: None,
+        files_deleted: 0,
+        transfers_deleted: 0,
+    };
+    if mode == DeleteAccountMode::KeepFiles {
+        record.completed_at = Some(now);
+        state.account_deletions.insert(principal, record);
+        return;
+    }
+
+    let mut job = state.erasure_jobs.remove(&principal).unwrap_or_default();
+    job.files.extend(state.file_owners.get(&principal).into_iter().flatten());
+    job.files.extend(state.trash.get(&principal).into_iter().flat_map(|files| files.keys()));
+    job.transfers.extend(
+        state
+            .transfers
+            .values()
+            .filter(|transfer| transfer.sender == principal)
+            .map(|transfer| transfer.transfer_id),
+    );
+    job.files.sort_unstable();
+    job.files.dedup();
+    job.transfers.sort_unstable();
+    job.transfers.dedup();
+
+    state.account_deletions.insert(principal, record);
+    state.erasure_jobs.insert(principal, job);
+    run_erasure(state, principal);
+}
+
+// Deletes one batch of the principal's data. Whatever is cheap is done in the
+// final step, once all files are gone.
+fn run_erasure(state: &mut State, principal: Principal) {
+    let Some(mut job) = state.erasure_jobs.remove(&principal) else {
+        return;
+    };
+    let split = job.files.len().saturating_sub(ERASURE_BATCH_FILES);
+    let batch = job.files.split_off(split);
+    let mut files_deleted = 0;
+    for file_id in batch {
+        if state.file_data.contains_key(&file_id) {
+            crate::api::delete_file::purge_file(state, file_id);
+            files_deleted += 1;
+        }
+    }
+    if !job.files.is_empty() {
+        state.erasure_jobs.insert(principal, job);
+        if let Some(record) = state.account_deletions.get_mut(&principal) {
+            record.files_deleted += files_deleted;
+        }
+        return;
+    }
+
+    // Transfers sent: their files were deleted above.
+    let mut transfers_deleted = 0;
+    for transfer_id in job.transfers {
+        if let Some(transfer) = state.transfers.remove(&transfer_id) {
+            if let Some(expires_at) = transfer.expires_at {
+                state.transfer_expiry.remove(&(expires_at, transfer_id));
+            }
+            for file_id in transfer.file_ids {
+                state.file_transfers.remove(&file_id);
+            }
+            transfers_deleted += 1;
+        }
+    }
+    // Transfers received: drop the principal from their recipients.
+    let received: Vec<u64> = state
+        .transfers
+        .values_mut()
+        .filter(|transfer| transfer.recipients.contains(&principal))
+        .map(|transfer| {
+            transfer.recipients.retain(|&r| r != principal);
+            transfer.transfer_id
+        })
+        .collect();
+    for transfer_id in received {
+        crate::api::transfers::refresh_bucket_readers(state, transfer_id, crate::get_time());
+        let file_ids = state.transfers[&transfer_id].file_ids.clone();
+        for file_id in file_ids {
+            crate::api::search::index_search(state, file_id);
+            crate::certification::certify_file(state, file_id);
+        }
+    }
+    remove_folders(state, principal);
+    files_deleted += crate::api::user_canisters::remove_user_canister(state, principal);
+    state.sui_addresses.remove(&principal);
+    if state.file_owners.get(&principal).map_or(false, |files| files.is_empty()) {
+        state.file_owners.remove(&principal);
+    }
+
+    if let Some(record) = state.account_deletions.get_mut(&principal) {
+        record.files_deleted += files_deleted;
+        record.transfers_deleted += transfers_deleted;
+        record.completed_at = Some(crate::get_time());
+    }
+}
+
+fn remove_folders(state: &mut State, principal: Principal) {
+    let parents: Vec<(Principal, Option<u64>)> = state
+        .subfolders
+        .range((principal, None)..)
+        .take_while(|((owner, _), _)| *owner == principal)
+        .map(|(key, _)| *key)
+        .collect();
+    for key in parents {
+        for folder_id in state.subfolders.remove(&key).unwrap_or_default() {
+            state.folders.remove(&folder_id);
+            for file_id in state.folder_files.remove(&folder_id).unwrap_or_default() {
+                state.file_folders.remove(&file_id);
+            }
+        }
+    }
+}
+
+/// Works through one batch of every pending erasure. Run by a timer.
+pub fn run_account_erasures(state: &mut State) {
+    let principals: Vec<Principal> = state.erasure_jobs.keys().copied().collect();
+    for principal in principals {
+        run_erasure(state, principal);
+    }
+}
+
+/// The record of the caller's account deletion, if they deleted their account.
+pub fn get_account_deletion(caller: Principal, state: &State) -> Option<AccountDeletionRecord> {
+    state.account_deletions.get(&caller).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{
+        create_folder, create_transfer, delete_file, list_files, list_trash, move_file, upload_file_atomic,
+        CreateTransferRequest, UploadFileAtomicRequest,
+    };
+
+    fn upload(state: &mut State, owner: Principal) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "notes.txt".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "text/plain".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    fn transfer(state: &mut State, sender: Principal, recipient: Principal, file_id: u64) -> u64 {
+        let request = CreateTransferRequest {
+            title: "Photos".to_string(),
+            message: "".to_string(),
+            recipients: vec![recipient],
+            file_ids: vec![file_id],
+            expires_at: None,
+        };
+        create_transfer(sender, request, state).unwrap().transfer_id
+    }
+
+    #[test]
+    fn erasing_an_account_removes_its_data_in_batches() {
+        let mut state = State::default();
+        let user = Principal::from_slice(&[3]);
+        let friend = Principal::from_slice(&[1]);
+        let folder = create_folder(user, "docs".to_string(), None, &mut state).unwrap();
+        let files: Vec<u64> = (0..ERASURE_BATCH_FILES + 5).map(|_| upload(&mut state, user)).collect();
+        move_file(user, files[0], Some(folder.folder_id), &mut state).unwrap();
+        delete_file(&mut state, user, files[1]);
+        let sent = transfer(&mut state, user, friend, files[2]);
+        let friends_file = upload(&mut state, friend);
+        let received = transfer(&mut state, friend, user, friends_file);
+
+        start_account_deletion(&mut state, user, DeleteAccountMode::EraseEverything);
+        let record = get_account_deletion(user, &state).unwrap();
+        assert_eq!(record.files_deleted, ERASURE_BATCH_FILES as u64);
+        assert_eq!(record.completed_at, None);
+
+        run_account_erasures(&mut state);
+        let record = get_account_deletion(user, &state).unwrap();
+        assert_eq!(record.files_deleted, files.len() as u64);
+        assert_eq!(record.transfers_deleted, 1);
+        assert!(record.completed_at.is_some());
+
+        assert!(list_files(user, &state).is_empty());
+        assert!(list_trash(user, &state).is_empty());
+        assert!(state.folders.is_empty());
+        assert!(!state.transfers.contains_key(&sent));
+        assert!(state.transfers[&received].recipients.is_empty());
+        assert_eq!(list_files(friend, &state).len(), 1);
+        assert!(state.erasure_jobs.is_empty());
+    }
+
+    #[test]
+    fn keeping_files_only_records_the_deletion() {
+        let mut state = State::default();
+        let user = Principal::from_slice(&[3]);
+        upload(&mut state, user);
+
+        start_account_deletion(&mut state, user, DeleteAccountMode::KeepFiles);
+        assert!(get_account_deletion(user, &state).unwrap().completed_at.is_some());
+        assert_eq!(list_files(user, &state).len(), 1);
+    }
+}
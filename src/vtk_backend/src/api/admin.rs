@@ -0,0 +1,463 @@
+use crate::State;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Admins manage the deployment: they see every user, can suspend accounts,
+// change the configuration and delete any file. The canister's controllers are
+// always admins; others come from the `admins` init arg or `add_admin`, and are
+// kept in stable memory. Every admin endpoint goes through `require_admin`.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminError {
+    #[serde(rename = "not_authorized")]
+    NotAuthorized,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "invalid_input")]
+    InvalidInput(String),
+    #[serde(rename = "call_failed")]
+    CallFailed(String),
+}
+
+/// The authorization check behind every admin endpoint. `is_controller` tells
+/// whether the caller controls this canister, which only the endpoint can ask.
+pub fn require_admin(caller: Principal, is_controller: bool, state: &State) -> Result<(), AdminError> {
+    if caller != Principal::anonymous() && (is_controller || state.admins.contains_key(&caller)) {
+        Ok(())
+    } else {
+        Err(AdminError::NotAuthorized)
+    }
+}
+
+/// Replaces the admin set, from the `admins` init arg.
+pub(crate) fn set_admins(state: &mut State, admins: Vec<Principal>) {
+    let previous: Vec<Principal> = state.admins.iter().map(|(admin, _)| admin).collect();
+    for admin in previous {
+        state.admins.remove(&admin);
+    }
+    for admin in admins.into_iter().filter(|&a| a != Principal::anonymous()) {
+        state.admins.insert(admin, ());
+    }
+}
+
+pub fn list_admins(
+    caller: Principal,
+    is_controller: bool,
+    state: &State,
+) -> Result<Vec<Principal>, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    Ok(state.admins.iter().map(|(admin, _)| admin).collect())
+}
+
+pub fn add_admin(
+    caller: Principal,
+    is_controller: bool,
+    admin: Principal,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    if admin == Principal::anonymous() {
+        return Err(AdminError::InvalidInput("the anonymous principal can't be an admin".to_string()));
+    }
+    state.admins.insert(admin, ());
+    Ok(())
+}
+
+/// Removes an admin. The last admin can't be removed.
+pub fn remove_admin(
+    caller: Principal,
+    is_controller: bool,
+    admin: Principal,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    if !state.admins.contains_key(&admin) {
+        return Err(AdminError::NotFound);
+    }
+    if state.admins.len() == 1 {
+        return Err(AdminError::InvalidInput("can't remove the last admin".to_string()));
+    }
+    state.admins.remove(&admin);
+    Ok(())
+}
+
+const MAX_SUSPENSION_REASON_LEN: usize = 500;
+
+/// Why and when an admin suspended an account. Kept apart from the profile, so
+/// deleting the profile doesn't lift it and principals without one can be suspended;
+/// a profile shows a copy of it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Suspension {
+    pub suspended_at: u64,
+    pub reason: String,
+}
+
+/// Suspended users can't upload, register files, share them or create a profile or
+/// storage canister, and can't download either when `block_suspended_downloads` is
+/// set. Their files are kept.
+pub(crate) fn is_suspended(state: &State, principal: Principal) -> bool {
+    state.suspensions.contains_key(&principal)
+}
+
+pub(crate) fn downloads_blocked(state: &State, principal: Principal) -> bool {
+    state.block_suspended_downloads && is_suspended(state, principal)
+}
+
+pub fn suspend_user(
+    caller: Principal,
+    is_controller: bool,
+    user: Principal,
+    reason: String,
+    state: &mut State,
+) -> Result<Suspension, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_SUSPENSION_REASON_LEN {
+        return Err(AdminError::InvalidInput("the reason must be 1-500 characters".to_string()));
+    }
+    let suspension = Suspension {
+        suspended_at: crate::get_time(),
+        reason,
+    };
+    state.suspensions.insert(user, suspension.clone());
+    if let Some(profile) = state.user_profiles.get_mut(&user) {
+        profile.is_active = false;
+        profile.suspended_at = Some(suspension.suspended_at);
+        profile.suspension_reason = Some(suspension.reason.clone());
+    }
+    Ok(suspension)
+}
+
+pub fn reactivate_user(
+    caller: Principal,
+    is_controller: bool,
+    user: Principal,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    state.suspensions.remove(&user).ok_or(AdminError::NotFound)?;
+    if let Some(profile) = state.user_profiles.get_mut(&user) {
+        profile.is_active = true;
+        profile.suspended_at = None;
+        profile.suspension_reason = None;
+    }
+    Ok(())
+}
+
+/// Every suspended principal, with or without a profile.
+pub fn list_suspensions(
+    caller: Principal,
+    is_controller: bool,
+    state: &State,
+) -> Result<Vec<(Principal, Suspension)>, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    Ok(state.suspensions.iter().map(|(&user, suspension)| (user, suspension.clone())).collect())
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GlobalStats {
+    pub user_count: u64,
+    pub file_count: u64,
+    // Bytes of file chunks, on this canister and in buckets.
+    pub stored_bytes: u64,
+    pub trashed_files: u64,
+    // Deleted files whose chunks are still being freed.
+    pub files_being_deleted: u64,
+    pub transfer_count: u64,
+    pub folder_count: u64,
+    pub bucket_count: u64,
+    pub pending_account_erasures: u64,
+}
+
+pub fn get_global_stats(
+    caller: Principal,
+    is_controller: bool,
+    state: &State,
+) -> Result<GlobalStats, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    Ok(GlobalStats {
+        user_count: state.user_profiles.len() as u64,
+        file_count: state.file_data.len() as u64,
+        stored_bytes: state
+            .file_data
+            .keys()
+            .map(|&file_id| state.chunks_for(file_id).size(state.storage_id(file_id)))
+            .sum(),
+        trashed_files: state.trash.values().map(|files| files.len() as u64).sum(),
+        files_being_deleted: state.deleting.len() as u64,
+        transfer_count: state.transfers.len() as u64,
+        folder_count: state.folders.len() as u64,
+        bucket_count: state.buckets.len() as u64,
+        pending_account_erasures: state.erasure_jobs.len() as u64,
+    })
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CanisterConfig {
+    pub walrus_aggregator_url: String,
+    pub sui_rpc_url: Option<String>,
+    pub bucket_capacity_bytes: u64,
+    pub block_suspended_downloads: bool,
+}
+
+/// Fields left as `None` keep their value. `sui_rpc_url = Some(None)` turns Sui checks off.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct UpdateConfigRequest {
+    pub walrus_aggregator_url: Option<String>,
+    pub sui_rpc_url: Option<Option<String>>,
+    pub bucket_capacity_bytes: Option<u64>,
+    pub block_suspended_downloads: Option<bool>,
+}
+
+fn config(state: &State) -> CanisterConfig {
+    CanisterConfig {
+        walrus_aggregator_url: state.walrus_aggregator_url.clone(),
+        sui_rpc_url: state.sui_rpc_url.clone(),
+        bucket_capacity_bytes: state.bucket_capacity_bytes,
+        block_suspended_downloads: state.block_suspended_downloads,
+    }
+}
+
+pub fn get_config(
+    caller: Principal,
+    is_controller: bool,
+    state: &State,
+) -> Result<CanisterConfig, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    Ok(config(state))
+}
+
+/// Updates the configuration. Nothing changes if any field is invalid.
+pub fn update_config(
+    caller: Principal,
+    is_controller: bool,
+    request: UpdateConfigRequest,
+    state: &mut State,
+) -> Result<CanisterConfig, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    let urls = [
+        request.walrus_aggregator_url.as_ref(),
+        request.sui_rpc_url.as_ref().and_then(|url| url.as_ref()),
+    ];
+    if urls.into_iter().flatten().any(|url| !url.trim().starts_with("https://")) {
+        return Err(AdminError::InvalidInput("urls must use https".to_string()));
+    }
+    if request.bucket_capacity_bytes == Some(0) {
+        return Err(AdminError::InvalidInput("bucket capacity must be positive".to_string()));
+    }
+
+    if let Some(url) = request.walrus_aggregator_url {
+        crate::api::set_walrus_aggregator_url(url, state).map_err(AdminError::InvalidInput)?;
+    }
+    if let Some(url) = request.sui_rpc_url {
+        crate::api::set_sui_rpc_url(url, state).map_err(AdminError::InvalidInput)?;
+    }
+    if let Some(capacity) = request.bucket_capacity_bytes {
+        state.bucket_capacity_bytes = capacity;
+    }
+    if let Some(block) = request.block_suspended_downloads {
+        state.block_suspended_downloads = block;
+    }
+    Ok(config(state))
+}
+
+/// Permanently deletes any file, skipping the owner's trash.
+pub fn force_delete_file(
+    caller: Principal,
+    is_controller: bool,
+    file_id: u64,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    if !state.file_data.contains_key(&file_id) {
+        return Err(AdminError::NotFound);
+    }
+    crate::api::delete_file::purge_file(state, file_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{create_user_profile, list_files, list_users, upload_file_atomic, UploadFileAtomicRequest};
+    use crate::{CreateUserRequest, UserListResponse};
+
+    fn admin_state() -> (State, Principal) {
+        let mut state = State::default();
+        let admin = Principal::from_slice(&[7]);
+        set_admins(&mut state, vec![admin, Principal::anonymous()]);
+        (state, admin)
+    }
+
+    #[test]
+    fn only_admins_pass_the_check() {
+        let (mut state, admin) = admin_state();
+        let user = Principal::from_slice(&[1]);
+        assert_eq!(require_admin(admin, false, &state), Ok(()));
+        assert_eq!(require_admin(user, false, &state), Err(AdminError::NotAuthorized));
+        assert_eq!(require_admin(Principal::anonymous(), false, &state), Err(AdminError::NotAuthorized));
+        // Controllers pass without being admins.
+        assert_eq!(require_admin(user, true, &state), Ok(()));
+        assert_eq!(list_admins(user, true, &state), Ok(vec![admin]));
+        assert_eq!(list_users(user, false, &state), UserListResponse::NotAuthorized);
+
+        assert_eq!(add_admin(user, false, user, &mut state), Err(AdminError::NotAuthorized));
+        add_admin(admin, false, user, &mut state).unwrap();
+        remove_admin(user, false, admin, &mut state).unwrap();
+        assert!(matches!(remove_admin(user, false, user, &mut state), Err(AdminError::InvalidInput(_))));
+        assert_eq!(list_admins(user, false, &state), Ok(vec![user]));
+        // A new state reads the admins back from stable memory, as after an upgrade.
+        assert_eq!(list_admins(user, false, &State::default()), Ok(vec![user]));
+    }
+
+    #[test]
+    fn admins_manage_users_and_files() {
+        let (mut state, admin) = admin_state();
+        let user = Principal::from_slice(&[1]);
+        let request = CreateUserRequest {
+            username: "alice".to_string(),
+            display_name: None,
+            email: None,
+            dedicated_storage: None,
+        };
+        create_user_profile(user, request, &mut state);
+        let file_id = upload_file_atomic(
+            user,
+            UploadFileAtomicRequest {
+                name: "leak.txt".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "text/plain".to_string(),
+                num_chunks: 1,
+            },
+            &mut state,
+        ).unwrap();
+
+        assert!(matches!(list_users(admin, false, &state), UserListResponse::Ok(users) if users.len() == 1));
+        let suspension = suspend_user(admin, false, user, "spam".to_string(), &mut state).unwrap();
+        assert_eq!(suspension.reason, "spam");
+        let profile = &state.user_profiles[&user];
+        assert!(!profile.is_active);
+        assert_eq!(profile.suspension_reason, Some("spam".to_string()));
+        assert_eq!(profile.suspended_at, Some(suspension.suspended_at));
+        assert_eq!(list_suspensions(admin, false, &state), Ok(vec![(user, suspension)]));
+        reactivate_user(admin, false, user, &mut state).unwrap();
+        let profile = &state.user_profiles[&user];
+        assert!(profile.is_active);
+        assert_eq!((profile.suspended_at, profile.suspension_reason.clone()), (None, None));
+        assert_eq!(reactivate_user(admin, false, user, &mut state), Err(AdminError::NotFound));
+
+        let stats = get_global_stats(admin, false, &state).unwrap();
+        assert_eq!((stats.user_count, stats.file_count, stats.stored_bytes), (1, 1, 3));
+
+        assert_eq!(force_delete_file(user, false, file_id, &mut state), Err(AdminError::NotAuthorized));
+        assert_eq!(force_delete_file(admin, false, file_id, &mut state), Ok(()));
+        assert!(list_files(user, &state).is_empty());
+        assert!(state.trash.is_empty());
+    }
+
+    #[test]
+    fn suspended_users_cannot_upload_or_share() {
+        let (mut state, admin) = admin_state();
+        let user = Principal::from_slice(&[1]);
+        let request = CreateUserRequest {
+            username: "mallory".to_string(),
+            display_name: None,
+            email: None,
+            dedicated_storage: None,
+        };
+        create_user_profile(user, request, &mut state);
+        let file_id = upload_file_atomic(
+            user,
+            UploadFileAtomicRequest {
+                name: "a.bin".to_string(),
+                content: vec![1],
+                file_type: "application/octet-stream".to_string(),
+                num_chunks: 2,
+            },
+            &mut state,
+        ).unwrap();
+        suspend_user(admin, false, user, "abuse report".to_string(), &mut state).unwrap();
+
+        let chunk = crate::UploadFileContinueRequest {
+            file_id,
+            chunk_id: 1,
+            contents: vec![2],
+        };
+        assert!(matches!(
+            crate::api::upload_file_continue(user, chunk.clone(), &mut state),
+            Err(crate::UploadFileError::Suspended)
+        ));
+        let transfer = crate::api::CreateTransferRequest {
+            title: "x".to_string(),
+            message: "".to_string(),
+            recipients: vec![],
+            file_ids: vec![file_id],
+            expires_at: None,
+        };
+        assert_eq!(
+            crate::api::create_transfer(user, transfer, &mut state),
+            Err(crate::api::TransferError::Suspended)
+        );
+
+        assert!(matches!(crate::api::download_file(&state, user, file_id, 0), crate::FileDownloadResponse::NotUploadedFile));
+        state.block_suspended_downloads = true;
+        assert_eq!(crate::api::download_file(&state, user, file_id, 0), crate::FileDownloadResponse::Suspended);
+
+        reactivate_user(admin, false, user, &mut state).unwrap();
+        assert!(crate::api::upload_file_continue(user, chunk, &mut state).is_ok());
+    }
+
+    #[test]
+    fn suspension_outlives_the_profile() {
+        let (mut state, admin) = admin_state();
+        let user = Principal::from_slice(&[1]);
+        let request = CreateUserRequest {
+            username: "mallory".to_string(),
+            display_name: None,
+            email: None,
+            dedicated_storage: None,
+        };
+        create_user_profile(user, request.clone(), &mut state);
+        suspend_user(admin, false, user, "abuse report".to_string(), &mut state).unwrap();
+
+        crate::api::delete_user_profile(user, None, &mut state);
+        assert!(is_suspended(&state, user));
+        assert_eq!(create_user_profile(user, request, &mut state), crate::UserResponse::Suspended);
+
+        // Principals that never had a profile can be suspended too.
+        let stranger = Principal::from_slice(&[2]);
+        suspend_user(admin, false, stranger, "spam".to_string(), &mut state).unwrap();
+        let upload = UploadFileAtomicRequest {
+            name: "spam.txt".to_string(),
+            content: vec![1],
+            file_type: "text/plain".to_string(),
+            num_chunks: 1,
+        };
+        assert!(matches!(
+            upload_file_atomic(stranger, upload, &mut state),
+            Err(crate::UploadFileError::Suspended)
+        ));
+    }
+
+    #[test]
+    fn invalid_config_changes_nothing() {
+        let (mut state, admin) = admin_state();
+        let before = get_config(admin, false, &state).unwrap();
+        let request = UpdateConfigRequest {
+            walrus_aggregator_url: Some("https://aggregator.example".to_string()),
+            sui_rpc_url: Some(Some("http://insecure.example".to_string())),
+            ..Default::default()
+        };
+        assert!(matches!(update_config(admin, false, request, &mut state), Err(AdminError::InvalidInput(_))));
+        assert_eq!(get_config(admin, false, &state), Ok(before));
+
+        let request = UpdateConfigRequest {
+            bucket_capacity_bytes: Some(42),
+            sui_rpc_url: Some(None),
+            ..Default::default()
+        };
+        let config = update_config(admin, false, request, &mut state).unwrap();
+        assert_eq!(config.bucket_capacity_bytes, 42);
+        assert_eq!(config.sui_rpc_url, None);
+    }
+}
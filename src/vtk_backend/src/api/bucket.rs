@@ -0,0 +1,117 @@
+use crate::State;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Endpoints a canister exposes when it serves as the remote chunk store of an
+// index canister (see `RemoteBucketStore`). Only the index may change them;
+// chunks can also be read by the principals the index lists for the file.
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum BucketError {
+    #[serde(rename = "not_index_canister")]
+    NotIndexCanister,
+}
+
+fn check_index(caller: Principal, state: &State) -> Result<(), BucketError> {
+    match state.index_canister {
+        Some(index) if index == caller => Ok(()),
+        _ => Err(BucketError::NotIndexCanister),
+    }
+}
+
+pub fn bucket_put_chunk(
+    caller: Principal,
+    file_id: u64,
+    chunk_id: u64,
+    contents: Vec<u8>,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_index(caller, state)?;
+    state.file_contents.put(file_id, chunk_id, contents);
+    Ok(())
+}
+
+pub fn bucket_delete_chunk(
+    caller: Principal,
+    file_id: u64,
+    chunk_id: u64,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_index(caller, state)?;
+    state.file_contents.delete(file_id, chunk_id);
+    Ok(())
+}
+
+pub fn bucket_set_readers(
+    caller: Principal,
+    file_id: u64,
+    readers: Vec<Principal>,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_index(caller, state)?;
+    if readers.is_empty() {
+        state.bucket_readers.remove(&file_id);
+    } else {
+        state.bucket_readers.insert(file_id, readers);
+    }
+    Ok(())
+}
+
+pub fn bucket_get_chunk(
+    caller: Principal,
+    file_id: u64,
+    chunk_id: u64,
+    state: &State,
+) -> Result<Option<Vec<u8>>, BucketError> {
+    let is_reader = state
+        .bucket_readers
+        .get(&file_id)
+        .map_or(false, |readers| readers.contains(&caller));
+    if !is_reader {
+        check_index(caller, state)?;
+    }
+    Ok(state.file_contents.get(file_id, chunk_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk_store::HeapChunkStore;
+
+    #[test]
+    fn only_index_can_store_chunks() {
+        let mut state = State::with_chunk_store(Box::new(HeapChunkStore::default()));
+        let index = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        state.index_canister = Some(index);
+
+        assert_eq!(
+            bucket_put_chunk(other, 0, 0, vec![1], &mut state),
+            Err(BucketError::NotIndexCanister)
+        );
+        assert_eq!(bucket_put_chunk(index, 0, 0, vec![1, 2], &mut state), Ok(()));
+        assert_eq!(bucket_get_chunk(index, 0, 0, &state), Ok(Some(vec![1, 2])));
+        assert_eq!(bucket_get_chunk(other, 0, 0, &state), Err(BucketError::NotIndexCanister));
+
+        assert_eq!(bucket_delete_chunk(index, 0, 0, &mut state), Ok(()));
+        assert_eq!(bucket_get_chunk(index, 0, 0, &state), Ok(None));
+    }
+
+    #[test]
+    fn readers_listed_by_index_can_fetch_chunks() {
+        let mut state = State::with_chunk_store(Box::new(HeapChunkStore::default()));
+        let index = Principal::from_slice(&[1]);
+        let owner = Principal::from_slice(&[2]);
+        state.index_canister = Some(index);
+        bucket_put_chunk(index, 7, 0, vec![1, 2], &mut state).unwrap();
+
+        assert_eq!(bucket_set_readers(owner, 7, vec![owner], &mut state), Err(BucketError::NotIndexCanister));
+        assert_eq!(bucket_get_chunk(owner, 7, 0, &state), Err(BucketError::NotIndexCanister));
+
+        bucket_set_readers(index, 7, vec![owner], &mut state).unwrap();
+        assert_eq!(bucket_get_chunk(owner, 7, 0, &state), Ok(Some(vec![1, 2])));
+
+        bucket_set_readers(index, 7, vec![], &mut state).unwrap();
+        assert_eq!(bucket_get_chunk(owner, 7, 0, &state), Err(BucketError::NotIndexCanister));
+    }
+}
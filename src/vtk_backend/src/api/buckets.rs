@@ -0,0 +1,487 @@
+use crate::api::{require_admin, AdminError, BucketError};
+use crate::chunk_store::RemoteBucketStore;
+use crate::{with_state, with_state_mut, InitArgs, State};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::management_canister::main::{
+    canister_status, create_canister, deposit_cycles, install_code, CanisterIdRecord,
+    CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+// A new bucket is created once no bucket has this much room left.
+const BUCKET_MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+const BUCKET_INITIAL_CYCLES: u128 = 2_000_000_000_000;
+const BUCKET_MIN_CYCLES: u128 = 500_000_000_000;
+const BUCKET_TOP_UP_CYCLES: u128 = 1_000_000_000_000;
+
+thread_local! {
+    static CREATING_BUCKET: Cell<bool> = Cell::new(false);
+    // Reader lists that couldn't be sent to their bucket, by (bucket, file_id).
+    static PENDING_READERS: RefCell<BTreeMap<(Principal, u64), Vec<Principal>>> = RefCell::new(BTreeMap::new());
+}
+
+// Clears `CREATING_BUCKET` once `create_bucket` is done, including when a
+// management canister callback traps and ic-cdk drops the pending future.
+struct CreatingBucketGuard;
+
+impl CreatingBucketGuard {
+    fn acquire() -> Option<Self> {
+        (!CREATING_BUCKET.with(|creating| creating.replace(true))).then_some(CreatingBucketGuard)
+    }
+}
+
+impl Drop for CreatingBucketGuard {
+    fn drop(&mut self) {
+        CREATING_BUCKET.with(|creating| creating.set(false));
+    }
+}
+
+/// A bucket canister as seen from the index.
+pub struct Bucket {
+    pub created_at: u64,
+    /// Set for a user's dedicated canister, which never receives other users' files.
+    pub dedicated_to: Option<Principal>,
+    // Bytes set aside for each file placed in the bucket.
+    pub reservations: BTreeMap<u64, u64>,
+    pub store: RemoteBucketStore,
+}
+
+impl Bucket {
+    pub fn new(canister_id: Principal, dedicated_to: Option<Principal>) -> Self {
+        Self {
+            created_at: crate::get_time(),
+            dedicated_to,
+            reservations: BTreeMap::new(),
+            store: RemoteBucketStore::new(canister_id),
+        }
+    }
+
+    pub fn reserved_bytes(&self) -> u64 {
+        self.reservations.values().sum()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BucketInfo {
+    pub canister_id: Principal,
+    pub created_at: u64,
+    pub dedicated_to: Option<Principal>,
+    pub file_count: u64,
+    pub reserved_bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+/// Applies the install arguments. A canister installed with an index becomes one of its buckets.
+pub fn apply_init_args(args: InitArgs, state: &mut State) {
+    if let Some(index) = args.index_canister {
+        state.index_canister = Some(index);
+    }
+    if let Some(capacity) = args.bucket_capacity_bytes {
+        state.bucket_capacity_bytes = capacity;
+    }
+    if let Some(admins) = args.admins {
+        crate::api::admin::set_admins(state, admins);
+    }
+    if let Some(dedup) = args.dedup_chunks {
+        if !state.set_chunk_dedup(dedup) {
+            ic_cdk::println!("dedup_chunks ignored: the canister already holds chunks");
+        }
+    }
+}
+
+/// Picks where `owner`'s new file of `size` bytes goes: their dedicated canister if
+/// they have one, otherwise a shared bucket with room, or `None` to keep it on the index.
+pub(crate) fn pick_bucket(state: &State, owner: Principal, size: u64) -> Option<Principal> {
+    if let Some(dedicated) = crate::api::user_canisters::dedicated_canister(state, owner) {
+        return Some(dedicated);
+    }
+    state
+        .buckets
+        .iter()
+        .filter(|(_, bucket)| bucket.dedicated_to.is_none())
+        .find(|(_, bucket)| bucket.reserved_bytes().saturating_add(size) <= state.bucket_capacity_bytes)
+        .map(|(&canister_id, _)| canister_id)
+}
+
+/// Adds a bucket to the index, and to stable memory so it survives upgrades.
+pub(crate) fn register_bucket(state: &mut State, canister_id: Principal, dedicated_to: Option<Principal>) {
+    let bucket = Bucket::new(canister_id, dedicated_to);
+    state.bucket_registry.insert(canister_id, bucket.created_at);
+    state.buckets.insert(canister_id, bucket);
+}
+
+/// Removes a bucket from the index and returns it, with the files it held.
+pub(crate) fn unregister_bucket(state: &mut State, canister_id: Principal) -> Option<Bucket> {
+    state.bucket_registry.remove(&canister_id);
+    let bucket = state.buckets.remove(&canister_id)?;
+    for &file_id in bucket.reservations.keys() {
+        state.bucket_reservations.remove(&(canister_id, file_id));
+    }
+    Some(bucket)
+}
+
+/// Registers the buckets recorded in stable memory again, with their reservations.
+/// Dedicated canisters are marked as such by `restore_user_canisters` afterwards.
+pub fn restore_buckets(state: &mut State) {
+    let registry: Vec<(Principal, u64)> = state.bucket_registry.iter().collect();
+    for (canister_id, created_at) in registry {
+        let reservations = state
+            .bucket_reservations
+            .range((canister_id, 0)..=(canister_id, u64::MAX))
+            .map(|((_, file_id), size)| (file_id, size))
+            .collect();
+        let bucket = state.buckets.entry(canister_id).or_insert_with(|| Bucket::new(canister_id, None));
+        bucket.created_at = created_at;
+        bucket.reservations = reservations;
+    }
+}
+
+/// Records that `file_id` is placed in `bucket` and lets `owner` read its chunks there.
+pub(crate) fn reserve_in_bucket(state: &mut State, bucket: Principal, file_id: u64, size: u64, owner: Principal) {
+    if let Some(b) = state.buckets.get_mut(&bucket) {
+        b.reservations.insert(file_id, size);
+        state.bucket_reservations.insert((bucket, file_id), size);
+        set_bucket_readers(bucket, file_id, vec![owner]);
+    }
+}
+
+/// Frees the space held by a deleted file and revokes read access in its bucket.
+pub(crate) fn release_from_bucket(state: &mut State, bucket: Principal, file_id: u64) {
+    if let Some(b) = state.buckets.get_mut(&bucket) {
+        if b.reservations.remove(&file_id).is_some() {
+            state.bucket_reservations.remove(&(bucket, file_id));
+            set_bucket_readers(bucket, file_id, vec![]);
+        }
+    }
+}
+
+/// A chunk queued by `State::store_chunk` for the bucket holding its file.
+pub struct BucketWrite {
+    pub bucket: Principal,
+    pub file_id: u64,
+    pub chunk_id: u64,
+    pub contents: Vec<u8>,
+}
+
+/// Sends the chunks the current call queued for buckets and waits until each bucket
+/// stored them. Returns the chunks that weren't stored, by `(file_id, chunk_id)`, with
+/// the reason; they stay missing from the file, so the client can upload them again.
+pub async fn send_bucket_writes() -> BTreeMap<(u64, u64), String> {
+    let writes = with_state_mut(|s| std::mem::take(&mut s.bucket_writes));
+    let mut failed = BTreeMap::new();
+    for write in writes {
+        let BucketWrite { bucket, file_id, chunk_id, contents } = write;
+        let size = contents.len() as u64;
+        let reply: Result<(Result<(), BucketError>,), _> =
+            ic_cdk::call(bucket, "bucket_put_chunk", (file_id, chunk_id, contents)).await;
+        let error = match reply {
+            Ok((Ok(()),)) => {
+                with_state_mut(|s| chunk_stored(s, bucket, file_id, chunk_id, size));
+                continue;
+            }
+            Ok((Err(err),)) => format!("{err:?}"),
+            Err((code, msg)) => format!("{code:?}: {msg}"),
+        };
+        ic_cdk::println!("bucket {bucket} failed to store chunk {chunk_id} of file {file_id}: {error}");
+        with_state_mut(|s| {
+            s.certified.remove_chunk(file_id, chunk_id);
+            s.certified.commit();
+        });
+        failed.insert((file_id, chunk_id), error);
+    }
+    failed
+}
+
+/// Records a chunk `bucket` confirmed it stored, and completes the file once it has
+/// all of its chunks. A chunk of a file deleted in the meantime is dropped again.
+pub(crate) fn chunk_stored(state: &mut State, bucket: Principal, file_id: u64, chunk_id: u64, size: u64) {
+    let live = state.file_data.get(&file_id).is_some_and(|f| f.metadata.bucket == Some(bucket));
+    if !live {
+        // A lost delete only leaks space in the bucket, so don't fail the upload.
+        let _ = ic_cdk::notify(bucket, "bucket_delete_chunk", (file_id, chunk_id));
+        return;
+    }
+    if let Some(b) = state.buckets.get_mut(&bucket) {
+        b.store.record(file_id, chunk_id, size);
+    }
+    crate::api::upload_chunks::complete_if_stored(state, file_id);
+}
+
+/// Sets who may read the chunks of `file_id` in `bucket`, replacing the previous list.
+/// A list that can't be sent is retried by `run_bucket_maintenance` instead of trapping,
+/// which would roll back the deletion or timer run that changed it.
+pub(crate) fn set_bucket_readers(bucket: Principal, file_id: u64, readers: Vec<Principal>) {
+    match ic_cdk::notify(bucket, "bucket_set_readers", (file_id, readers.clone())) {
+        Ok(()) => {
+            // A newer list replaces one still waiting to be retried.
+            PENDING_READERS.with(|pending| pending.borrow_mut().remove(&(bucket, file_id)));
+        }
+        Err(code) => {
+            ic_cdk::println!("failed to update the readers of file {file_id} in bucket {bucket}: {code:?}");
+            PENDING_READERS.with(|pending| pending.borrow_mut().insert((bucket, file_id), readers));
+        }
+    }
+}
+
+// Sends the reader lists that failed to go out earlier again.
+fn retry_bucket_readers() {
+    let pending = PENDING_READERS.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    for ((bucket, file_id), readers) in pending {
+        set_bucket_readers(bucket, file_id, readers);
+    }
+}
+
+/// True when scaling out is enabled and every bucket is (nearly) full.
+pub(crate) fn needs_new_bucket(state: &State) -> bool {
+    state.bucket_wasm.is_some()
+        && state.buckets.values().filter(|b| b.dedicated_to.is_none()).all(|bucket| {
+            state.bucket_capacity_bytes.saturating_sub(bucket.reserved_bytes()) < BUCKET_MIN_FREE_BYTES
+        })
+}
+
+/// Sets the wasm installed into new buckets and dedicated canisters.
+pub fn set_bucket_wasm(
+    caller: Principal,
+    is_controller: bool,
+    wasm: Vec<u8>,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    state.bucket_wasm = Some(wasm);
+    Ok(())
+}
+
+/// Sets the index canister allowed to manage this bucket's chunks, or none with `None`.
+pub fn set_index_canister(
+    caller: Principal,
+    is_controller: bool,
+    index: Option<Principal>,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    state.index_canister = index;
+    Ok(())
+}
+
+pub fn list_buckets(
+    caller: Principal,
+    is_controller: bool,
+    state: &State,
+) -> Result<Vec<BucketInfo>, AdminError> {
+    require_admin(caller, is_controller, state)?;
+    Ok(state
+        .buckets
+        .iter()
+        .map(|(&canister_id, bucket)| BucketInfo {
+            canister_id,
+            created_at: bucket.created_at,
+            dedicated_to: bucket.dedicated_to,
+            file_count: bucket.reservations.len() as u64,
+            reserved_bytes: bucket.reserved_bytes(),
+            capacity_bytes: state.bucket_capacity_bytes,
+        })
+        .collect())
+}
+
+/// Creates a bucket canister controlled by this canister and installs the bucket wasm into it.
+pub async fn create_bucket() -> Result<Principal, String> {
+    let Some(_guard) = CreatingBucketGuard::acquire() else {
+        return Err("a bucket is already being created".to_string());
+    };
+    create_and_install_bucket(None).await
+}
+
+/// Creates a bucket and registers it with the index. `dedicated_to` keeps it for a single user.
+pub(crate) async fn create_and_install_bucket(dedicated_to: Option<Principal>) -> Result<Principal, String> {
+    let wasm_module = with_state(|s| s.bucket_wasm.clone()).ok_or("no bucket wasm configured")?;
+    let index = ic_cdk::id();
+
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(CanisterSettings {
+                controllers: Some(vec![index]),
+                ..Default::default()
+            }),
+        },
+        BUCKET_INITIAL_CYCLES,
+    )
+    .await
+    .map_err(|(code, msg)| format!("create_canister failed: {code:?}: {msg}"))?;
+    let canister_id = record.canister_id;
+
+    let init_args = InitArgs {
+        index_canister: Some(index),
+        bucket_capacity_bytes: None,
+        // Buckets store chunks the same way as their index.
+        dedup_chunks: Some(crate::chunk_store::dedup_enabled()),
+        admins: None,
+    };
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg: candid::encode_one(Some(init_args)).map_err(|e| e.to_string())?,
+    })
+    .await
+    .map_err(|(code, msg)| format!("install_code failed: {code:?}: {msg}"))?;
+
+    with_state_mut(|s| register_bucket(s, canister_id, dedicated_to));
+    Ok(canister_id)
+}
+
+/// Timer entry point: resends failed reader updates, tops up buckets running low on
+/// cycles and adds a bucket when all are full.
+pub async fn run_bucket_maintenance() {
+    retry_bucket_readers();
+    let buckets: Vec<Principal> = with_state(|s| s.buckets.keys().cloned().collect());
+    for canister_id in buckets {
+        if let Ok((status,)) = canister_status(CanisterIdRecord { canister_id }).await {
+            if status.cycles < Nat::from(BUCKET_MIN_CYCLES) {
+                let _ = deposit_cycles(CanisterIdRecord { canister_id }, BUCKET_TOP_UP_CYCLES).await;
+            }
+        }
+    }
+
+    if with_state(needs_new_bucket) {
+        if let Err(err) = create_bucket().await {
+            ic_cdk::println!("bucket creation failed: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FileContent;
+
+    fn bucket_with(reserved: &[(u64, u64)]) -> Bucket {
+        Bucket {
+            created_at: 0,
+            dedicated_to: None,
+            reservations: reserved.iter().cloned().collect(),
+            store: RemoteBucketStore::new(Principal::anonymous()),
+        }
+    }
+
+    #[test]
+    fn files_go_to_first_bucket_with_room() {
+        let mut state = State::default();
+        state.bucket_capacity_bytes = 100;
+        let full = Principal::from_slice(&[1]);
+        let roomy = Principal::from_slice(&[2]);
+        state.buckets.insert(full, bucket_with(&[(0, 95)]));
+        state.buckets.insert(roomy, bucket_with(&[(1, 10)]));
+
+        let owner = Principal::from_slice(&[3]);
+        assert_eq!(pick_bucket(&state, owner, 5), Some(full));
+        assert_eq!(pick_bucket(&state, owner, 50), Some(roomy));
+        assert_eq!(pick_bucket(&state, owner, 95), None);
+    }
+
+    #[test]
+    fn new_bucket_only_when_enabled_and_full() {
+        let mut state = State::default();
+        assert!(!needs_new_bucket(&state));
+
+        state.bucket_wasm = Some(vec![0, 97, 115, 109]);
+        assert!(needs_new_bucket(&state));
+
+        state.bucket_capacity_bytes = 2 * BUCKET_MIN_FREE_BYTES;
+        state.buckets.insert(Principal::from_slice(&[1]), bucket_with(&[(0, 10)]));
+        assert!(!needs_new_bucket(&state));
+
+        state.buckets.insert(Principal::from_slice(&[1]), bucket_with(&[(0, BUCKET_MIN_FREE_BYTES + 1)]));
+        assert!(needs_new_bucket(&state));
+    }
+
+    #[test]
+    fn upload_completes_once_the_bucket_stored_every_chunk() {
+        let mut state = State::default();
+        let bucket = Principal::from_slice(&[1]);
+        let owner = Principal::from_slice(&[2]);
+        state.buckets.insert(bucket, bucket_with(&[(0, 10)]));
+        state.file_data.insert(
+            0,
+            crate::File {
+                metadata: crate::FileMetadata {
+                    file_name: "report.pdf".to_string(),
+                    requester_principal: owner,
+                    requested_at: 12345,
+                    uploaded_at: Some(12345),
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: Some(bucket),
+                    description: String::new(),
+                    tags: vec![],
+                },
+                content: FileContent::PartiallyUploaded {
+                    num_chunks: 2,
+                    file_type: "pdf".to_string(),
+                    owner_key: vec![],
+                },
+            },
+        );
+        state.file_owners.insert(owner, vec![0]);
+
+        // Chunks for a bucket are only queued: the file isn't complete until the bucket replied.
+        state.store_chunk(0, 0, vec![1, 2, 3]);
+        state.store_chunk(0, 1, vec![4, 5]);
+        assert_eq!(state.bucket_writes.len(), 2);
+        assert!(state.file_contents.is_empty());
+        assert!(!state.chunks_for(0).contains(0, 0));
+
+        chunk_stored(&mut state, bucket, 0, 0, 3);
+        assert!(matches!(state.file_data[&0].content, FileContent::PartiallyUploaded { .. }));
+        chunk_stored(&mut state, bucket, 0, 1, 2);
+        assert!(matches!(state.file_data[&0].content, FileContent::Uploaded { num_chunks: 2, .. }));
+        assert_eq!(state.chunks_for(0).size(0), 5);
+    }
+
+    #[test]
+    fn bucket_creation_guard_is_released_when_dropped() {
+        let guard = CreatingBucketGuard::acquire();
+        assert!(guard.is_some());
+        assert!(CreatingBucketGuard::acquire().is_none());
+        drop(guard);
+        assert!(CreatingBucketGuard::acquire().is_some());
+    }
+
+    #[test]
+    fn buckets_are_restored_with_their_reservations() {
+        let mut state = State::default();
+        let bucket = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        register_bucket(&mut state, bucket, None);
+        register_bucket(&mut state, other, None);
+        state.bucket_reservations.insert((bucket, 0), 10);
+        state.bucket_reservations.insert((bucket, 3), 5);
+        unregister_bucket(&mut state, other);
+
+        // Heap state is lost on upgrade; stable memory isn't.
+        state.buckets.clear();
+        restore_buckets(&mut state);
+        assert_eq!(state.buckets.len(), 1);
+        assert_eq!(state.buckets[&bucket].created_at, 12345);
+        assert_eq!(state.buckets[&bucket].reserved_bytes(), 15);
+        assert_eq!(state.buckets[&bucket].reservations.keys().copied().collect::<Vec<_>>(), vec![0, 3]);
+    }
+
+    #[test]
+    fn init_args_turn_canister_into_bucket() {
+        let mut state = State::default();
+        let index = Principal::from_slice(&[3]);
+        apply_init_args(
+            InitArgs {
+                index_canister: Some(index),
+                bucket_capacity_bytes: Some(42),
+                dedup_chunks: None,
+                admins: None,
+            },
+            &mut state,
+        );
+        assert_eq!(state.index_canister, Some(index));
+        assert_eq!(state.bucket_capacity_bytes, 42);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::api::file_metadata::{normalize_tags, MAX_DESCRIPTION_LEN, MAX_FILE_NAME_LEN};
+use crate::api::upload_session::MAX_UPLOAD_SIZE;
+use crate::api::{Folder, Transfer};
+use crate::{File, FileContent, FileMetadata, State, UserProfile};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// A user takes their data elsewhere in two steps: `export_my_data` returns a
+// manifest of everything the canister keeps about them, and the client then
+// pages through the ciphertext of each file with `export_file_chunks`. On the
+// other deployment, `import_my_data` recreates the profile, folders and files
+// from the manifest, and the client uploads the chunks to the new file IDs with
+// `upload_file_continue`.
+
+/// Upper bound on the chunk bytes returned by one `export_file_chunks` call.
+/// A page always holds at least one chunk.
+pub const EXPORT_PAGE_BYTES: u64 = 2 * 1024 * 1024;
+/// Most files `import_my_data` accepts in one manifest.
+pub const MAX_IMPORT_FILES: usize = 500;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportedFile {
+    pub file_id: u64,
+    pub file_name: String,
+    pub file_type: String,
+    pub num_chunks: u64,
+    pub size: u64,
+    pub requested_at: u64,
+    pub uploaded_at: Option<u64>,
+    pub storage_provider: String,
+    pub blob_id: Option<String>,
+    // Set when the chunks live in a bucket canister; read them there with `bucket_get_chunk`.
+    pub bucket: Option<Principal>,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub folder: Option<u64>,
+    pub trashed: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DataExport {
+    pub principal: Principal,
+    pub exported_at: u64,
+    pub profile: Option<UserProfile>,
+    pub files: Vec<ExportedFile>,
+    pub folders: Vec<Folder>,
+    pub transfers_sent: Vec<Transfer>,
+    pub transfers_received: Vec<Transfer>,
+    pub sui_addresses: Vec<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportChunkPage {
+    pub chunks: Vec<(u64, Vec<u8>)>,
+    // Pass as `start_chunk` to get the next page; `None` once all chunks were returned.
+    pub next_chunk: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportedFile {
+    pub exported_file_id: u64,
+    pub file_id: u64,
+    // Chunks to upload with `upload_file_continue`.
+    pub num_chunks: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportResult {
+    pub profile_created: bool,
+    pub files: Vec<ImportedFile>,
+    // Exported folder ID and the ID of the folder recreated for it.
+    pub folders: Vec<(u64, u64)>,
+    // Exported IDs of the Walrus files, which are not imported: register each one
+    // with `register_file`, which checks that the caller owns the blob.
+    pub walrus_files: Vec<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DataExportError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "stored_in_bucket")]
+    StoredInBucket(Principal),
+    #[serde(rename = "invalid_input")]
+    InvalidInput,
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+fn chunk_info(file: &File) -> (u64, String) {
+    match &file.content {
+        FileContent::Uploaded { num_chunks, file_type, .. }
+        | FileContent::PartiallyUploaded { num_chunks, file_type, .. } => (*num_chunks, file_type.clone()),
+        FileContent::Pending { .. } => (0, String::new()),
+    }
+}
+
+// The caller's files, both listed and in the trash.
+fn exportable(caller: Principal, file_id: u64, state: &State) -> bool {
+    state.is_file_owner(caller, file_id) || state.trash.get(&caller).map_or(false, |files| files.contains_key(&file_id))
+}
+
+/// Everything the canister keeps about the caller. Payments are not recorded by this canister.
+pub fn export_my_data(caller: Principal, state: &State) -> Result<DataExport, DataExportError> {
+    if caller == Principal::anonymous() {
+        return Err(DataExportError::NotAuthenticated);
+    }
+    let trashed = state.trash.get(&caller);
+    let file_ids = state
+        .file_owners
+        .get(&caller)
+        .into_iter()
+        .flatten()
+        .chain(trashed.into_iter().flat_map(|files| files.keys()));
+
+    let mut files = vec![];
+    for &file_id in file_ids {
+        let Some(file) = state.file_data.get(&file_id) else {
+            continue;
+        };
+        let (num_chunks, file_type) = chunk_info(file);
+        let in_trash = trashed.and_then(|files| files.get(&file_id));
+        files.push(ExportedFile {
+            file_id,
+            file_name: file.metadata.file_name.clone(),
+            file_type,
+            num_chunks,
+            size: state.chunks_for(file_id).size(state.storage_id(file_id)),
+            requested_at: file.metadata.requested_at,
+            uploaded_at: file.metadata.uploaded_at,
+            storage_provider: file.metadata.storage_provider.clone(),
+            blob_id: file.metadata.blob_id.clone(),
+            bucket: file.metadata.bucket,
+            description: file.metadata.description.clone(),
+            tags: file.metadata.tags.clone(),
+            folder: in_trash.map_or_else(|| state.file_folders.get(&file_id).copied(), |t| t.folder),
+            trashed: in_trash.is_some(),
+        });
+    }
+    files.sort_by_key(|file| file.file_id);
+
+    let now = crate::get_time();
+    Ok(DataExport {
+        principal: caller,
+        exported_at: now,
+        profile: state.user_profiles.get(&caller).cloned(),
+        files,
+        folders: state.folders.values().filter(|f| f.owner == caller).cloned().collect(),
+        transfers_sent: state.transfers.values().filter(|t| t.sender == caller).cloned().collect(),
+        transfers_received: state
+            .transfers
+            .values()
+            .filter(|t| t.sender != caller && t.can_read(caller, now))
+            .cloned()
+            .collect(),
+        sui_addresses: state.sui_addresses.get(&caller).cloned().unwrap_or_default(),
+    })
+}
+
+/// The chunks of one of the caller's files from `start_chunk` on, up to `EXPORT_PAGE_BYTES`.
+pub fn export_file_chunks(
+    caller: Principal,
+    file_id: u64,
+    start_chunk: u64,
+    state: &State,
+) -> Result<ExportChunkPage, DataExportError> {
+    if caller == Principal::anonymous() {
+        return Err(DataExportError::NotAuthenticated);
+    }
+    let file = state.file_data.get(&file_id).ok_or(DataExportError::NotFound)?;
+    if !exportable(caller, file_id, state) {
+        return Err(DataExportError::PermissionError);
+    }
+    if crate::api::admin::downloads_blocked(state, caller) {
+        return Err(DataExportError::Suspended);
+    }
+    if let Some(bucket) = file.metadata.bucket {
+        return Err(DataExportError::StoredInBucket(bucket));
+    }
+
+    let (num_chunks, _) = chunk_info(file);
+    let mut chunks = vec![];
+    let mut bytes = 0;
+    let mut chunk_id = start_chunk;
+    let storage_id = state.storage_id(file_id);
+    while chunk_id < num_chunks && (chunks.is_empty() || bytes < EXPORT_PAGE_BYTES) {
+        if let Some(contents) = state.file_contents.get(storage_id, chunk_id) {
+            if !chunks.is_empty() && bytes + contents.len() as u64 > EXPORT_PAGE_BYTES {
+                break;
+            }
+            bytes += contents.len() as u64;
+            chunks.push((chunk_id, contents));
+        }
+        chunk_id += 1;
+    }
+    Ok(ExportChunkPage {
+        chunks,
+        next_chunk: (chunk_id < num_chunks).then_some(chunk_id),
+    })
+}
+
+/// Recreates the caller's profile, folders and files from an export made on another
+/// deployment. Trashed files, Walrus files and transfers are not imported. Each imported
+/// file waits for its chunks, to be uploaded with `upload_file_continue` under the new file ID.
+///
+/// The manifest comes from the client, so its files are checked like new uploads: names,
+/// descriptions and tags must be valid, sizes within `MAX_UPLOAD_SIZE` with at least a
+/// byte per chunk, at most `MAX_IMPORT_FILES` files, and the times are set here.
+pub fn import_my_data(caller: Principal, export: DataExport, state: &mut State) -> Result<ImportResult, DataExportError> {
+    if caller == Principal::anonymous() {
+        return Err(DataExportError::NotAuthenticated);
+    }
+    if export.principal != caller {
+        return Err(DataExportError::PermissionError);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(DataExportError::Suspended);
+    }
+    let (walrus, files): (Vec<ExportedFile>, Vec<ExportedFile>) = export
+        .files
+        .into_iter()
+        .filter(|f| !f.trashed)
+        .partition(|f| f.storage_provider == "walrus");
+    if files.len() > MAX_IMPORT_FILES {
+        return Err(DataExportError::InvalidInput);
+    }
+    let mut checked = Vec::with_capacity(files.len());
+    for mut file in files {
+        let name = file.file_name.trim();
+        if file.storage_provider != "icp"
+            || name.is_empty()
+            || name.len() > MAX_FILE_NAME_LEN
+            || file.description.len() > MAX_DESCRIPTION_LEN
+            || (file.num_chunks > 0 && (file.size > MAX_UPLOAD_SIZE || file.num_chunks > file.size))
+        {
+            return Err(DataExportError::InvalidInput);
+        }
+        file.file_name = name.to_string();
+        file.tags = normalize_tags(file.tags).map_err(|_| DataExportError::InvalidInput)?;
+        checked.push(file);
+    }
+
+    // An existing profile, or one whose username is taken here, is left alone.
+    let mut profile_created = false;
+    if let Some(profile) = export.profile {
+        let request = crate::CreateUserRequest {
+            username: profile.username,
+            display_name: profile.display_name,
+            email: profile.email,
+            dedicated_storage: None,
+        };
+        profile_created = matches!(
+            crate::api::create_user_profile(caller, request, state),
+            crate::UserResponse::Ok(_)
+        );
+    }
+
+    // Parents first; a folder whose parent can't be recreated goes to the root.
+    let mut folder_ids: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut pending = export.folders;
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<Folder>, Vec<Folder>) = pending.into_iter().partition(|folder| {
+            folder.parent.map_or(true, |parent| folder_ids.contains_key(&parent))
+        });
+        let ready = if ready.is_empty() {
+            waiting.iter().cloned().map(|folder| Folder { parent: None, ..folder }).collect()
+        } else {
+            ready
+        };
+        let created: Vec<u64> = ready.iter().map(|folder| folder.folder_id).collect();
+        for folder in ready {
+            let parent = folder.parent.and_then(|parent| folder_ids.get(&parent).copied());
+            if let Ok(new) = crate::api::create_folder(caller, folder.name, parent, state) {
+                folder_ids.insert(folder.folder_id, new.folder_id);
+            }
+        }
+        pending = waiting.into_iter().filter(|folder| !created.contains(&folder.folder_id)).collect();
+    }
+
+    let now = crate::get_time();
+    let mut imported = vec![];
+    for exported in checked {
+        let file_id = state.generate_file_id();
+        let chunked = exported.num_chunks > 0;
+        let num_chunks = if chunked { exported.num_chunks } else { 0 };
+        let content = if !chunked {
+            FileContent::Pending { alias: String::new() }
+        } else {
+            FileContent::PartiallyUploaded {
+                num_chunks,
+                file_type: exported.file_type,
+                owner_key: vec![],
+            }
+        };
+        let bucket = if chunked {
+            crate::api::buckets::pick_bucket(state, caller, exported.size)
+        } else {
+            None
+        };
+        if let Some(bucket) = bucket {
+            crate::api::buckets::reserve_in_bucket(state, bucket, file_id, exported.size, caller);
+        }
+        state.file_data.insert(
+            file_id,
+            File {
+                metadata: FileMetadata {
+                    file_name: exported.file_name,
+                    requester_principal: caller,
+                    requested_at: now,
+                    uploaded_at: None,
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket,
+                    description: exported.description,
+                    tags: exported.tags,
+                },
+                content,
+            },
+        );
+        state.file_owners.entry(caller).or_default().push(file_id);
+        if let Some(&folder) = exported.folder.and_then(|folder| folder_ids.get(&folder)) {
+            state.file_folders.insert(file_id, folder);
+            state.folder_files.entry(folder).or_default().insert(file_id);
+        }
+        crate::api::file_metadata::add_file_tags(state, file_id);
+        crate::api::file_index::index_file(state, file_id);
+        crate::certification::certify_file(state, file_id);
+        imported.push(ImportedFile {
+            exported_file_id: exported.file_id,
+            file_id,
+            num_chunks,
+        });
+    }
+
+    Ok(ImportResult {
+        profile_created,
+        files: imported,
+        folders: folder_ids.into_iter().collect(),
+        walrus_files: walrus.into_iter().map(|f| f.file_id).collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{
+        create_folder, download_file, move_file, upload_file_atomic, upload_file_continue, UploadFileAtomicRequest,
+    };
+    use crate::{FileDownloadResponse, UploadFileContinueRequest};
+
+    fn upload(state: &mut State, owner: Principal, chunks: &[Vec<u8>]) -> u64 {
+        let file_id = upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "album.zip".to_string(),
+                content: chunks[0].clone(),
+                file_type: "application/zip".to_string(),
+                num_chunks: chunks.len() as u64,
+            },
+            state,
+        ).unwrap();
+        for (chunk_id, contents) in chunks.iter().enumerate().skip(1) {
+            let request = UploadFileContinueRequest {
+                file_id,
+                chunk_id: chunk_id as u64,
+                contents: contents.clone(),
+            };
+            upload_file_continue(owner, request, state).unwrap();
+        }
+        file_id
+    }
+
+    #[test]
+    fn chunks_are_paged_by_size() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[3]);
+        let big = vec![0; EXPORT_PAGE_BYTES as usize - 10];
+        let file_id = upload(&mut state, owner, &[big.clone(), big.clone(), vec![2]]);
+
+        let page = export_file_chunks(owner, file_id, 0, &state).unwrap();
+        assert_eq!(page.chunks, vec![(0, big.clone())]);
+        assert_eq!(page.next_chunk, Some(1));
+        let page = export_file_chunks(owner, file_id, 1, &state).unwrap();
+        assert_eq!(page.chunks, vec![(1, big), (2, vec![2])]);
+        assert_eq!(page.next_chunk, None);
+
+        assert_eq!(
+            export_file_chunks(Principal::from_slice(&[1]), file_id, 0, &state),
+            Err(DataExportError::PermissionError)
+        );
+    }
+
+    #[test]
+    fn exported_data_can_be_imported_elsewhere() {
+        let mut source = State::default();
+        let owner = Principal::from_slice(&[3]);
+        let parent = create_folder(owner, "photos".to_string(), None, &mut source).unwrap();
+        let child = create_folder(owner, "2024".to_string(), Some(parent.folder_id), &mut source).unwrap();
+        let file_id = upload(&mut source, owner, &[vec![1, 2], vec![3]]);
+        move_file(owner, file_id, Some(child.folder_id), &mut source).unwrap();
+
+        let export = export_my_data(owner, &source).unwrap();
+        assert_eq!(export.files.len(), 1);
+        assert_eq!(export.folders.len(), 2);
+        assert_eq!(
+            import_my_data(Principal::from_slice(&[1]), export.clone(), &mut State::default()),
+            Err(DataExportError::PermissionError)
+        );
+
+        let mut target = State::default();
+        target.generate_file_id();
+        let result = import_my_data(owner, export, &mut target).unwrap();
+        let imported = &result.files[0];
+        assert_ne!(imported.file_id, file_id);
+        let page = export_file_chunks(owner, file_id, 0, &source).unwrap();
+        for (chunk_id, contents) in page.chunks {
+            let request = UploadFileContinueRequest {
+                file_id: imported.file_id,
+                chunk_id,
+                contents,
+            };
+            upload_file_continue(owner, request, &mut target).unwrap();
+        }
+
+        assert_eq!(
+            download_file(&target, owner, imported.file_id, 1),
+            download_file(&source, owner, file_id, 1)
+        );
+        assert!(matches!(
+            download_file(&target, owner, imported.file_id, 0),
+            FileDownloadResponse::FoundFile(_)
+        ));
+        assert_eq!(target.file_data[&imported.file_id].metadata.uploaded_at, Some(crate::get_time()));
+        let new_child = result.folders.iter().find(|(old, _)| *old == child.folder_id).unwrap().1;
+        assert_eq!(target.file_folders.get(&imported.file_id), Some(&new_child));
+        assert_eq!(target.folders[&new_child].parent, Some(result.folders[0].1));
+    }
+
+    #[test]
+    fn imported_files_are_validated() {
+        let owner = Principal::from_slice(&[3]);
+        let mut source = State::default();
+        let file_id = upload(&mut source, owner, &[vec![1]]);
+        let mut export = export_my_data(owner, &source).unwrap();
+        let mut walrus = export.files[0].clone();
+        walrus.file_id = file_id + 1;
+        walrus.storage_provider = "walrus".to_string();
+        walrus.blob_id = Some("someone-elses-blob".to_string());
+        export.files.push(walrus);
+        export.files[0].requested_at = u64::MAX;
+        export.files[0].tags = vec![" Photos ".to_string(), "photos".to_string()];
+
+        let mut invalid = export.clone();
+        invalid.files[0].description = "x".repeat(MAX_DESCRIPTION_LEN + 1);
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+        let mut invalid = export.clone();
+        invalid.files[0].tags = vec![String::new()];
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+        let mut invalid = export.clone();
+        invalid.files[0].storage_provider = "s3".to_string();
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+        let mut invalid = export.clone();
+        invalid.files[0].size = MAX_UPLOAD_SIZE + 1;
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+        let mut invalid = export.clone();
+        invalid.files[0].num_chunks = invalid.files[0].size + 1;
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+        let mut invalid = export.clone();
+        invalid.files = vec![invalid.files[0].clone(); MAX_IMPORT_FILES + 1];
+        assert_eq!(import_my_data(owner, invalid, &mut State::default()), Err(DataExportError::InvalidInput));
+
+        let mut target = State::default();
+        let result = import_my_data(owner, export, &mut target).unwrap();
+        assert_eq!(result.walrus_files, vec![file_id + 1]);
+        assert_eq!(result.files.len(), 1);
+        let metadata = &target.file_data[&result.files[0].file_id].metadata;
+        assert_eq!(metadata.requested_at, crate::get_time());
+        assert_eq!(metadata.uploaded_at, None);
+        assert_eq!(metadata.tags, vec!["photos".to_string()]);
+        assert_eq!(metadata.blob_id, None);
+        assert!(target.file_data.values().all(|f| f.metadata.storage_provider == "icp"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::chunk_store::ChunkStore;
 use crate::{FileContent, State};
 use candid::{CandidType, Principal};
 use serde::{Serialize, Deserialize};
@@ -10,6 +11,8 @@ pub enum DeleteFileResult {
     PermissionError,
 }
 
+/// Moves one of the caller's files to their trash. It can be restored with
+/// `restore_file` until it is purged; see `crate::api::trash`.
 pub fn delete_file(state: &mut State, caller: Principal, file_id: u64) -> DeleteFileResult {
     // Check if caller is authenticated (not anonymous)
     if caller == Principal::anonymous() {
@@ -22,32 +25,126 @@ pub fn delete_file(state: &mut State, caller: Principal, file_id: u64) -> Delete
             if !files.contains(&file_id) {
                 return DeleteFileResult::PermissionError;
             }
-
-            if let Some(file) = state.file_data.remove(&file_id) {
-                // Remove all chunks
-                let num_chunks = match file.content {
-                    FileContent::Uploaded { num_chunks, .. } |
-                    FileContent::PartiallyUploaded { num_chunks, .. } => num_chunks,
-                    FileContent::Pending { .. } => 0,
-                };
-                for chunk_id in 0..num_chunks {
-                    state.file_contents.remove(&(file_id, chunk_id));
-                }
-
-                // Remove the file from the user's owned files
-                if let Some(user_files) = state.file_owners.get_mut(&caller) {
-                    user_files.retain(|&id| id != file_id);
-                }
-
-                DeleteFileResult::Ok
-            } else {
-                DeleteFileResult::NotFound
+            if !state.file_data.contains_key(&file_id) {
+                return DeleteFileResult::NotFound;
             }
+            crate::api::trash::trash_file(state, caller, file_id);
+            DeleteFileResult::Ok
         }
         None => DeleteFileResult::PermissionError,
     }
 }
 
+/// How many chunks a single deletion step frees, to stay well within the instruction limit.
+pub const DELETE_BATCH_CHUNKS: u64 = 500;
+
+/// A deleted file whose chunks are still being freed. The file itself is already
+/// gone from every listing and download; only its chunks are left.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeletingFile {
+    pub bucket: Option<Principal>,
+    pub next_chunk: u64,
+    pub num_chunks: u64,
+}
+
+/// Permanently removes a file, whether or not it is in the trash. The file is
+/// dropped at once; its chunks are freed in batches, see `reclaim_deleted_chunks`.
+pub(crate) fn purge_file(state: &mut State, file_id: u64) {
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+    let num_chunks = match file.content {
+        FileContent::Uploaded { num_chunks, .. } |
+        FileContent::PartiallyUploaded { num_chunks, .. } => num_chunks,
+        FileContent::Pending { .. } => 0,
+    };
+    let bucket = file.metadata.bucket;
+    let owner = file.metadata.requester_principal;
+    if let Some(bucket) = bucket {
+        crate::api::buckets::release_from_bucket(state, bucket, file_id);
+    }
+    crate::api::file_metadata::remove_file_tags(state, file_id);
+    crate::api::file_index::unindex_file(state, file_id);
+    crate::api::trash::remove_from_trash(state, owner, file_id);
+    crate::api::versions::purge_versions(state, file_id);
+    state.file_data.remove(&file_id);
+
+    state.file_replication.remove(&file_id);
+    state.upload_sessions.remove(&file_id);
+    crate::api::transfers::remove_file_from_transfer(state, file_id);
+    crate::api::folders::remove_file_from_folder(state, file_id);
+    crate::api::http_gateway::remove_download_links(state, file_id);
+
+    // Remove the file from the user's owned files
+    if let Some(user_files) = state.file_owners.get_mut(&owner) {
+        user_files.retain(|&id| id != file_id);
+    }
+
+    let storage_id = state.file_storage.remove(&file_id).unwrap_or(file_id);
+    reclaim_chunks(state, storage_id, bucket, num_chunks);
+    if storage_id != file_id {
+        // The chunks are certified under the file's ID, not where they are stored.
+        for chunk_id in 0..num_chunks {
+            state.certified.remove_chunk(file_id, chunk_id);
+        }
+    }
+    crate::certification::uncertify_file(state, owner, file_id);
+}
+
+/// Marks the chunks stored under `storage_id` for deletion and frees a first batch
+/// right away, which is all of them for most files.
+pub(crate) fn reclaim_chunks(state: &mut State, storage_id: u64, bucket: Option<Principal>, num_chunks: u64) {
+    state.deleting.insert(
+        storage_id,
+        DeletingFile {
+            bucket,
+            next_chunk: 0,
+            num_chunks,
+        },
+    );
+    reclaim_file(state, storage_id, DELETE_BATCH_CHUNKS);
+}
+
+// Frees up to `budget` chunks of one file and returns how many it freed.
+fn reclaim_file(state: &mut State, storage_id: u64, budget: u64) -> u64 {
+    let Some(mut deleting) = state.deleting.remove(&storage_id) else {
+        return 0;
+    };
+    let start = deleting.next_chunk;
+    let end = deleting.num_chunks.min(start.saturating_add(budget));
+    let chunks: &mut dyn ChunkStore = match deleting.bucket.and_then(|b| state.buckets.get_mut(&b)) {
+        Some(bucket) => &mut bucket.store,
+        None => state.file_contents.as_mut(),
+    };
+    for chunk_id in start..end {
+        chunks.delete(storage_id, chunk_id);
+    }
+    // An archived version can be stored under the ID of its file, whose certified
+    // hashes are those of the current version.
+    if !state.file_data.contains_key(&storage_id) {
+        for chunk_id in start..end {
+            state.certified.remove_chunk(storage_id, chunk_id);
+        }
+    }
+    if end < deleting.num_chunks {
+        deleting.next_chunk = end;
+        state.deleting.insert(storage_id, deleting);
+    }
+    end - start
+}
+
+/// Frees up to `DELETE_BATCH_CHUNKS` chunks of deleted files. Run by a timer.
+pub fn reclaim_deleted_chunks(state: &mut State) {
+    let mut budget = DELETE_BATCH_CHUNKS;
+    while budget > 0 {
+        let Some(&storage_id) = state.deleting.keys().next() else {
+            break;
+        };
+        budget -= reclaim_file(state, storage_id, budget);
+    }
+    state.certified.commit();
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -56,7 +153,7 @@ mod test {
     #[test]
     fn delete_existing_file() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
 
         // Insert a file manually
         state.file_data.insert(
@@ -67,15 +164,21 @@ mod test {
                     requester_principal: test_principal,
                     requested_at: 12345,
                     uploaded_at: Some(12345),
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
                 },
                 content: FileContent::Uploaded {
                     num_chunks: 1,
                     file_type: "txt".to_string(),
+                    owner_key: vec![],
                 },
             },
         );
         // Insert a chunk
-        state.file_contents.insert((0, 0), vec![1, 2, 3]);
+        state.file_contents.put(0, 0, vec![1, 2, 3]);
 
         // Add file to user's owned files
         state.file_owners.insert(test_principal, vec![0]);
@@ -83,17 +186,18 @@ mod test {
         // Delete the file
         let result = delete_file(&mut state, test_principal, 0);
 
-        // Check result and that file is gone
+        // The file moves to the trash: it keeps its chunks but is no longer owned
         assert_eq!(result, DeleteFileResult::Ok);
-        assert!(!state.file_data.contains_key(&0));
-        assert!(state.file_contents.get(&(0, 0)).is_none());
+        assert!(state.file_data.contains_key(&0));
+        assert_eq!(state.file_contents.get(0, 0), Some(vec![1, 2, 3]));
         assert!(!state.file_owners.get(&test_principal).unwrap().contains(&0));
+        assert_eq!(crate::api::list_trash(test_principal, &state)[0].file_id, 0);
     }
 
     #[test]
     fn delete_nonexistent_file() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
 
         // Try to delete a file that doesn't exist
         let result = delete_file(&mut state, test_principal, 42);
@@ -116,8 +220,8 @@ mod test {
     #[test]
     fn wrong_user_cannot_delete() {
         let mut state = State::default();
-        let test_principal1 = Principal::from_text("2vxsx-fae").unwrap();
-        let test_principal2 = Principal::from_text("3vxsx-fae").unwrap();
+        let test_principal1 = Principal::from_slice(&[1]);
+        let test_principal2 = Principal::from_slice(&[2]);
 
         // Insert a file owned by principal1
         state.file_data.insert(
@@ -128,10 +232,16 @@ mod test {
                     requester_principal: test_principal1,
                     requested_at: 12345,
                     uploaded_at: Some(12345),
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
                 },
                 content: FileContent::Uploaded {
                     num_chunks: 1,
                     file_type: "txt".to_string(),
+                    owner_key: vec![],
                 },
             },
         );
@@ -178,7 +288,7 @@ mod test {
 //             };
 
 //             for chunk_id in 0..num_chunks {
-//                 state.file_contents.remove(&(file_id, chunk_id));
+//                 state.file_contents.delete(file_id, chunk_id);
 //             }
 
 //             // Finally remove the file data itself
@@ -0,0 +1,226 @@
+use crate::{BucketLocation, File, FileContent, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Replies larger than this are rejected by the IC, so batches are capped here
+// whatever the client asks for. Leaves room for the candid encoding overhead.
+pub const MAX_DOWNLOAD_BATCH_BYTES: u64 = 3_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkBatch {
+    pub start_chunk: u64,
+    pub chunks: Vec<Vec<u8>>,
+    // Where to continue, or `None` once the last chunk has been returned.
+    pub next_chunk: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DownloadChunksResponse {
+    #[serde(rename = "not_found_file")]
+    NotFoundFile,
+    #[serde(rename = "not_uploaded_file")]
+    NotUploadedFile,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "stored_in_bucket")]
+    StoredInBucket(BucketLocation),
+    #[serde(rename = "chunks")]
+    Chunks(ChunkBatch),
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileManifest {
+    pub file_id: u64,
+    pub file_name: String,
+    pub file_type: String,
+    pub num_chunks: u64,
+    pub size: u64,
+    // SHA-256 of each chunk, as certified under `chunks/`.
+    pub chunk_hashes: Vec<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum FileManifestResponse {
+    #[serde(rename = "not_found_file")]
+    NotFoundFile,
+    #[serde(rename = "not_uploaded_file")]
+    NotUploadedFile,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "ok")]
+    Ok(FileManifest),
+}
+
+enum Rejected {
+    NotFound,
+    NotUploaded,
+    Permission,
+}
+
+// The uploaded file `caller` may read, with its type and chunk count.
+fn readable_file<'a>(s: &'a State, caller: Principal, file_id: u64) -> Result<(&'a File, &'a str, u64), Rejected> {
+    if caller == Principal::anonymous() || !s.is_file_owner(caller, file_id) {
+        return Err(Rejected::Permission);
+    }
+    let file = s.file_data.get(&file_id).ok_or(Rejected::NotFound)?;
+    match &file.content {
+        FileContent::Uploaded { file_type, num_chunks, .. } => Ok((file, file_type.as_str(), *num_chunks)),
+        _ => Err(Rejected::NotUploaded),
+    }
+}
+
+/// Returns consecutive chunks from `start_chunk` on, as many as fit in `max_bytes`.
+///
+/// At least one chunk is returned so progress is always made.
+pub fn download_chunks(
+    s: &State,
+    caller: Principal,
+    file_id: u64,
+    start_chunk: u64,
+    max_bytes: u64,
+) -> DownloadChunksResponse {
+    let (file, file_type, num_chunks) = match readable_file(s, caller, file_id) {
+        Ok(file) => file,
+        Err(Rejected::NotFound) => return DownloadChunksResponse::NotFoundFile,
+        Err(Rejected::NotUploaded) => return DownloadChunksResponse::NotUploadedFile,
+        Err(Rejected::Permission) => return DownloadChunksResponse::PermissionError,
+    };
+    if crate::api::admin::downloads_blocked(s, caller) {
+        return DownloadChunksResponse::Suspended;
+    }
+    if let Some(canister_id) = file.metadata.bucket {
+        return DownloadChunksResponse::StoredInBucket(BucketLocation {
+            canister_id,
+            file_type: file_type.to_string(),
+            num_chunks,
+        });
+    }
+    if start_chunk >= num_chunks {
+        return DownloadChunksResponse::NotFoundFile;
+    }
+
+    let budget = max_bytes.min(MAX_DOWNLOAD_BATCH_BYTES);
+    let mut used = 0;
+    let mut chunks = vec![];
+    let mut next = start_chunk;
+    let storage_id = s.storage_id(file_id);
+    while next < num_chunks {
+        let Some(chunk) = s.file_contents.get(storage_id, next) else {
+            return DownloadChunksResponse::NotFoundFile;
+        };
+        let len = chunk.len() as u64;
+        if !chunks.is_empty() && used + len > budget {
+            break;
+        }
+        used += len;
+        chunks.push(chunk);
+        next += 1;
+    }
+
+    DownloadChunksResponse::Chunks(ChunkBatch {
+        start_chunk,
+        chunks,
+        next_chunk: (next < num_chunks).then_some(next),
+    })
+}
+
+/// Everything needed to download and verify a file, returned once instead of with every chunk.
+pub fn file_manifest(s: &State, caller: Principal, file_id: u64) -> FileManifestResponse {
+    let (file, file_type, num_chunks) = match readable_file(s, caller, file_id) {
+        Ok(file) => file,
+        Err(Rejected::NotFound) => return FileManifestResponse::NotFoundFile,
+        Err(Rejected::NotUploaded) => return FileManifestResponse::NotUploadedFile,
+        Err(Rejected::Permission) => return FileManifestResponse::PermissionError,
+    };
+    FileManifestResponse::Ok(FileManifest {
+        file_id,
+        file_name: file.metadata.file_name.clone(),
+        file_type: file_type.to_string(),
+        num_chunks,
+        size: s.chunks_for(file_id).size(s.storage_id(file_id)),
+        chunk_hashes: (0..num_chunks)
+            .map(|chunk_id| s.certified.chunk_hash(file_id, chunk_id).map_or_else(Vec::new, |h| h.to_vec()))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{upload_file_atomic, upload_file_continue, UploadFileAtomicRequest};
+    use crate::certification::sha256;
+    use crate::UploadFileContinueRequest;
+
+    fn upload(state: &mut State, owner: Principal, chunks: &[Vec<u8>]) -> u64 {
+        let file_id = upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "data.bin".to_string(),
+                content: chunks[0].clone(),
+                file_type: "application/octet-stream".to_string(),
+                num_chunks: chunks.len() as u64,
+            },
+            state,
+        ).unwrap();
+        for (chunk_id, contents) in chunks.iter().enumerate().skip(1) {
+            upload_file_continue(
+                owner,
+                UploadFileContinueRequest {
+                    file_id,
+                    chunk_id: chunk_id as u64,
+                    contents: contents.clone(),
+                },
+                state,
+            )
+            .unwrap();
+        }
+        file_id
+    }
+
+    #[test]
+    fn batches_fill_up_to_max_bytes() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, &[vec![0; 4], vec![1; 4], vec![2; 4]]);
+
+        assert_eq!(
+            download_chunks(&state, owner, file_id, 0, 8),
+            DownloadChunksResponse::Chunks(ChunkBatch {
+                start_chunk: 0,
+                chunks: vec![vec![0; 4], vec![1; 4]],
+                next_chunk: Some(2),
+            })
+        );
+        // A chunk larger than the budget is still returned on its own.
+        assert_eq!(
+            download_chunks(&state, owner, file_id, 2, 1),
+            DownloadChunksResponse::Chunks(ChunkBatch {
+                start_chunk: 2,
+                chunks: vec![vec![2; 4]],
+                next_chunk: None,
+            })
+        );
+        assert_eq!(download_chunks(&state, owner, file_id, 3, 8), DownloadChunksResponse::NotFoundFile);
+        assert_eq!(
+            download_chunks(&state, Principal::anonymous(), file_id, 0, 8),
+            DownloadChunksResponse::PermissionError
+        );
+    }
+
+    #[test]
+    fn manifest_lists_size_and_hashes() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, &[vec![0; 4], vec![1; 2]]);
+
+        let FileManifestResponse::Ok(manifest) = file_manifest(&state, owner, file_id) else {
+            panic!("expected a manifest");
+        };
+        assert_eq!(manifest.num_chunks, 2);
+        assert_eq!(manifest.size, 6);
+        assert_eq!(manifest.file_type, "application/octet-stream");
+        assert_eq!(manifest.chunk_hashes, vec![sha256(&[0; 4]).to_vec(), sha256(&[1; 2]).to_vec()]);
+    }
+}
@@ -1,6 +1,8 @@
 // pub use crate::ceil_division;
-use crate::{FileContent, FileData, FileDownloadResponse, State};
-use candid::Principal;
+use crate::certification::encode_witness;
+use crate::{BucketLocation, FileContent, FileData, FileDownloadResponse, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
 // use ic_cdk::export::candid::Principal;
 // use candid::Principal;
 // use ic_cdk::println;
@@ -12,20 +14,31 @@ pub fn download_file(s: &State, caller: Principal, file_id: u64, chunk_id: u64)
     }
 
     // Check if the caller owns this file
-    match s.file_owners.get(&caller) {
-        Some(files) => {
-            if !files.contains(&file_id) {
-                return FileDownloadResponse::PermissionError;
-            }
-        }
-        None => return FileDownloadResponse::PermissionError,
+    if !s.is_file_owner(caller, file_id) {
+        return FileDownloadResponse::PermissionError;
+    }
+    if crate::api::admin::downloads_blocked(s, caller) {
+        return FileDownloadResponse::Suspended;
     }
 
+    file_chunk(s, file_id, chunk_id)
+}
+
+/// A chunk of `file_id`, for a caller whose access has already been checked.
+pub(crate) fn file_chunk(s: &State, file_id: u64, chunk_id: u64) -> FileDownloadResponse {
     match s.file_data.get(&file_id) {
         None => FileDownloadResponse::NotFoundFile,
         Some(file) => match &file.content {
             FileContent::Uploaded { file_type, num_chunks, owner_key: _ } => {
-                match s.file_contents.get(&(file_id, chunk_id)) {
+                // Chunks of files placed in a bucket are fetched from the bucket directly.
+                if let Some(canister_id) = file.metadata.bucket {
+                    return FileDownloadResponse::StoredInBucket(BucketLocation {
+                        canister_id,
+                        file_type: file_type.clone(),
+                        num_chunks: *num_chunks,
+                    });
+                }
+                match s.file_contents.get(s.storage_id(file_id), chunk_id) {
                     Some(contents) => FileDownloadResponse::FoundFile(FileData {
                         contents: contents.clone(),
                         file_type: file_type.clone(),
@@ -39,7 +52,33 @@ pub fn download_file(s: &State, caller: Principal, file_id: u64, chunk_id: u64)
     }
 }
 
+/// `download_file` together with what a client needs to check the chunk against the certified data.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub struct CertifiedDownloadResponse {
+    pub response: FileDownloadResponse,
+    pub certificate: Option<Vec<u8>>,
+    // CBOR hash tree revealing `chunks/<file_id><chunk_id>`, the SHA-256 of the returned contents.
+    pub witness: Vec<u8>,
+}
 
+pub fn download_file_certified(
+    s: &State,
+    caller: Principal,
+    file_id: u64,
+    chunk_id: u64,
+    certificate: Option<Vec<u8>>,
+) -> CertifiedDownloadResponse {
+    let response = download_file(s, caller, file_id, chunk_id);
+    let witness = match response {
+        FileDownloadResponse::FoundFile(_) => encode_witness(&s.certified.chunk_witness(file_id, chunk_id)),
+        _ => vec![],
+    };
+    CertifiedDownloadResponse {
+        response,
+        certificate,
+        witness,
+    }
+}
 
 // fn get_file_data(s: &State, file_id: u64, chunk_id: u64) -> FileDownloadResponse {
 //     // unwrap is safe because we already know the file exists
@@ -135,7 +174,7 @@ mod test {
     #[test]
     fn download_existing_uploaded_file() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
         
         state.file_data.insert(
             0,
@@ -147,6 +186,9 @@ mod test {
                     uploaded_at: Some(12345),
                     storage_provider: "icp".to_string(),
                     blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
                 },
                 content: FileContent::Uploaded {
                     num_chunks: 1,
@@ -155,7 +197,7 @@ mod test {
                 },
             },
         );
-        state.file_contents.insert((0, 0), vec![1, 2, 3]);
+        state.file_contents.put(0, 0, vec![1, 2, 3]);
         
         // Add file to user's owned files
         state.file_owners.insert(test_principal, vec![0]);
@@ -174,7 +216,7 @@ mod test {
     #[test]
     fn download_nonexistent_file() {
         let state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
         let result = download_file(&state, test_principal, 42, 0);
         assert_eq!(result, FileDownloadResponse::NotFoundFile);
     }
@@ -182,7 +224,7 @@ mod test {
     #[test]
     fn download_not_uploaded_file() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
         
         state.file_data.insert(
             0,
@@ -194,6 +236,9 @@ mod test {
                     uploaded_at: None,
                     storage_provider: "icp".to_string(),
                     blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
                 },
                 content: FileContent::Pending { alias: "abc".to_string() },
             },
@@ -216,8 +261,8 @@ mod test {
     #[test]
     fn wrong_user_cannot_download() {
         let mut state = State::default();
-        let test_principal1 = Principal::from_text("2vxsx-fae").unwrap();
-        let test_principal2 = Principal::from_text("3vxsx-fae").unwrap();
+        let test_principal1 = Principal::from_slice(&[1]);
+        let test_principal2 = Principal::from_slice(&[2]);
         
         state.file_data.insert(
             0,
@@ -229,6 +274,9 @@ mod test {
                     uploaded_at: Some(12345),
                     storage_provider: "icp".to_string(),
                     blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
                 },
                 content: FileContent::Uploaded {
                     num_chunks: 1,
@@ -237,7 +285,7 @@ mod test {
                 },
             },
         );
-        state.file_contents.insert((0, 0), vec![1, 2, 3]);
+        state.file_contents.put(0, 0, vec![1, 2, 3]);
         
         // Add file to principal1's owned files
         state.file_owners.insert(test_principal1, vec![0]);
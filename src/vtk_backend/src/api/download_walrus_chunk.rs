@@ -0,0 +1,359 @@
+use crate::{with_state, FileData, FileDownloadResponse, State};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+
+/// Size of the byte ranges fetched from the aggregator. Kept below the 2MiB
+/// response limit so a chunk always fits in a single reply.
+pub const WALRUS_CHUNK_SIZE: u64 = 1_800_000;
+
+// Room for the response headers on top of the chunk itself.
+const WALRUS_MAX_RESPONSE_BYTES: u64 = WALRUS_CHUNK_SIZE + 8 * 1024;
+
+// Generous upper bound for a ~2MB outcall on a 13-node subnet; unused cycles are refunded.
+const WALRUS_OUTCALL_CYCLES: u128 = 30_000_000_000;
+
+const DEFAULT_FILE_TYPE: &str = "application/octet-stream";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct WalrusChunkRequest {
+    pub url: String,
+    pub chunk_id: u64,
+    pub range_start: u64,
+    pub range_end: u64,
+}
+
+/// Outcome of the synchronous part of a download: either the answer is already
+/// known (ICP files, permission errors) or a byte range must be fetched from Walrus.
+#[derive(Debug, PartialEq)]
+pub enum WalrusDownload {
+    Local(FileDownloadResponse),
+    Remote(WalrusChunkRequest),
+}
+
+pub fn prepare_walrus_download(
+    s: &State,
+    caller: Principal,
+    file_id: u64,
+    chunk_id: u64,
+) -> WalrusDownload {
+    let file = match s.file_data.get(&file_id) {
+        Some(file) if file.metadata.storage_provider == "walrus" => file,
+        // Files stored on the canister are served from `file_contents`.
+        _ => return WalrusDownload::Local(crate::api::download_file(s, caller, file_id, chunk_id)),
+    };
+
+    // Same access rules as `download_file`.
+    if caller == Principal::anonymous() || !s.is_file_owner(caller, file_id) {
+        return WalrusDownload::Local(FileDownloadResponse::PermissionError);
+    }
+    if crate::api::admin::downloads_blocked(s, caller) {
+        return WalrusDownload::Local(FileDownloadResponse::Suspended);
+    }
+
+    let blob_id = match &file.metadata.blob_id {
+        Some(blob_id) if !blob_id.is_empty() => blob_id,
+        _ => return WalrusDownload::Local(FileDownloadResponse::NotFoundFile),
+    };
+
+    WalrusDownload::Remote(walrus_chunk_request(&s.walrus_aggregator_url, blob_id, chunk_id))
+}
+
+pub(crate) fn walrus_chunk_request(aggregator_url: &str, blob_id: &str, chunk_id: u64) -> WalrusChunkRequest {
+    let range_start = chunk_id.saturating_mul(WALRUS_CHUNK_SIZE);
+    WalrusChunkRequest {
+        url: format!("{}/v1/blobs/{}", aggregator_url, blob_id),
+        chunk_id,
+        range_start,
+        range_end: range_start.saturating_add(WALRUS_CHUNK_SIZE - 1),
+    }
+}
+
+pub async fn download_walrus_chunk(caller: Principal, file_id: u64, chunk_id: u64) -> FileDownloadResponse {
+    match with_state(|s| prepare_walrus_download(s, caller, file_id, chunk_id)) {
+        WalrusDownload::Local(response) => response,
+        WalrusDownload::Remote(request) => fetch_walrus_chunk(&request).await,
+    }
+}
+
+/// Fetches one byte range of a Walrus blob from the aggregator.
+pub(crate) async fn fetch_walrus_chunk(request: &WalrusChunkRequest) -> FileDownloadResponse {
+    let arg = CanisterHttpRequestArgument {
+        url: request.url.clone(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(WALRUS_MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext::from_name(
+            "transform_walrus_response".to_string(),
+            vec![],
+        )),
+        headers: vec![HttpHeader {
+            name: "Range".to_string(),
+            value: format!("bytes={}-{}", request.range_start, request.range_end),
+        }],
+    };
+
+    match http_request(arg, WALRUS_OUTCALL_CYCLES).await {
+        Ok((response,)) => chunk_from_response(request, response),
+        Err((code, msg)) => {
+            FileDownloadResponse::StorageProviderError(format!("{code:?}: {msg}"))
+        }
+    }
+}
+
+/// Points Walrus downloads at another aggregator. Callers must check that the
+/// request comes from an admin, see `update_config`.
+pub fn set_walrus_aggregator_url(url: String, state: &mut State) -> Result<(), String> {
+    let url = url.trim().trim_end_matches('/');
+    if !url.starts_with("https://") {
+        return Err("aggregator url must use https".to_string());
+    }
+    state.walrus_aggregator_url = url.to_string();
+    Ok(())
+}
+
+/// Turns the aggregator reply into the same `FileData` shape `download_file` returns.
+pub fn chunk_from_response(request: &WalrusChunkRequest, response: HttpResponse) -> FileDownloadResponse {
+    let status: u64 = u64::try_from(response.status.0).unwrap_or(0);
+    let file_type = header_value(&response.headers, "content-type")
+        .unwrap_or(DEFAULT_FILE_TYPE)
+        .to_string();
+
+    match status {
+        206 => {
+            let total = match header_value(&response.headers, "content-range")
+                .and_then(parse_content_range_total)
+            {
+                Some(total) => total,
+                None => {
+                    return FileDownloadResponse::StorageProviderError(
+                        "missing content-range in partial response".to_string(),
+                    )
+                }
+            };
+            FileDownloadResponse::FoundFile(FileData {
+                contents: response.body,
+                file_type,
+                num_chunks: num_walrus_chunks(total),
+            })
+        }
+        // Aggregator ignored the Range header and returned the whole blob.
+        200 => {
+            let total = response.body.len() as u64;
+            if request.range_start >= total && total > 0 {
+                return FileDownloadResponse::NotFoundFile;
+            }
+            let start = request.range_start.min(total) as usize;
+            let end = (request.range_end + 1).min(total) as usize;
+            FileDownloadResponse::FoundFile(FileData {
+                contents: response.body[start..end].to_vec(),
+                file_type,
+                num_chunks: num_walrus_chunks(total),
+            })
+        }
+        404 | 416 => FileDownloadResponse::NotFoundFile,
+        _ => FileDownloadResponse::StorageProviderError(format!(
+            "aggregator returned status {status} for chunk {}",
+            request.chunk_id
+        )),
+    }
+}
+
+/// Strips everything but the headers we rely on so that all replicas agree on the response.
+pub fn transform_walrus_response(args: TransformArgs) -> HttpResponse {
+    let headers = args
+        .response
+        .headers
+        .into_iter()
+        .filter_map(|h| {
+            let name = h.name.to_lowercase();
+            (name == "content-range" || name == "content-type").then_some(HttpHeader {
+                name,
+                value: h.value,
+            })
+        })
+        .collect();
+
+    HttpResponse {
+        status: args.response.status,
+        headers,
+        body: args.response.body,
+    }
+}
+
+fn header_value<'a>(headers: &'a [HttpHeader], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Parses the total blob size out of e.g. `bytes 0-1799999/5242880`.
+fn parse_content_range_total(value: &str) -> Option<u64> {
+    value.rsplit('/').next()?.trim().parse().ok()
+}
+
+fn num_walrus_chunks(total_size: u64) -> u64 {
+    crate::ceil_division(total_size as usize, WALRUS_CHUNK_SIZE as usize).max(1) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{File, FileContent, FileMetadata, State};
+
+    fn insert_walrus_file(state: &mut State, owner: Principal, blob_id: Option<&str>) {
+        state.file_data.insert(
+            0,
+            File {
+                metadata: FileMetadata {
+                    file_name: "video.mp4".to_string(),
+                    requester_principal: owner,
+                    requested_at: 12345,
+                    uploaded_at: Some(12345),
+                    storage_provider: "walrus".to_string(),
+                    blob_id: blob_id.map(|b| b.to_string()),
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
+                },
+                content: FileContent::Pending { alias: String::new() },
+            },
+        );
+        state.file_owners.insert(owner, vec![0]);
+    }
+
+    fn response(status: u64, headers: Vec<(&str, &str)>, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status: status.into(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| HttpHeader {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            body,
+        }
+    }
+
+    #[test]
+    fn walrus_chunk_request_uses_configured_aggregator() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_walrus_file(&mut state, owner, Some("M4hsZGQ1"));
+        state.walrus_aggregator_url = "https://aggregator.example".to_string();
+
+        let result = prepare_walrus_download(&state, owner, 0, 2);
+        assert_eq!(
+            result,
+            WalrusDownload::Remote(WalrusChunkRequest {
+                url: "https://aggregator.example/v1/blobs/M4hsZGQ1".to_string(),
+                chunk_id: 2,
+                range_start: 2 * WALRUS_CHUNK_SIZE,
+                range_end: 3 * WALRUS_CHUNK_SIZE - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_user_cannot_download_walrus_file() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        insert_walrus_file(&mut state, owner, Some("M4hsZGQ1"));
+
+        assert_eq!(
+            prepare_walrus_download(&state, other, 0, 0),
+            WalrusDownload::Local(FileDownloadResponse::PermissionError)
+        );
+        assert_eq!(
+            prepare_walrus_download(&state, Principal::anonymous(), 0, 0),
+            WalrusDownload::Local(FileDownloadResponse::PermissionError)
+        );
+    }
+
+    #[test]
+    fn walrus_file_without_blob_id_is_not_found() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_walrus_file(&mut state, owner, None);
+
+        assert_eq!(
+            prepare_walrus_download(&state, owner, 0, 0),
+            WalrusDownload::Local(FileDownloadResponse::NotFoundFile)
+        );
+    }
+
+    #[test]
+    fn partial_response_reports_total_chunks() {
+        let request = WalrusChunkRequest {
+            url: String::new(),
+            chunk_id: 0,
+            range_start: 0,
+            range_end: WALRUS_CHUNK_SIZE - 1,
+        };
+        let total = WALRUS_CHUNK_SIZE * 2 + 10;
+        let result = chunk_from_response(
+            &request,
+            response(
+                206,
+                vec![
+                    ("Content-Range", &format!("bytes 0-{}/{}", WALRUS_CHUNK_SIZE - 1, total)),
+                    ("Content-Type", "video/mp4"),
+                ],
+                vec![1, 2, 3],
+            ),
+        );
+        assert_eq!(
+            result,
+            FileDownloadResponse::FoundFile(FileData {
+                contents: vec![1, 2, 3],
+                file_type: "video/mp4".to_string(),
+                num_chunks: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn range_past_end_is_not_found() {
+        let request = WalrusChunkRequest {
+            url: String::new(),
+            chunk_id: 5,
+            range_start: 5 * WALRUS_CHUNK_SIZE,
+            range_end: 6 * WALRUS_CHUNK_SIZE - 1,
+        };
+        let result = chunk_from_response(&request, response(416, vec![], vec![]));
+        assert_eq!(result, FileDownloadResponse::NotFoundFile);
+    }
+
+    #[test]
+    fn aggregator_url_must_be_https() {
+        let mut state = State::default();
+        assert!(set_walrus_aggregator_url("http://aggregator.example".to_string(), &mut state).is_err());
+        assert_eq!(state.walrus_aggregator_url, crate::DEFAULT_WALRUS_AGGREGATOR_URL);
+
+        set_walrus_aggregator_url("https://aggregator.example/".to_string(), &mut state).unwrap();
+        assert_eq!(state.walrus_aggregator_url, "https://aggregator.example");
+    }
+
+    #[test]
+    fn transform_keeps_only_deterministic_headers() {
+        let args = TransformArgs {
+            response: response(
+                206,
+                vec![
+                    ("Content-Range", "bytes 0-2/3"),
+                    ("Date", "Mon, 06 Jul 2025 07:00:42 GMT"),
+                ],
+                vec![1, 2, 3],
+            ),
+            context: vec![],
+        };
+        let transformed = transform_walrus_response(args);
+        assert_eq!(transformed.headers.len(), 1);
+        assert_eq!(transformed.headers[0].name, "content-range");
+    }
+}
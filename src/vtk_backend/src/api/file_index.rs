@@ -0,0 +1,465 @@
+use crate::{FileContent, PublicFileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::str::FromStr;
+
+// Secondary indexes over each user's files, so `list_files_v2` can walk them in
+// the requested order from a cursor instead of sorting every file on each call.
+// Every file is indexed once per scope it belongs to (all files, its status, its
+// provider, and both), so a status or provider filter walks only matching files.
+// `index_file` must be called whenever a file's name, tags, status or contents
+// change; it also keeps the search index in sync.
+
+pub const DEFAULT_PAGE_LIMIT: u32 = 100;
+pub const MAX_PAGE_LIMIT: u32 = 500;
+// Most index entries one page walks. A filter few files match, like a rare name
+// prefix with another sort key than the name, then ends the page early with a
+// cursor to resume from, instead of walking every file in one call.
+const MAX_PAGE_SCAN: usize = 2_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct IndexedFile {
+    owner: Principal,
+    name: String,
+    uploaded_at: u64,
+    // Only known once the file is uploaded; 0 until then.
+    size: u64,
+    status: FileStatusFilter,
+    provider: String,
+}
+
+// The files of a user one index covers: those matching the given filters.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Scope {
+    status: Option<FileStatusFilter>,
+    provider: Option<String>,
+}
+
+impl IndexedFile {
+    fn scopes(&self) -> [Scope; 4] {
+        let status = Some(self.status);
+        let provider = Some(self.provider.clone());
+        [
+            Scope { status: None, provider: None },
+            Scope { status, provider: None },
+            Scope { status: None, provider: provider.clone() },
+            Scope { status, provider },
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct OwnerIndex {
+    by_name: BTreeSet<(String, u64)>,
+    by_uploaded_at: BTreeSet<(u64, u64)>,
+    by_size: BTreeSet<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FileIndex {
+    files: BTreeMap<u64, IndexedFile>,
+    owners: BTreeMap<(Principal, Scope), OwnerIndex>,
+}
+
+impl FileIndex {
+    fn insert(&mut self, file_id: u64, file: IndexedFile) {
+        for scope in file.scopes() {
+            let index = self.owners.entry((file.owner, scope)).or_default();
+            index.by_name.insert((file.name.clone(), file_id));
+            index.by_uploaded_at.insert((file.uploaded_at, file_id));
+            index.by_size.insert((file.size, file_id));
+        }
+        self.files.insert(file_id, file);
+    }
+
+    fn remove(&mut self, file_id: u64) -> Option<IndexedFile> {
+        let file = self.files.remove(&file_id)?;
+        for scope in file.scopes() {
+            let key = (file.owner, scope);
+            if let Some(index) = self.owners.get_mut(&key) {
+                index.by_name.remove(&(file.name.clone(), file_id));
+                index.by_uploaded_at.remove(&(file.uploaded_at, file_id));
+                index.by_size.remove(&(file.size, file_id));
+                if index.by_name.is_empty() {
+                    self.owners.remove(&key);
+                }
+            }
+        }
+        Some(file)
+    }
+}
+
+/// Brings the indexes of a file up to date with its metadata and contents.
+pub(crate) fn index_file(state: &mut State, file_id: u64) {
+    // Staged versions and trashed files stay out of listings and search.
+    if crate::api::versions::is_staged_version(state, file_id) || crate::api::trash::is_trashed(state, file_id) {
+        return;
+    }
+    crate::api::search::index_search(state, file_id);
+    let previous = state.file_index.remove(file_id);
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+    let status = match file.content {
+        FileContent::Pending { .. } => FileStatusFilter::Pending,
+        FileContent::PartiallyUploaded { .. } => FileStatusFilter::PartiallyUploaded,
+        FileContent::Uploaded { .. } => FileStatusFilter::Uploaded,
+    };
+    let uploaded = status == FileStatusFilter::Uploaded;
+    // Summing chunk sizes reads the chunks, so only do it once per file.
+    let size = match previous {
+        Some(previous) if previous.status == FileStatusFilter::Uploaded && uploaded => previous.size,
+        _ if uploaded => state.chunks_for(file_id).size(state.storage_id(file_id)),
+        _ => 0,
+    };
+    let indexed = IndexedFile {
+        owner: file.metadata.requester_principal,
+        name: file.metadata.file_name.to_lowercase(),
+        uploaded_at: file.metadata.uploaded_at.unwrap_or(file.metadata.requested_at),
+        size,
+        status,
+        provider: file.metadata.storage_provider.clone(),
+    };
+    state.file_index.insert(file_id, indexed);
+}
+
+pub(crate) fn unindex_file(state: &mut State, file_id: u64) {
+    crate::api::search::unindex_search(state, file_id);
+    state.file_index.remove(file_id);
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileSortKey {
+    #[serde(rename = "name")]
+    Name,
+    #[serde(rename = "uploaded_at")]
+    UploadedAt,
+    #[serde(rename = "size")]
+    Size,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    #[serde(rename = "asc")]
+    Asc,
+    #[serde(rename = "desc")]
+    Desc,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileStatusFilter {
+    #[serde(rename = "pending")]
+    Pending,
+    #[serde(rename = "partially_uploaded")]
+    PartiallyUploaded,
+    #[serde(rename = "uploaded")]
+    Uploaded,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ListFilesRequest {
+    // `next_cursor` of the previous page; `None` for the first page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+    pub sort_by: FileSortKey,
+    pub order: SortOrder,
+    pub status_filter: Option<FileStatusFilter>,
+    // "icp" or "walrus".
+    pub provider_filter: Option<String>,
+    // Case-insensitive.
+    pub name_prefix: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ListFilesPage {
+    pub files: Vec<PublicFileMetadata>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ListFilesError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_cursor")]
+    InvalidCursor,
+}
+
+// Cursors are "<file_id>/<sort value>" of the last file of the previous page.
+fn parse_cursor<K: FromStr>(cursor: &str) -> Result<(K, u64), ListFilesError> {
+    let (file_id, key) = cursor.split_once('/').ok_or(ListFilesError::InvalidCursor)?;
+    let file_id = file_id.parse().map_err(|_| ListFilesError::InvalidCursor)?;
+    let key = key.parse().map_err(|_| ListFilesError::InvalidCursor)?;
+    Ok((key, file_id))
+}
+
+// Walks `[from, to)` of the index (unbounded where `None`) in `order`, resuming
+// after the cursor `after`.
+fn walk<'a, K: Ord + Clone + 'a>(
+    index: &'a BTreeSet<(K, u64)>,
+    after: Option<(K, u64)>,
+    from: Option<(K, u64)>,
+    to: Option<(K, u64)>,
+    order: SortOrder,
+) -> Box<dyn Iterator<Item = &'a (K, u64)> + 'a> {
+    let lower = match (order, after.clone(), from) {
+        (SortOrder::Asc, Some(after), Some(from)) if after < from => Included(from),
+        (SortOrder::Asc, Some(after), _) => Excluded(after),
+        (_, _, Some(from)) => Included(from),
+        (_, _, None) => Unbounded,
+    };
+    let upper = match (order, after, to) {
+        (SortOrder::Desc, Some(after), Some(to)) if after >= to => Excluded(to),
+        (SortOrder::Desc, Some(after), _) => Excluded(after),
+        (_, _, Some(to)) => Excluded(to),
+        (_, _, None) => Unbounded,
+    };
+    // `range` panics on an empty interval; the upper bound is never inclusive.
+    if let (Included(l) | Excluded(l), Excluded(u)) = (&lower, &upper) {
+        if l >= u {
+            return Box::new(std::iter::empty());
+        }
+    }
+    match order {
+        SortOrder::Asc => Box::new(index.range((lower, upper))),
+        SortOrder::Desc => Box::new(index.range((lower, upper)).rev()),
+    }
+}
+
+// The first string after every string starting with `prefix`, if there is one.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+fn page<K: Ord + Clone + Display + FromStr>(
+    index: &BTreeSet<(K, u64)>,
+    request: &ListFilesRequest,
+    (from, to): (Option<(K, u64)>, Option<(K, u64)>),
+    matches: impl Fn(u64) -> bool,
+) -> Result<(Vec<u64>, Option<String>), ListFilesError> {
+    let after = request.cursor.as_deref().map(parse_cursor::<K>).transpose()?;
+    let limit = request.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT) as usize;
+
+    let cursor = |(key, file_id): &(K, u64)| format!("{}/{}", file_id, key);
+    let mut file_ids = Vec::new();
+    // The last entry walked: the next page resumes after it.
+    let mut last = None;
+    for (scanned, entry) in walk(index, after, from, to, request.order).enumerate() {
+        if scanned == MAX_PAGE_SCAN {
+            return Ok((file_ids, last.map(cursor)));
+        }
+        if matches(entry.1) {
+            if file_ids.len() == limit {
+                return Ok((file_ids, last.map(cursor)));
+            }
+            file_ids.push(entry.1);
+        }
+        last = Some(entry);
+    }
+    Ok((file_ids, None))
+}
+
+/// One page of the caller's files, in the requested order and filtered.
+pub fn list_files_v2(caller: Principal, request: ListFilesRequest, state: &State) -> Result<ListFilesPage, ListFilesError> {
+    if caller == Principal::anonymous() {
+        return Err(ListFilesError::NotAuthenticated);
+    }
+    let scope = Scope {
+        status: request.status_filter,
+        provider: request.provider_filter.clone(),
+    };
+    let Some(index) = state.file_index.owners.get(&(caller, scope)) else {
+        return Ok(ListFilesPage {
+            files: vec![],
+            next_cursor: None,
+        });
+    };
+
+    let prefix = request.name_prefix.as_deref().map(str::to_lowercase).unwrap_or_default();
+    let has_prefix = |file_id: u64| {
+        state
+            .file_index
+            .files
+            .get(&file_id)
+            .map_or(false, |indexed| indexed.name.starts_with(&prefix))
+    };
+
+    let (file_ids, next_cursor) = match request.sort_by {
+        // Names sharing the prefix are adjacent in the name index, so only their range is walked.
+        FileSortKey::Name => {
+            let range = (Some((prefix.clone(), 0)), prefix_end(&prefix).map(|end| (end, 0)));
+            page(&index.by_name, &request, range, |_| true)?
+        }
+        // Without a name index to narrow, a prefix is checked file by file while walking.
+        FileSortKey::UploadedAt => page(&index.by_uploaded_at, &request, (None, None), has_prefix)?,
+        FileSortKey::Size => page(&index.by_size, &request, (None, None), has_prefix)?,
+    };
+    Ok(ListFilesPage {
+        files: file_ids
+            .into_iter()
+            .filter_map(|file_id| state.public_file_metadata(file_id))
+            .collect(),
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{upload_file_atomic, UploadFileAtomicRequest};
+
+    fn upload(state: &mut State, owner: Principal, name: &str, content: Vec<u8>) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: name.to_string(),
+                content,
+                file_type: "text/plain".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    fn request(sort_by: FileSortKey, order: SortOrder, limit: u32) -> ListFilesRequest {
+        ListFilesRequest {
+            cursor: None,
+            limit: Some(limit),
+            sort_by,
+            order,
+            status_filter: None,
+            provider_filter: None,
+            name_prefix: None,
+        }
+    }
+
+    fn names(page: &ListFilesPage) -> Vec<&str> {
+        page.files.iter().map(|f| f.file_name.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_follow_the_cursor() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        for name in ["c.txt", "A.txt", "b.txt"] {
+            upload(&mut state, owner, name, vec![1]);
+        }
+
+        let mut request = request(FileSortKey::Name, SortOrder::Asc, 2);
+        let first = list_files_v2(owner, request.clone(), &state).unwrap();
+        assert_eq!(names(&first), vec!["A.txt", "b.txt"]);
+        request.cursor = first.next_cursor;
+        let second = list_files_v2(owner, request, &state).unwrap();
+        assert_eq!(names(&second), vec!["c.txt"]);
+        assert_eq!(second.next_cursor, None);
+
+        let mut request = self::request(FileSortKey::Name, SortOrder::Desc, 2);
+        let first = list_files_v2(owner, request.clone(), &state).unwrap();
+        assert_eq!(names(&first), vec!["c.txt", "b.txt"]);
+        request.cursor = first.next_cursor;
+        assert_eq!(names(&list_files_v2(owner, request, &state).unwrap()), vec!["A.txt"]);
+    }
+
+    #[test]
+    fn sorts_by_size_and_filters() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        upload(&mut state, owner, "big.txt", vec![0; 30]);
+        upload(&mut state, owner, "small.txt", vec![0; 10]);
+        upload(&mut state, owner, "bigger.txt", vec![0; 50]);
+        upload(&mut state, Principal::from_slice(&[9]), "theirs.txt", vec![0; 20]);
+
+        let by_size = list_files_v2(owner, request(FileSortKey::Size, SortOrder::Desc, 10), &state).unwrap();
+        assert_eq!(names(&by_size), vec!["bigger.txt", "big.txt", "small.txt"]);
+
+        let mut filtered = request(FileSortKey::Name, SortOrder::Asc, 10);
+        filtered.name_prefix = Some("BIG".to_string());
+        assert_eq!(names(&list_files_v2(owner, filtered, &state).unwrap()), vec!["big.txt", "bigger.txt"]);
+
+        let mut filtered = request(FileSortKey::UploadedAt, SortOrder::Asc, 10);
+        filtered.status_filter = Some(FileStatusFilter::PartiallyUploaded);
+        assert!(list_files_v2(owner, filtered, &state).unwrap().files.is_empty());
+
+        let mut bad_cursor = request(FileSortKey::Size, SortOrder::Asc, 10);
+        bad_cursor.cursor = Some("nope".to_string());
+        assert_eq!(list_files_v2(owner, bad_cursor, &state), Err(ListFilesError::InvalidCursor));
+    }
+
+    #[test]
+    fn prefix_and_filters_walk_only_matching_files() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        for name in ["a.txt", "ba.txt", "bb.txt", "c.txt"] {
+            upload(&mut state, owner, name, vec![1]);
+        }
+        let partial = upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "bc.txt".to_string(),
+                content: vec![1],
+                file_type: "text/plain".to_string(),
+                num_chunks: 2,
+            },
+            &mut state,
+        ).unwrap();
+
+        let mut request = request(FileSortKey::Name, SortOrder::Desc, 1);
+        request.name_prefix = Some("b".to_string());
+        request.status_filter = Some(FileStatusFilter::Uploaded);
+        let first = list_files_v2(owner, request.clone(), &state).unwrap();
+        assert_eq!(names(&first), vec!["bb.txt"]);
+        request.cursor = first.next_cursor;
+        let second = list_files_v2(owner, request.clone(), &state).unwrap();
+        assert_eq!(names(&second), vec!["ba.txt"]);
+        assert_eq!(second.next_cursor, None);
+
+        request.status_filter = Some(FileStatusFilter::PartiallyUploaded);
+        request.cursor = None;
+        let partial_page = list_files_v2(owner, request, &state).unwrap();
+        assert_eq!(partial_page.files.iter().map(|f| f.file_id).collect::<Vec<_>>(), vec![partial]);
+
+        assert_eq!(prefix_end("ab"), Some("ac".to_string()));
+        assert_eq!(prefix_end("\u{10FFFF}"), None);
+    }
+
+    #[test]
+    fn rare_prefixes_resume_from_a_cursor() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let needle = upload(&mut state, owner, "needle.txt", vec![1]);
+        for i in 0..MAX_PAGE_SCAN + 5 {
+            upload(&mut state, owner, &format!("hay{i}.txt"), vec![1]);
+        }
+
+        let mut request = request(FileSortKey::UploadedAt, SortOrder::Desc, 10);
+        request.name_prefix = Some("needle".to_string());
+        let first = list_files_v2(owner, request.clone(), &state).unwrap();
+        assert!(first.files.is_empty());
+        assert!(first.next_cursor.is_some());
+        request.cursor = first.next_cursor;
+        let second = list_files_v2(owner, request, &state).unwrap();
+        assert_eq!(second.files.iter().map(|f| f.file_id).collect::<Vec<_>>(), vec![needle]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn deleted_files_leave_the_index() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, "a.txt", vec![1]);
+        crate::api::delete_file(&mut state, owner, file_id);
+
+        let page = list_files_v2(owner, request(FileSortKey::Name, SortOrder::Asc, 10), &state).unwrap();
+        assert!(page.files.is_empty());
+        assert!(state.file_index.files.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{PublicFileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+pub(crate) const MAX_FILE_NAME_LEN: usize = 255;
+pub(crate) const MAX_DESCRIPTION_LEN: usize = 2_000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 50;
+
+/// Fields left as `None` are kept as they are.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UpdateFileMetadataRequest {
+    pub file_name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateFileMetadataError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "invalid_file_name")]
+    InvalidFileName,
+    #[serde(rename = "invalid_description")]
+    InvalidDescription,
+    #[serde(rename = "invalid_tags")]
+    InvalidTags,
+}
+
+// Tags are compared case-insensitively and stored lowercased, without duplicates.
+pub(crate) fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, UpdateFileMetadataError> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(UpdateFileMetadataError::InvalidTags);
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(UpdateFileMetadataError::InvalidTags);
+    }
+    Ok(normalized)
+}
+
+pub fn update_file_metadata(
+    caller: Principal,
+    file_id: u64,
+    request: UpdateFileMetadataRequest,
+    state: &mut State,
+) -> Result<PublicFileMetadata, UpdateFileMetadataError> {
+    if caller == Principal::anonymous() {
+        return Err(UpdateFileMetadataError::NotAuthenticated);
+    }
+    if !state.is_file_owner(caller, file_id) || !state.file_data.contains_key(&file_id) {
+        return Err(UpdateFileMetadataError::NotFound);
+    }
+
+    // Validate everything before changing anything.
+    let file_name = match request.file_name {
+        Some(name) => {
+            let name = name.trim().to_string();
+            if name.is_empty() || name.len() > MAX_FILE_NAME_LEN {
+                return Err(UpdateFileMetadataError::InvalidFileName);
+            }
+            Some(name)
+        }
+        None => None,
+    };
+    if request.description.as_ref().map_or(false, |d| d.len() > MAX_DESCRIPTION_LEN) {
+        return Err(UpdateFileMetadataError::InvalidDescription);
+    }
+    let tags = request.tags.map(normalize_tags).transpose()?;
+
+    if tags.is_some() {
+        remove_file_tags(state, file_id);
+    }
+    let metadata = &mut state.file_data.get_mut(&file_id).expect("file exists").metadata;
+    if let Some(file_name) = file_name {
+        metadata.file_name = file_name;
+    }
+    if let Some(description) = request.description {
+        metadata.description = description;
+    }
+    if let Some(tags) = tags {
+        metadata.tags = tags;
+        add_file_tags(state, file_id);
+    }
+
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+    Ok(state.public_file_metadata(file_id).expect("file exists"))
+}
+
+/// The caller's files with this tag.
+pub fn list_files_by_tag(caller: Principal, tag: String, state: &State) -> Vec<PublicFileMetadata> {
+    let tag = tag.trim().to_lowercase();
+    state
+        .file_tags
+        .get(&(caller, tag))
+        .map(|files| files.iter().filter_map(|&id| state.public_file_metadata(id)).collect())
+        .unwrap_or_default()
+}
+
+pub(crate) fn add_file_tags(state: &mut State, file_id: u64) {
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+    let owner = file.metadata.requester_principal;
+    for tag in &file.metadata.tags {
+        state.file_tags.entry((owner, tag.clone())).or_default().insert(file_id);
+    }
+}
+
+/// Drops a file from the tag index, e.g. when it's deleted.
+pub(crate) fn remove_file_tags(state: &mut State, file_id: u64) {
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+    let owner = file.metadata.requester_principal;
+    for tag in &file.metadata.tags {
+        let key = (owner, tag.clone());
+        if let Some(files) = state.file_tags.get_mut(&key) {
+            files.remove(&file_id);
+            if files.is_empty() {
+                state.file_tags.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{list_files, upload_file_atomic, UploadFileAtomicRequest};
+
+    fn upload(state: &mut State, owner: Principal) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "IMG_0001.jpg".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "image/jpeg".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    fn request(file_name: Option<&str>, tags: Option<Vec<&str>>) -> UpdateFileMetadataRequest {
+        UpdateFileMetadataRequest {
+            file_name: file_name.map(str::to_string),
+            description: None,
+            tags: tags.map(|tags| tags.into_iter().map(str::to_string).collect()),
+        }
+    }
+
+    #[test]
+    fn updates_show_up_in_list_files() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner);
+
+        let updated = update_file_metadata(
+            owner,
+            file_id,
+            UpdateFileMetadataRequest {
+                file_name: Some(" beach.jpg ".to_string()),
+                description: Some("Summer 2024".to_string()),
+                tags: Some(vec!["Holiday".to_string(), "holiday".to_string(), "family".to_string()]),
+            },
+            &mut state,
+        )
+        .unwrap();
+        assert_eq!(updated.file_name, "beach.jpg");
+        assert_eq!(updated.tags, vec!["holiday".to_string(), "family".to_string()]);
+        assert_eq!(list_files(owner, &state), vec![updated]);
+    }
+
+    #[test]
+    fn tag_index_follows_updates_and_deletes() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let a = upload(&mut state, owner);
+        let b = upload(&mut state, owner);
+        update_file_metadata(owner, a, request(None, Some(vec!["work"])), &mut state).unwrap();
+        update_file_metadata(owner, b, request(None, Some(vec!["work", "tax"])), &mut state).unwrap();
+        assert_eq!(list_files_by_tag(owner, "WORK".to_string(), &state).len(), 2);
+
+        update_file_metadata(owner, a, request(None, Some(vec![])), &mut state).unwrap();
+        assert_eq!(list_files_by_tag(owner, "work".to_string(), &state)[0].file_id, b);
+
+        crate::api::delete_file(&mut state, owner, b);
+        assert!(list_files_by_tag(owner, "work".to_string(), &state).is_empty());
+        assert!(state.file_tags.is_empty());
+    }
+
+    #[test]
+    fn invalid_updates_are_rejected() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner);
+
+        assert_eq!(
+            update_file_metadata(owner, file_id, request(Some("  "), None), &mut state),
+            Err(UpdateFileMetadataError::InvalidFileName)
+        );
+        assert_eq!(
+            update_file_metadata(owner, file_id, request(Some("ok.jpg"), Some(vec![""])), &mut state),
+            Err(UpdateFileMetadataError::InvalidTags)
+        );
+        // Nothing is applied when part of the request is invalid.
+        assert_eq!(state.file_data[&file_id].metadata.file_name, "IMG_0001.jpg");
+        assert_eq!(
+            update_file_metadata(Principal::from_slice(&[9]), file_id, request(Some("x"), None), &mut state),
+            Err(UpdateFileMetadataError::NotFound)
+        );
+    }
+}
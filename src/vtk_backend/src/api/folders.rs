@@ -0,0 +1,356 @@
+use crate::{PublicFileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+// Each user has a folder tree. Folders are identified by ID and their names are
+// unique among siblings, so they can also be addressed by path ("/taxes/2024").
+// Files start out in the root folder (`None`) and can be moved into a folder.
+
+pub const FOLDER_PAGE_SIZE: usize = 100;
+const MAX_FOLDER_NAME_LEN: usize = 255;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Folder {
+    pub folder_id: u64,
+    pub owner: Principal,
+    pub name: String,
+    pub parent: Option<u64>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum FolderLocator {
+    #[serde(rename = "id")]
+    Id(u64),
+    // Slash-separated folder names from the root; "/" is the root itself.
+    #[serde(rename = "path")]
+    Path(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FolderListing {
+    // `None` for the root folder.
+    pub folder: Option<Folder>,
+    pub folders: Vec<Folder>,
+    pub files: Vec<PublicFileMetadata>,
+    pub page: u64,
+    pub has_more: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum FolderError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "invalid_name")]
+    InvalidName,
+    #[serde(rename = "name_taken")]
+    NameTaken,
+    // Moving a folder into itself or one of its subfolders.
+    #[serde(rename = "invalid_move")]
+    InvalidMove,
+}
+
+fn check_caller(caller: Principal) -> Result<(), FolderError> {
+    if caller == Principal::anonymous() {
+        return Err(FolderError::NotAuthenticated);
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<String, FolderError> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_FOLDER_NAME_LEN || name.contains('/') {
+        return Err(FolderError::InvalidName);
+    }
+    Ok(name.to_string())
+}
+
+// The caller's folder with this ID; other users' folders are reported as not found.
+fn owned_folder(caller: Principal, folder_id: u64, state: &State) -> Result<&Folder, FolderError> {
+    state
+        .folders
+        .get(&folder_id)
+        .filter(|folder| folder.owner == caller)
+        .ok_or(FolderError::NotFound)
+}
+
+fn check_parent(caller: Principal, parent: Option<u64>, state: &State) -> Result<(), FolderError> {
+    match parent {
+        Some(parent) => owned_folder(caller, parent, state).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+fn child_named(caller: Principal, parent: Option<u64>, name: &str, state: &State) -> Option<u64> {
+    state
+        .subfolders
+        .get(&(caller, parent))?
+        .iter()
+        .find(|id| state.folders.get(id).map_or(false, |f| f.name == name))
+        .copied()
+}
+
+pub fn create_folder(
+    caller: Principal,
+    name: String,
+    parent: Option<u64>,
+    state: &mut State,
+) -> Result<Folder, FolderError> {
+    check_caller(caller)?;
+    let name = validate_name(&name)?;
+    check_parent(caller, parent, state)?;
+    if child_named(caller, parent, &name, state).is_some() {
+        return Err(FolderError::NameTaken);
+    }
+
+    let folder_id = state.folder_count;
+    state.folder_count += 1;
+    let folder = Folder {
+        folder_id,
+        owner: caller,
+        name,
+        parent,
+        created_at: crate::get_time(),
+    };
+    state.folders.insert(folder_id, folder.clone());
+    state.subfolders.entry((caller, parent)).or_default().insert(folder_id);
+    Ok(folder)
+}
+
+pub fn rename_folder(caller: Principal, folder_id: u64, name: String, state: &mut State) -> Result<Folder, FolderError> {
+    check_caller(caller)?;
+    let name = validate_name(&name)?;
+    let parent = owned_folder(caller, folder_id, state)?.parent;
+    match child_named(caller, parent, &name, state) {
+        Some(id) if id != folder_id => return Err(FolderError::NameTaken),
+        _ => {}
+    }
+    let folder = state.folders.get_mut(&folder_id).expect("folder exists");
+    folder.name = name;
+    Ok(folder.clone())
+}
+
+fn is_same_or_descendant(folder_id: u64, ancestor: u64, state: &State) -> bool {
+    let mut current = Some(folder_id);
+    while let Some(id) = current {
+        if id == ancestor {
+            return true;
+        }
+        current = state.folders.get(&id).and_then(|f| f.parent);
+    }
+    false
+}
+
+pub fn move_folder(
+    caller: Principal,
+    folder_id: u64,
+    new_parent: Option<u64>,
+    state: &mut State,
+) -> Result<Folder, FolderError> {
+    check_caller(caller)?;
+    let folder = owned_folder(caller, folder_id, state)?.clone();
+    check_parent(caller, new_parent, state)?;
+    if new_parent.map_or(false, |parent| is_same_or_descendant(parent, folder_id, state)) {
+        return Err(FolderError::InvalidMove);
+    }
+    if child_named(caller, new_parent, &folder.name, state).is_some_and(|id| id != folder_id) {
+        return Err(FolderError::NameTaken);
+    }
+
+    if let Some(siblings) = state.subfolders.get_mut(&(caller, folder.parent)) {
+        siblings.remove(&folder_id);
+    }
+    state.subfolders.entry((caller, new_parent)).or_default().insert(folder_id);
+    let folder = state.folders.get_mut(&folder_id).expect("folder exists");
+    folder.parent = new_parent;
+    Ok(folder.clone())
+}
+
+/// Moves one of the caller's files into a folder, or back to the root with `None`.
+pub fn move_file(caller: Principal, file_id: u64, folder: Option<u64>, state: &mut State) -> Result<(), FolderError> {
+    check_caller(caller)?;
+    if !state.is_file_owner(caller, file_id) {
+        return Err(FolderError::NotFound);
+    }
+    check_parent(caller, folder, state)?;
+    remove_file_from_folder(state, file_id);
+    if let Some(folder_id) = folder {
+        state.file_folders.insert(file_id, folder_id);
+        state.folder_files.entry(folder_id).or_default().insert(file_id);
+    }
+    Ok(())
+}
+
+/// Takes a file out of its folder, putting it back in the root.
+pub(crate) fn remove_file_from_folder(state: &mut State, file_id: u64) {
+    if let Some(folder_id) = state.file_folders.remove(&file_id) {
+        if let Some(files) = state.folder_files.get_mut(&folder_id) {
+            files.remove(&file_id);
+        }
+    }
+}
+
+/// Deletes a folder with all its subfolders, moving the files in them to the trash.
+pub fn delete_folder(caller: Principal, folder_id: u64, state: &mut State) -> Result<(), FolderError> {
+    check_caller(caller)?;
+    let parent = owned_folder(caller, folder_id, state)?.parent;
+    if let Some(siblings) = state.subfolders.get_mut(&(caller, parent)) {
+        siblings.remove(&folder_id);
+    }
+
+    let mut pending = vec![folder_id];
+    while let Some(id) = pending.pop() {
+        pending.extend(state.subfolders.remove(&(caller, Some(id))).unwrap_or_default());
+        for file_id in state.folder_files.remove(&id).unwrap_or_default() {
+            crate::api::delete_file(state, caller, file_id);
+        }
+        state.folders.remove(&id);
+    }
+    Ok(())
+}
+
+fn resolve(caller: Principal, locator: &FolderLocator, state: &State) -> Result<Option<u64>, FolderError> {
+    match locator {
+        FolderLocator::Id(folder_id) => owned_folder(caller, *folder_id, state).map(|f| Some(f.folder_id)),
+        FolderLocator::Path(path) => {
+            let mut current = None;
+            for name in path.split('/').filter(|name| !name.is_empty()) {
+                current = Some(child_named(caller, current, name, state).ok_or(FolderError::NotFound)?);
+            }
+            Ok(current)
+        }
+    }
+}
+
+/// Lists a folder's subfolders followed by its files, `FOLDER_PAGE_SIZE` entries per page.
+pub fn list_folder(
+    caller: Principal,
+    locator: FolderLocator,
+    page: u64,
+    state: &State,
+) -> Result<FolderListing, FolderError> {
+    check_caller(caller)?;
+    let folder_id = resolve(caller, &locator, state)?;
+
+    let empty = BTreeSet::new();
+    let folder_ids = state.subfolders.get(&(caller, folder_id)).unwrap_or(&empty);
+    let file_ids: Vec<u64> = match folder_id {
+        Some(id) => state.folder_files.get(&id).map(|files| files.iter().copied().collect()).unwrap_or_default(),
+        None => state
+            .file_owners
+            .get(&caller)
+            .map(|files| files.iter().copied().filter(|id| !state.file_folders.contains_key(id)).collect())
+            .unwrap_or_default(),
+    };
+
+    let skip = (page as usize).saturating_mul(FOLDER_PAGE_SIZE);
+    let total = folder_ids.len() + file_ids.len();
+    let folders: Vec<Folder> = folder_ids
+        .iter()
+        .skip(skip)
+        .take(FOLDER_PAGE_SIZE)
+        .filter_map(|id| state.folders.get(id).cloned())
+        .collect();
+    let files: Vec<PublicFileMetadata> = file_ids
+        .iter()
+        .skip(skip.saturating_sub(folder_ids.len()))
+        .take(FOLDER_PAGE_SIZE - folders.len())
+        .filter_map(|&id| state.public_file_metadata(id))
+        .collect();
+
+    Ok(FolderListing {
+        folder: folder_id.and_then(|id| state.folders.get(&id).cloned()),
+        folders,
+        files,
+        page,
+        has_more: skip + FOLDER_PAGE_SIZE < total,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{upload_file_atomic, UploadFileAtomicRequest};
+
+    fn upload(state: &mut State, owner: Principal) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "scan.pdf".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "application/pdf".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    #[test]
+    fn folders_are_addressed_by_id_or_path() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let taxes = create_folder(owner, "taxes".to_string(), None, &mut state).unwrap();
+        let year = create_folder(owner, "2024".to_string(), Some(taxes.folder_id), &mut state).unwrap();
+        let file_id = upload(&mut state, owner);
+        move_file(owner, file_id, Some(year.folder_id), &mut state).unwrap();
+
+        let listing = list_folder(owner, FolderLocator::Path("/taxes/2024".to_string()), 0, &state).unwrap();
+        assert_eq!(listing.folder, Some(year.clone()));
+        assert_eq!(listing.files.len(), 1);
+
+        let root = list_folder(owner, FolderLocator::Path("/".to_string()), 0, &state).unwrap();
+        assert_eq!(root.folders, vec![taxes.clone()]);
+        assert!(root.files.is_empty());
+
+        assert_eq!(
+            create_folder(owner, "2024".to_string(), Some(taxes.folder_id), &mut state),
+            Err(FolderError::NameTaken)
+        );
+        assert_eq!(
+            list_folder(Principal::from_slice(&[9]), FolderLocator::Id(taxes.folder_id), 0, &state),
+            Err(FolderError::NotFound)
+        );
+    }
+
+    #[test]
+    fn folders_cannot_move_into_themselves() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let a = create_folder(owner, "a".to_string(), None, &mut state).unwrap();
+        let b = create_folder(owner, "b".to_string(), Some(a.folder_id), &mut state).unwrap();
+
+        assert_eq!(move_folder(owner, a.folder_id, Some(b.folder_id), &mut state), Err(FolderError::InvalidMove));
+        assert_eq!(move_folder(owner, a.folder_id, Some(a.folder_id), &mut state), Err(FolderError::InvalidMove));
+
+        let moved = move_folder(owner, b.folder_id, None, &mut state).unwrap();
+        assert_eq!(moved.parent, None);
+        let renamed = rename_folder(owner, b.folder_id, "archive".to_string(), &mut state).unwrap();
+        assert_eq!(renamed.name, "archive");
+        assert_eq!(
+            list_folder(owner, FolderLocator::Path("/".to_string()), 0, &state).unwrap().folders.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn deleting_a_folder_deletes_its_contents() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let a = create_folder(owner, "a".to_string(), None, &mut state).unwrap();
+        let b = create_folder(owner, "b".to_string(), Some(a.folder_id), &mut state).unwrap();
+        let file_id = upload(&mut state, owner);
+        move_file(owner, file_id, Some(b.folder_id), &mut state).unwrap();
+
+        delete_folder(owner, a.folder_id, &mut state).unwrap();
+        assert!(state.folders.is_empty());
+        assert!(list_folder(owner, FolderLocator::Path("/".to_string()), 0, &state).unwrap().folders.is_empty());
+        assert_eq!(crate::api::list_trash(owner, &state)[0].file_id, file_id);
+
+        crate::api::empty_trash(owner, &mut state).unwrap();
+        assert!(state.file_data.get(&file_id).is_none());
+        assert_eq!(state.file_contents.get(file_id, 0), None);
+    }
+}
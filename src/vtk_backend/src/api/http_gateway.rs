@@ -0,0 +1,695 @@
+use crate::certification::{encode_expr_path, encode_witness, sha256};
+use crate::{with_state, with_state_mut, FileContent, State};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::{define_function, CandidType, Principal};
+use ic_cdk::api::management_canister::main::raw_rand;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use ic_certified_map::Hash;
+use sha2::{Digest, Sha256};
+
+// Serves uploaded files to browsers and `curl` through the HTTP gateway at
+// `/f/<token>`. A token is an unguessable download link the owner creates for a
+// file; anyone holding it can fetch the (client-side encrypted) contents. The
+// first chunk is returned with the response and the rest is streamed through
+// `http_request_streaming_callback`, one chunk per callback.
+//
+// A single `Range: bytes=...` is answered with `206 Partial Content` holding
+// the whole chunk the range starts in, which HTTP allows and clients follow up
+// on. Byte offsets are mapped onto chunks knowing every chunk but the last has
+// the size of the first one, and the last at most that: uploads reject other
+// chunks.
+//
+// Full responses carry an `IC-Certificate` header proving the SHA-256 of the
+// whole body, which is certified under `http_assets/f/<token>` when the link is
+// created. As partial responses are whole chunks, there are only as many as
+// chunks, and each is certified under `http_expr/f/<token>` with the gateway's
+// v2 response certification, covering its status, body, `Content-Type` and
+// `Content-Range`.
+
+const LINK_PATH_PREFIX: &str = "/f/";
+
+// The certification expression of range responses: no part of the request is
+// certified, and of the response its status, body and these headers.
+const RANGE_CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\",\"content-range\"]}}}})";
+
+define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GatewayRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GatewayResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamingCallbackToken {
+    pub link: String,
+    pub chunk_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackResponse {
+    pub body: ByteBuf,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DownloadLinkError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "not_uploaded")]
+    NotUploaded,
+    #[serde(rename = "suspended")]
+    Suspended,
+    /// Only files stored on this canister can be served through the gateway.
+    #[serde(rename = "stored_in_bucket")]
+    StoredInBucket,
+}
+
+fn check_link_owner(caller: Principal, file_id: u64, state: &State) -> Result<(), DownloadLinkError> {
+    if caller == Principal::anonymous() {
+        return Err(DownloadLinkError::NotAuthenticated);
+    }
+    if !state.file_data.contains_key(&file_id) {
+        return Err(DownloadLinkError::NotFound);
+    }
+    if !state.is_file_owner(caller, file_id) {
+        return Err(DownloadLinkError::PermissionError);
+    }
+    Ok(())
+}
+
+fn insert_download_link(
+    caller: Principal,
+    file_id: u64,
+    link: String,
+    state: &mut State,
+) -> Result<String, DownloadLinkError> {
+    check_link_owner(caller, file_id, state)?;
+    let file = &state.file_data[&file_id];
+    let num_chunks = match &file.content {
+        FileContent::Uploaded { num_chunks, .. } => *num_chunks,
+        _ => return Err(DownloadLinkError::NotUploaded),
+    };
+    if file.metadata.bucket.is_some() {
+        return Err(DownloadLinkError::StoredInBucket);
+    }
+    let path = certify_link(&link, file_id, num_chunks, state);
+    state.certified.commit();
+    state.download_links.insert(link, file_id);
+    Ok(path)
+}
+
+// Certifies the bodies served at a link, the whole file and the range response of
+// every chunk, and returns the link's path. The file is hashed one chunk at a time,
+// so it is never held in memory at once.
+fn certify_link(link: &str, file_id: u64, num_chunks: u64, state: &mut State) -> String {
+    let path = format!("{LINK_PATH_PREFIX}{link}");
+    let (chunk_size, total) = file_layout(file_id, num_chunks, state);
+    let file_type = match &state.file_data[&file_id].content {
+        FileContent::Uploaded { file_type, .. } => file_type.clone(),
+        _ => String::new(),
+    };
+    let mut hasher = Sha256::new();
+    let mut range_responses = vec![];
+    let storage_id = state.storage_id(file_id);
+    for chunk_id in 0..num_chunks {
+        if let Some(contents) = state.file_contents.get(storage_id, chunk_id) {
+            hasher.update(&contents);
+            if !contents.is_empty() {
+                let content_range = chunk_range(chunk_id, chunk_size, contents.len() as u64, total);
+                range_responses.push(range_response_hash(&file_type, &content_range, &contents));
+            }
+        }
+    }
+    state.certified.certify_asset(&path, hasher.finalize().into());
+    state
+        .certified
+        .certify_range_responses(link, sha256(RANGE_CERTIFICATE_EXPRESSION.as_bytes()), &range_responses);
+    path
+}
+
+// The `Content-Range` of the range response serving `chunk_id`, `len` bytes long.
+fn chunk_range(chunk_id: u64, chunk_size: u64, len: u64, total: u64) -> String {
+    let start = chunk_id * chunk_size;
+    format!("bytes {start}-{}/{total}", start + len - 1)
+}
+
+// The hash the gateway computes of a range response to look it up under `http_expr`:
+// the representation-independent hash of its certified headers and status, followed
+// by the SHA-256 of its body.
+fn range_response_hash(file_type: &str, content_range: &str, body: &[u8]) -> Hash {
+    let mut fields: Vec<Vec<u8>> = [
+        ("content-type", sha256(file_type.as_bytes())),
+        ("content-range", sha256(content_range.as_bytes())),
+        ("ic-certificateexpression", sha256(RANGE_CERTIFICATE_EXPRESSION.as_bytes())),
+        (":ic-cert-status", sha256(&leb128(206))),
+    ]
+    .iter()
+    .map(|(name, value)| [sha256(name.as_bytes()).as_slice(), value.as_slice()].concat())
+    .collect();
+    fields.sort();
+    let headers_hash = sha256(&fields.concat());
+    sha256(&[headers_hash, sha256(body)].concat())
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Recertifies the links to a file whose contents changed, e.g. on a new version.
+pub(crate) fn recertify_download_links(state: &mut State, file_id: u64) {
+    // Links to bucket files can't be created, see `insert_download_link`.
+    let num_chunks = match state.file_data.get(&file_id) {
+        Some(file) if file.metadata.bucket.is_some() => return,
+        Some(file) => match &file.content {
+            FileContent::Uploaded { num_chunks, .. } => *num_chunks,
+            _ => return,
+        },
+        None => return,
+    };
+    let links: Vec<String> = state
+        .download_links
+        .iter()
+        .filter(|(_, &linked)| linked == file_id)
+        .map(|(link, _)| link.clone())
+        .collect();
+    for link in links {
+        certify_link(&link, file_id, num_chunks, state);
+    }
+    state.certified.commit();
+}
+
+/// Creates a download link for an uploaded file and returns its path, `/f/<token>`.
+pub async fn create_download_link(caller: Principal, file_id: u64) -> Result<String, DownloadLinkError> {
+    with_state(|s| {
+        if crate::api::admin::is_suspended(s, caller) {
+            return Err(DownloadLinkError::Suspended);
+        }
+        check_link_owner(caller, file_id, s)
+    })?;
+    let (random,) = raw_rand().await.unwrap_or_else(|(code, msg)| {
+        ic_cdk::trap(&format!("raw_rand failed: {code:?}: {msg}"))
+    });
+    let link = hex::encode(&random[..16]);
+    with_state_mut(|s| insert_download_link(caller, file_id, link, s))
+}
+
+/// Invalidates a download link. Only the owner of the linked file can revoke it.
+pub fn revoke_download_link(caller: Principal, link: String, state: &mut State) -> Result<(), DownloadLinkError> {
+    let file_id = *state.download_links.get(&link).ok_or(DownloadLinkError::NotFound)?;
+    check_link_owner(caller, file_id, state)?;
+    state.download_links.remove(&link);
+    state.certified.remove_asset(&format!("{LINK_PATH_PREFIX}{link}"));
+    state.certified.remove_range_responses(&link);
+    state.certified.commit();
+    Ok(())
+}
+
+/// Drops every download link pointing to `file_id`.
+pub(crate) fn remove_download_links(state: &mut State, file_id: u64) {
+    let links: Vec<String> = state
+        .download_links
+        .iter()
+        .filter(|(_, &linked)| linked == file_id)
+        .map(|(link, _)| link.clone())
+        .collect();
+    for link in links {
+        state.download_links.remove(&link);
+        state.certified.remove_asset(&format!("{LINK_PATH_PREFIX}{link}"));
+        state.certified.remove_range_responses(&link);
+    }
+    state.certified.commit();
+}
+
+// The `IC-Certificate` header proving the body served at `/f/<link>`.
+fn certificate_header(certificate: &[u8], link: &str, state: &State) -> (String, String) {
+    let witness = state.certified.asset_witness(&format!("{LINK_PATH_PREFIX}{link}"));
+    let value = format!(
+        "certificate=:{}:, tree=:{}:",
+        BASE64.encode(certificate),
+        BASE64.encode(encode_witness(&witness))
+    );
+    ("IC-Certificate".to_string(), value)
+}
+
+// The `IC-Certificate` header proving a range response of `/f/<link>`, by the hash
+// of the whole response.
+fn range_certificate_header(certificate: &[u8], link: &str, response_hash: Hash, state: &State) -> (String, String) {
+    let witness = state.certified.range_response_witness(
+        link,
+        sha256(RANGE_CERTIFICATE_EXPRESSION.as_bytes()),
+        response_hash,
+    );
+    let value = format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=:2:",
+        BASE64.encode(certificate),
+        BASE64.encode(encode_witness(&witness)),
+        BASE64.encode(encode_expr_path(link))
+    );
+    ("IC-Certificate".to_string(), value)
+}
+
+fn text_response(status_code: u16, text: &str) -> GatewayResponse {
+    GatewayResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: ByteBuf::from(text.as_bytes()),
+        streaming_strategy: None,
+    }
+}
+
+// The link token in a `/f/<token>` URL, ignoring any query string.
+fn link_from_url(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    path.strip_prefix(LINK_PATH_PREFIX).filter(|link| !link.is_empty() && !link.contains('/'))
+}
+
+// The locally stored chunks of the file behind `link`, as (file_id, file_type, num_chunks).
+fn linked_file<'a>(link: &str, state: &'a State) -> Result<(u64, &'a str, u64), GatewayResponse> {
+    let file_id = *state
+        .download_links
+        .get(link)
+        .ok_or_else(|| text_response(404, "Not found"))?;
+    let file = state.file_data.get(&file_id).ok_or_else(|| text_response(404, "Not found"))?;
+    match &file.content {
+        FileContent::Uploaded { file_type, num_chunks, .. } if file.metadata.bucket.is_none() => {
+            Ok((file_id, file_type.as_str(), *num_chunks))
+        }
+        FileContent::Uploaded { .. } => Err(text_response(404, "File is stored in a bucket canister")),
+        _ => Err(text_response(404, "File is not uploaded yet")),
+    }
+}
+
+// Chunk size and total size of a file, from the lengths of its first and last chunk.
+fn file_layout(file_id: u64, num_chunks: u64, state: &State) -> (u64, u64) {
+    let storage_id = state.storage_id(file_id);
+    let chunk_len = |chunk_id| state.file_contents.chunk_len(storage_id, chunk_id).unwrap_or(0);
+    let chunk_size = chunk_len(0);
+    if num_chunks <= 1 {
+        return (chunk_size, chunk_size);
+    }
+    (chunk_size, chunk_size * (num_chunks - 1) + chunk_len(num_chunks - 1))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    // No usable `Range` header: serve the whole file.
+    Full,
+    // First and last byte, both inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(headers: &[(String, String)], total: u64) -> ByteRange {
+    let Some((_, value)) = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("range")) else {
+        return ByteRange::Full;
+    };
+    // Multiple ranges are not supported; serving the whole file is a valid answer to them.
+    let Some(spec) = value.trim().strip_prefix("bytes=").filter(|spec| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(total.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, total.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (total.saturating_sub(suffix), total.saturating_sub(1)),
+        _ => return ByteRange::Full,
+    };
+    if start >= total {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+fn next_token(link: &str, chunk_id: u64, num_chunks: u64) -> Option<StreamingCallbackToken> {
+    let next = chunk_id + 1;
+    (next < num_chunks).then(|| StreamingCallbackToken {
+        link: link.to_string(),
+        chunk_id: next,
+    })
+}
+
+/// Answers a gateway request; `canister_id` is this canister's, where the streaming callback is called.
+pub fn http_request(
+    canister_id: Principal,
+    req: GatewayRequest,
+    certificate: Option<Vec<u8>>,
+    state: &State,
+) -> GatewayResponse {
+    if req.method != "GET" && req.method != "HEAD" {
+        return text_response(405, "Method not allowed");
+    }
+    let Some(link) = link_from_url(&req.url) else {
+        return text_response(404, "Not found");
+    };
+    let (file_id, file_type, num_chunks) = match linked_file(link, state) {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+
+    let (chunk_size, total) = file_layout(file_id, num_chunks, state);
+    let mut headers = vec![
+        ("Content-Type".to_string(), file_type.to_string()),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+    ];
+    let range_start = match parse_range(&req.headers, total) {
+        ByteRange::Full => None,
+        ByteRange::Partial(start, _) => Some(start),
+        ByteRange::Unsatisfiable => {
+            headers.push(("Content-Range".to_string(), format!("bytes */{total}")));
+            return GatewayResponse {
+                status_code: 416,
+                headers,
+                body: ByteBuf::new(),
+                streaming_strategy: None,
+            };
+        }
+    };
+    let storage_id = state.storage_id(file_id);
+
+    let Some(start) = range_start else {
+        headers.push(("Content-Length".to_string(), total.to_string()));
+        if let Some(certificate) = &certificate {
+            headers.push(certificate_header(certificate, link, state));
+        }
+        if req.method == "HEAD" {
+            return GatewayResponse {
+                status_code: 200,
+                headers,
+                body: ByteBuf::new(),
+                streaming_strategy: None,
+            };
+        }
+        return GatewayResponse {
+            status_code: 200,
+            headers,
+            body: ByteBuf::from(state.file_contents.get(storage_id, 0).unwrap_or_default()),
+            streaming_strategy: next_token(link, 0, num_chunks).map(|token| StreamingStrategy::Callback {
+                callback: StreamingCallback::new(canister_id, "http_request_streaming_callback".to_string()),
+                token,
+            }),
+        };
+    };
+
+    // A range is satisfiable only if the file isn't empty, so neither is its first chunk.
+    let chunk_id = start / chunk_size;
+    let body = state.file_contents.get(storage_id, chunk_id).unwrap_or_default();
+    let content_range = chunk_range(chunk_id, chunk_size, body.len() as u64, total);
+    headers.push(("Content-Range".to_string(), content_range.clone()));
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    if req.method == "HEAD" {
+        return GatewayResponse {
+            status_code: 206,
+            headers,
+            body: ByteBuf::new(),
+            streaming_strategy: None,
+        };
+    }
+    if let Some(certificate) = &certificate {
+        let response_hash = range_response_hash(file_type, &content_range, &body);
+        headers.push(range_certificate_header(certificate, link, response_hash, state));
+        headers.push(("IC-CertificateExpression".to_string(), RANGE_CERTIFICATE_EXPRESSION.to_string()));
+    }
+    GatewayResponse {
+        status_code: 206,
+        headers,
+        body: ByteBuf::from(body),
+        streaming_strategy: None,
+    }
+}
+
+pub fn http_request_streaming_callback(token: StreamingCallbackToken, state: &State) -> StreamingCallbackResponse {
+    // The link is looked up again so a revoked link stops streaming.
+    let (file_id, _, num_chunks) = linked_file(&token.link, state)
+        .unwrap_or_else(|_| ic_cdk::trap("download link is no longer valid"));
+    let body = state
+        .file_contents
+        .get(state.storage_id(file_id), token.chunk_id)
+        .unwrap_or_else(|| ic_cdk::trap("chunk not found"));
+    StreamingCallbackResponse {
+        body: ByteBuf::from(body),
+        token: next_token(&token.link, token.chunk_id, num_chunks),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk_store::HeapChunkStore;
+    use crate::{File, FileMetadata};
+
+    fn state_with_file(owner: Principal, num_chunks: u64) -> State {
+        let mut state = State::with_chunk_store(Box::new(HeapChunkStore::default()));
+        state.file_data.insert(
+            0,
+            File {
+                metadata: FileMetadata {
+                    file_name: "movie.mp4".to_string(),
+                    requester_principal: owner,
+                    requested_at: 12345,
+                    uploaded_at: Some(12345),
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
+                },
+                content: FileContent::Uploaded {
+                    num_chunks,
+                    file_type: "video/mp4".to_string(),
+                    owner_key: vec![],
+                },
+            },
+        );
+        state.file_owners.insert(owner, vec![0]);
+        for chunk_id in 0..num_chunks {
+            state.file_contents.put(0, chunk_id, vec![chunk_id as u8; 3]);
+        }
+        state
+    }
+
+    fn canister() -> Principal {
+        Principal::from_slice(&[42])
+    }
+
+    fn get(url: &str) -> GatewayRequest {
+        GatewayRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        }
+    }
+
+    fn get_range(url: &str, range: &str) -> GatewayRequest {
+        GatewayRequest {
+            headers: vec![("Range".to_string(), range.to_string())],
+            ..get(url)
+        }
+    }
+
+    fn stream(mut token: Option<StreamingCallbackToken>, state: &State) -> Vec<u8> {
+        let mut body = vec![];
+        while let Some(t) = token {
+            let response = http_request_streaming_callback(t, state);
+            body.extend_from_slice(&response.body);
+            token = response.token;
+        }
+        body
+    }
+
+    #[test]
+    fn only_owners_create_links() {
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[9]);
+        let mut state = state_with_file(owner, 1);
+
+        assert_eq!(
+            insert_download_link(other, 0, "abc".to_string(), &mut state),
+            Err(DownloadLinkError::PermissionError)
+        );
+        assert_eq!(
+            insert_download_link(owner, 1, "abc".to_string(), &mut state),
+            Err(DownloadLinkError::NotFound)
+        );
+        assert_eq!(insert_download_link(owner, 0, "abc".to_string(), &mut state), Ok("/f/abc".to_string()));
+        assert_eq!(revoke_download_link(other, "abc".to_string(), &mut state), Err(DownloadLinkError::PermissionError));
+        assert_eq!(revoke_download_link(owner, "abc".to_string(), &mut state), Ok(()));
+        assert_eq!(http_request(canister(), get("/f/abc"), None, &state).status_code, 404);
+    }
+
+    #[test]
+    fn bucket_files_get_no_links() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 1);
+        state.file_data.get_mut(&0).unwrap().metadata.bucket = Some(Principal::from_slice(&[5]));
+
+        assert_eq!(
+            insert_download_link(owner, 0, "abc".to_string(), &mut state),
+            Err(DownloadLinkError::StoredInBucket)
+        );
+        assert!(state.download_links.is_empty());
+    }
+
+    #[test]
+    fn single_chunk_file_is_served_whole() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 1);
+        insert_download_link(owner, 0, "abc".to_string(), &mut state).unwrap();
+
+        let response = http_request(canister(), get("/f/abc?download=1"), None, &state);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body.as_slice(), &[0, 0, 0]);
+        assert!(response.streaming_strategy.is_none());
+        assert!(response.headers.contains(&("Content-Type".to_string(), "video/mp4".to_string())));
+        assert!(response.headers.contains(&("Content-Length".to_string(), "3".to_string())));
+    }
+
+    #[test]
+    fn full_responses_are_certified() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 1);
+        insert_download_link(owner, 0, "abc".to_string(), &mut state).unwrap();
+        assert_eq!(state.certified.asset_witness("/f/abc").reconstruct(), state.certified.root_hash());
+
+        let has_certificate =
+            |response: &GatewayResponse| response.headers.iter().any(|(name, _)| name == "IC-Certificate");
+        assert!(has_certificate(&http_request(canister(), get("/f/abc"), Some(vec![1]), &state)));
+    }
+
+    #[test]
+    fn range_responses_are_certified() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 3);
+        insert_download_link(owner, 0, "abc".to_string(), &mut state).unwrap();
+
+        let response = http_request(canister(), get_range("/f/abc", "bytes=4-"), Some(vec![1]), &state);
+        assert_eq!(response.status_code, 206);
+        let header = |name: &str| {
+            response.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone()).unwrap()
+        };
+        assert!(header("IC-Certificate").ends_with("version=:2:"));
+        assert!(header("IC-Certificate").contains(&format!("expr_path=:{}:", BASE64.encode(encode_expr_path("abc")))));
+        assert_eq!(header("IC-CertificateExpression"), RANGE_CERTIFICATE_EXPRESSION);
+
+        // The witness proves the hash of what was served, so it is the one of the header.
+        let response_hash = range_response_hash(&header("Content-Type"), &header("Content-Range"), &response.body);
+        let witness = state.certified.range_response_witness(
+            "abc",
+            sha256(RANGE_CERTIFICATE_EXPRESSION.as_bytes()),
+            response_hash,
+        );
+        assert_eq!(witness.reconstruct(), state.certified.root_hash());
+        let tree = BASE64.encode(encode_witness(&witness));
+        assert!(header("IC-Certificate").contains(&format!("tree=:{tree}:")));
+
+        // Revoking the link drops its certified responses with the full body's.
+        let linked = state.certified.root_hash();
+        revoke_download_link(owner, "abc".to_string(), &mut state).unwrap();
+        assert_ne!(state.certified.root_hash(), linked);
+    }
+
+    #[test]
+    fn response_hashes_follow_the_status_encoding() {
+        assert_eq!(leb128(206), vec![0xce, 0x01]);
+        assert_eq!(leb128(5), vec![5]);
+    }
+
+    #[test]
+    fn remaining_chunks_are_streamed() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 3);
+        insert_download_link(owner, 0, "abc".to_string(), &mut state).unwrap();
+
+        let response = http_request(canister(), get("/f/abc"), None, &state);
+        assert_eq!(response.body.as_slice(), &[0, 0, 0]);
+        match response.streaming_strategy {
+            Some(StreamingStrategy::Callback { callback, token }) => {
+                assert_eq!(callback.0.principal, canister());
+                assert_eq!(stream(Some(token), &state), vec![1, 1, 1, 2, 2, 2]);
+            }
+            None => panic!("expected the remaining chunks to be streamed"),
+        }
+    }
+
+    #[test]
+    fn range_headers_are_parsed() {
+        let range = |value: &str| parse_range(&[("range".to_string(), value.to_string())], 100);
+        assert_eq!(parse_range(&[], 100), ByteRange::Full);
+        assert_eq!(range("bytes=10-19"), ByteRange::Partial(10, 19));
+        assert_eq!(range("bytes=90-"), ByteRange::Partial(90, 99));
+        assert_eq!(range("bytes=-5"), ByteRange::Partial(95, 99));
+        assert_eq!(range("bytes=50-500"), ByteRange::Partial(50, 99));
+        assert_eq!(range("bytes=100-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,5-6"), ByteRange::Full);
+        assert_eq!(range("bytes=9-3"), ByteRange::Full);
+        assert_eq!(range("items=0-1"), ByteRange::Full);
+    }
+
+    #[test]
+    fn ranges_map_onto_chunks() {
+        let owner = Principal::from_slice(&[1]);
+        let mut state = state_with_file(owner, 3);
+        insert_download_link(owner, 0, "abc".to_string(), &mut state).unwrap();
+
+        // Bytes 4..=6 start inside chunk 1: that chunk is served, and the client asks
+        // for the rest.
+        let response = http_request(canister(), get_range("/f/abc", "bytes=4-6"), None, &state);
+        assert_eq!(response.status_code, 206);
+        assert!(response.headers.contains(&("Content-Range".to_string(), "bytes 3-5/9".to_string())));
+        assert!(response.headers.contains(&("Content-Length".to_string(), "3".to_string())));
+        assert_eq!(response.body.as_slice(), &[1, 1, 1]);
+        assert!(response.streaming_strategy.is_none());
+
+        let response = http_request(canister(), get_range("/f/abc", "bytes=-2"), None, &state);
+        assert!(response.headers.contains(&("Content-Range".to_string(), "bytes 6-8/9".to_string())));
+        assert_eq!(response.body.as_slice(), &[2, 2, 2]);
+
+        let response = http_request(canister(), get_range("/f/abc", "bytes=9-"), None, &state);
+        assert_eq!(response.status_code, 416);
+        assert!(response.headers.contains(&("Content-Range".to_string(), "bytes */9".to_string())));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let state = State::default();
+        assert_eq!(http_request(canister(), get("/"), None, &state).status_code, 404);
+        assert_eq!(http_request(canister(), get("/f/"), None, &state).status_code, 404);
+        assert_eq!(http_request(canister(), get("/f/missing"), None, &state).status_code, 404);
+    }
+}
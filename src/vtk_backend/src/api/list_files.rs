@@ -0,0 +1,68 @@
+use crate::certification::encode_witness;
+use crate::{PublicFileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// `list_files` together with what a client needs to check it against the certified data.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertifiedFileList {
+    pub files: Vec<PublicFileMetadata>,
+    pub certificate: Option<Vec<u8>>,
+    // CBOR hash tree revealing `files/<caller>`, the metadata hashes of every listed file.
+    pub witness: Vec<u8>,
+}
+
+pub fn list_files(caller: Principal, state: &State) -> Vec<PublicFileMetadata> {
+    // If caller is anonymous, return empty list
+    if caller == Principal::anonymous() {
+        panic!("Not authenticated");
+    }
+
+    // Get files owned by the caller
+    let empty_vec = Vec::new();
+    let owned_files = state.file_owners.get(&caller).unwrap_or(&empty_vec);
+
+    owned_files
+        .iter()
+        .filter_map(|&file_id| state.public_file_metadata(file_id))
+        .collect()
+}
+
+pub fn list_files_certified(caller: Principal, certificate: Option<Vec<u8>>, state: &State) -> CertifiedFileList {
+    CertifiedFileList {
+        files: list_files(caller, state),
+        certificate,
+        witness: encode_witness(&state.certified.files_witness(caller)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::upload_file_atomic;
+    use crate::api::UploadFileAtomicRequest;
+
+    #[test]
+    fn listing_is_covered_by_the_witness() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "notes.txt".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "text/plain".to_string(),
+                num_chunks: 1,
+            },
+            &mut state,
+        ).unwrap();
+
+        let listing = list_files_certified(owner, None, &state);
+        assert_eq!(listing.files.len(), 1);
+        assert_eq!(
+            state.certified.files_witness(owner).reconstruct(),
+            state.certified.root_hash()
+        );
+        assert!(!listing.witness.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{FileMetadata, State, with_state_mut};
+use crate::api::sui_ownership::{verify_blob_ownership, SuiOwnershipError};
+use crate::{FileMetadata, State, with_state, with_state_mut};
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +11,7 @@ pub struct RegisterFileRequest {
     pub blob_id: Option<String>,  // Only for Walrus
     pub requested_at: u64,
     pub uploaded_at: Option<u64>,
+    pub sui_object_id: Option<String>, // Sui Blob object, required when ownership checks are enabled
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
@@ -17,30 +19,68 @@ pub struct RegisterFileResponse {
     pub file_id: u64,
 }
 
-pub fn register_file(caller: Principal, req: RegisterFileRequest) -> RegisterFileResponse {
-    with_state_mut(|state: &mut State| {
-        let file_id = state.generate_file_id();
-        let metadata = FileMetadata {
-            file_name: req.file_name,
-            requester_principal: caller,
-            requested_at: req.requested_at,
-            uploaded_at: req.uploaded_at,
-            storage_provider: req.storage_provider,
-            blob_id: req.blob_id,
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum RegisterFileError {
+    #[serde(rename = "sui_ownership_error")]
+    SuiOwnershipError(SuiOwnershipError),
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+pub async fn register_file(
+    caller: Principal,
+    req: RegisterFileRequest,
+) -> Result<RegisterFileResponse, RegisterFileError> {
+    if with_state(|s| crate::api::admin::is_suspended(s, caller)) {
+        return Err(RegisterFileError::Suspended);
+    }
+    // When a Sui RPC endpoint is configured, Walrus blobs can only be registered
+    // by a principal that has linked the Sui address owning the blob object.
+    let rpc_url = with_state(|s| s.sui_rpc_url.clone());
+    if let (Some(rpc_url), "walrus") = (rpc_url, req.storage_provider.as_str()) {
+        let (object_id, blob_id) = match (&req.sui_object_id, &req.blob_id) {
+            (Some(object_id), Some(blob_id)) => (object_id.clone(), blob_id.clone()),
+            _ => {
+                return Err(RegisterFileError::SuiOwnershipError(
+                    SuiOwnershipError::MissingSuiObject,
+                ))
+            }
         };
-        // Insert into file_data with empty content for now
-        state.file_data.insert(file_id, crate::File {
-            metadata,
-            content: crate::FileContent::Pending { alias: String::new() },
-        });
-        
-        // Add the caller as the owner of this file
-        state
-            .file_owners
-            .entry(caller)
-            .or_insert_with(Vec::new)
-            .push(file_id);
-            
-        RegisterFileResponse { file_id }
-    })
-} 
\ No newline at end of file
+        verify_blob_ownership(caller, rpc_url, object_id, blob_id)
+            .await
+            .map_err(RegisterFileError::SuiOwnershipError)?;
+    }
+
+    Ok(with_state_mut(|state| insert_registered_file(caller, req, state)))
+}
+
+fn insert_registered_file(caller: Principal, req: RegisterFileRequest, state: &mut State) -> RegisterFileResponse {
+    let file_id = state.generate_file_id();
+    let metadata = FileMetadata {
+        file_name: req.file_name,
+        requester_principal: caller,
+        requested_at: req.requested_at,
+        uploaded_at: req.uploaded_at,
+        storage_provider: req.storage_provider,
+        blob_id: req.blob_id,
+        bucket: None,
+        description: String::new(),
+        tags: vec![],
+    };
+    // Insert into file_data with empty content for now
+    state.file_data.insert(file_id, crate::File {
+        metadata,
+        content: crate::FileContent::Pending { alias: String::new() },
+    });
+
+    // Add the caller as the owner of this file
+    state
+        .file_owners
+        .entry(caller)
+        .or_insert_with(Vec::new)
+        .push(file_id);
+
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+    RegisterFileResponse { file_id }
+}
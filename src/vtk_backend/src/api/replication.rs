@@ -0,0 +1,753 @@
+use super::download_walrus_chunk::{fetch_walrus_chunk, walrus_chunk_request, WALRUS_CHUNK_SIZE};
+use super::sui_ownership::{verify_blob_ownership, SuiOwnershipError};
+use crate::{with_state, with_state_mut, FileContent, FileDownloadResponse, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+
+/// Number of files whose replicas are checked on every verifier tick.
+pub const REPLICATION_BATCH_SIZE: usize = 5;
+
+const ICP: &str = "icp";
+const WALRUS: &str = "walrus";
+
+thread_local! {
+    static VERIFIER_RUNNING: Cell<bool> = Cell::new(false);
+}
+
+// Clears `VERIFIER_RUNNING` when the verifier returns, and also when an outcall
+// callback traps: ic-cdk drops the pending future during the callback's cleanup.
+struct VerifierGuard;
+
+impl VerifierGuard {
+    fn acquire() -> Option<Self> {
+        (!VERIFIER_RUNNING.with(|running| running.replace(true))).then_some(VerifierGuard)
+    }
+}
+
+impl Drop for VerifierGuard {
+    fn drop(&mut self) {
+        VERIFIER_RUNNING.with(|running| running.set(false));
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicationPolicy {
+    #[serde(rename = "icp")]
+    Icp,
+    #[serde(rename = "walrus")]
+    Walrus,
+    #[serde(rename = "icp+walrus")]
+    IcpAndWalrus,
+}
+
+impl ReplicationPolicy {
+    fn providers(&self) -> &'static [&'static str] {
+        match self {
+            ReplicationPolicy::Icp => &[ICP],
+            ReplicationPolicy::Walrus => &[WALRUS],
+            ReplicationPolicy::IcpAndWalrus => &[ICP, WALRUS],
+        }
+    }
+}
+
+/// Health of one copy of a file. The verifier restores a `Missing` or `Corrupted`
+/// canister copy from Walrus, but never repairs a Walrus copy: the owner uploads a
+/// new blob and records it with `add_walrus_replica`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReplicaHealth {
+    #[serde(rename = "unknown")]
+    Unknown,
+    #[serde(rename = "healthy")]
+    Healthy,
+    #[serde(rename = "missing")]
+    Missing,
+    #[serde(rename = "corrupted")]
+    Corrupted,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Replica {
+    pub provider: String, // "icp" or "walrus"
+    pub blob_id: Option<String>, // Only for Walrus
+    pub health: ReplicaHealth,
+    pub last_checked: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationInfo {
+    pub policy: ReplicationPolicy,
+    /// SHA-256 of the full (encrypted) file contents, used to check every replica.
+    pub sha256: Vec<u8>,
+    pub replicas: Vec<Replica>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SetReplicationPolicyRequest {
+    pub file_id: u64,
+    pub policy: ReplicationPolicy,
+    pub sha256: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum ReplicationError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "not_found_file")]
+    NotFoundFile,
+    #[serde(rename = "invalid_hash")]
+    InvalidHash,
+    #[serde(rename = "no_replication_policy")]
+    NoReplicationPolicy,
+    #[serde(rename = "provider_not_in_policy")]
+    ProviderNotInPolicy,
+    #[serde(rename = "sui_ownership_error")]
+    SuiOwnershipError(SuiOwnershipError),
+}
+
+fn check_owner(state: &State, caller: Principal, file_id: u64) -> Result<(), ReplicationError> {
+    if caller == Principal::anonymous() {
+        return Err(ReplicationError::NotAuthenticated);
+    }
+    if !state.file_data.contains_key(&file_id) {
+        return Err(ReplicationError::NotFoundFile);
+    }
+    if !state.is_file_owner(caller, file_id) {
+        return Err(ReplicationError::PermissionError);
+    }
+    Ok(())
+}
+
+/// Attaches a replication policy to a file. Existing copies are recorded as
+/// replicas to be verified, and every provider the policy asks for but the file
+/// doesn't have yet starts out as `Missing`. The verifier restores a missing
+/// canister copy from Walrus; a missing Walrus copy is uploaded by the client,
+/// which then records it with `add_walrus_replica`.
+pub fn set_replication_policy(
+    caller: Principal,
+    request: SetReplicationPolicyRequest,
+    state: &mut State,
+) -> Result<ReplicationInfo, ReplicationError> {
+    check_owner(state, caller, request.file_id)?;
+    if request.sha256.len() != 32 {
+        return Err(ReplicationError::InvalidHash);
+    }
+
+    let file = state.file_data.get(&request.file_id).unwrap();
+    let previous = state.file_replication.get(&request.file_id);
+
+    let replicas = request
+        .policy
+        .providers()
+        .iter()
+        .map(|&provider| {
+            if let Some(existing) =
+                previous.and_then(|info| info.replicas.iter().find(|r| r.provider == provider))
+            {
+                return existing.clone();
+            }
+            let (present, blob_id) = match provider {
+                ICP => (file.metadata.storage_provider == ICP, None),
+                _ => (file.metadata.blob_id.is_some(), file.metadata.blob_id.clone()),
+            };
+            Replica {
+                provider: provider.to_string(),
+                blob_id,
+                health: if present { ReplicaHealth::Unknown } else { ReplicaHealth::Missing },
+                last_checked: None,
+            }
+        })
+        .collect();
+
+    let info = ReplicationInfo {
+        policy: request.policy,
+        sha256: request.sha256,
+        replicas,
+    };
+    state.file_replication.insert(request.file_id, info.clone());
+    Ok(info)
+}
+
+/// Records a Walrus copy the client uploaded itself. It is verified on the next pass.
+///
+/// As with `register_file`, when a Sui RPC endpoint is configured the blob must be
+/// owned by a Sui address the caller linked, shown by its `sui_object_id`.
+pub async fn add_walrus_replica(
+    caller: Principal,
+    file_id: u64,
+    blob_id: String,
+    sui_object_id: Option<String>,
+) -> Result<ReplicationInfo, ReplicationError> {
+    let ownership_check = with_state(|s| walrus_replica_check(caller, file_id, sui_object_id, s))?;
+    if let Some((rpc_url, object_id)) = ownership_check {
+        verify_blob_ownership(caller, rpc_url, object_id, blob_id.clone())
+            .await
+            .map_err(ReplicationError::SuiOwnershipError)?;
+    }
+    with_state_mut(|s| record_walrus_replica(caller, file_id, blob_id, s))
+}
+
+// Checks a Walrus replica can be recorded before its blob is looked up on Sui, and
+// returns the RPC endpoint and object to look up, if ownership checks are enabled.
+fn walrus_replica_check(
+    caller: Principal,
+    file_id: u64,
+    sui_object_id: Option<String>,
+    state: &State,
+) -> Result<Option<(String, String)>, ReplicationError> {
+    check_owner(state, caller, file_id)?;
+    let info = state
+        .file_replication
+        .get(&file_id)
+        .ok_or(ReplicationError::NoReplicationPolicy)?;
+    if !info.replicas.iter().any(|r| r.provider == WALRUS) {
+        return Err(ReplicationError::ProviderNotInPolicy);
+    }
+    let Some(rpc_url) = state.sui_rpc_url.clone() else {
+        return Ok(None);
+    };
+    let object_id =
+        sui_object_id.ok_or(ReplicationError::SuiOwnershipError(SuiOwnershipError::MissingSuiObject))?;
+    Ok(Some((rpc_url, object_id)))
+}
+
+// Checked again after the ownership lookup, as the file may have changed meanwhile.
+fn record_walrus_replica(
+    caller: Principal,
+    file_id: u64,
+    blob_id: String,
+    state: &mut State,
+) -> Result<ReplicationInfo, ReplicationError> {
+    check_owner(state, caller, file_id)?;
+    let info = state
+        .file_replication
+        .get_mut(&file_id)
+        .ok_or(ReplicationError::NoReplicationPolicy)?;
+    let replica = info
+        .replicas
+        .iter_mut()
+        .find(|r| r.provider == WALRUS)
+        .ok_or(ReplicationError::ProviderNotInPolicy)?;
+    replica.blob_id = Some(blob_id);
+    replica.health = ReplicaHealth::Unknown;
+    Ok(info.clone())
+}
+
+pub fn get_replication_status(
+    caller: Principal,
+    file_id: u64,
+    state: &State,
+) -> Result<ReplicationInfo, ReplicationError> {
+    check_owner(state, caller, file_id)?;
+    state
+        .file_replication
+        .get(&file_id)
+        .cloned()
+        .ok_or(ReplicationError::NoReplicationPolicy)
+}
+
+/// Hash of the chunks stored on the canister, or `None` if the file isn't fully there.
+pub(crate) fn icp_content_hash(state: &State, file_id: u64) -> Option<Vec<u8>> {
+    let num_chunks = match state.file_data.get(&file_id).map(|f| &f.content) {
+        Some(FileContent::Uploaded { num_chunks, .. }) => *num_chunks,
+        _ => return None,
+    };
+    // Files placed in a bucket can't be read synchronously; they are checked by the bucket.
+    if state.file_data.get(&file_id).and_then(|f| f.metadata.bucket).is_some() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    let storage_id = state.storage_id(file_id);
+    for chunk_id in 0..num_chunks {
+        hasher.update(state.file_contents.get(storage_id, chunk_id)?);
+    }
+    Some(hasher.finalize().to_vec())
+}
+
+fn health_for_hash(actual: Option<Vec<u8>>, expected: &[u8]) -> ReplicaHealth {
+    match actual {
+        None => ReplicaHealth::Missing,
+        Some(hash) if hash == expected => ReplicaHealth::Healthy,
+        Some(_) => ReplicaHealth::Corrupted,
+    }
+}
+
+/// Picks the next batch of replicated files after the last one checked, wrapping around.
+pub(crate) fn next_files_to_verify(state: &State) -> Vec<u64> {
+    let start = state.replication_cursor.map_or(0, |last| last.saturating_add(1));
+    state
+        .file_replication
+        .range(start..)
+        .chain(state.file_replication.range(..start))
+        .map(|(&file_id, _)| file_id)
+        .take(REPLICATION_BATCH_SIZE)
+        .collect()
+}
+
+/// Timer entry point: checks a batch of files and repairs what it can.
+pub async fn run_replication_verifier() {
+    let Some(_guard) = VerifierGuard::acquire() else {
+        return;
+    };
+    let batch = with_state(next_files_to_verify);
+    for file_id in batch {
+        verify_file_replicas(file_id).await;
+        with_state_mut(|s| s.replication_cursor = Some(file_id));
+    }
+}
+
+async fn verify_file_replicas(file_id: u64) {
+    let (info, aggregator_url) = match with_state(|s| {
+        s.file_replication
+            .get(&file_id)
+            .cloned()
+            .map(|info| (info, s.walrus_aggregator_url.clone()))
+    }) {
+        Some(found) => found,
+        None => return,
+    };
+
+    for replica in &info.replicas {
+        let in_bucket = with_state(|s| {
+            s.file_data.get(&file_id).and_then(|f| f.metadata.bucket).is_some()
+        });
+        let health = match (replica.provider.as_str(), &replica.blob_id) {
+            (ICP, _) if in_bucket => continue,
+            (ICP, _) => with_state(|s| health_for_hash(icp_content_hash(s, file_id), &info.sha256)),
+            (WALRUS, Some(blob_id)) => match walrus_blob_hash(&aggregator_url, blob_id).await {
+                Ok(hash) => health_for_hash(hash, &info.sha256),
+                // Transient outcall failures say nothing about the replica itself.
+                Err(_) => continue,
+            },
+            _ => ReplicaHealth::Missing,
+        };
+        update_replica(file_id, &replica.provider, |r| {
+            r.health = health;
+            r.last_checked = Some(crate::get_time());
+        });
+    }
+
+    repair_file_replicas(file_id, &aggregator_url).await;
+}
+
+async fn repair_file_replicas(file_id: u64, aggregator_url: &str) {
+    let info = match with_state(|s| s.file_replication.get(&file_id).cloned()) {
+        Some(info) => info,
+        None => return,
+    };
+    let healthy = |provider: &str| {
+        info.replicas
+            .iter()
+            .any(|r| r.provider == provider && r.health == ReplicaHealth::Healthy)
+    };
+    let needs_repair = |r: &Replica| {
+        matches!(r.health, ReplicaHealth::Missing | ReplicaHealth::Corrupted)
+    };
+
+    for replica in info.replicas.iter().filter(|r| needs_repair(r)) {
+        match replica.provider.as_str() {
+            ICP if healthy(WALRUS) => {
+                let blob_id = info
+                    .replicas
+                    .iter()
+                    .find(|r| r.provider == WALRUS)
+                    .and_then(|r| r.blob_id.clone());
+                if let Some(blob_id) = blob_id {
+                    if copy_walrus_to_icp(file_id, aggregator_url, &blob_id).await.is_ok() {
+                        let health =
+                            with_state(|s| health_for_hash(icp_content_hash(s, file_id), &info.sha256));
+                        update_replica(file_id, ICP, |r| r.health = health);
+                    }
+                }
+            }
+            // Uploading to a Walrus publisher takes a PUT request, which HTTPS
+            // outcalls can't make, so a Walrus replica is left for the owner to restore.
+            _ => {}
+        }
+    }
+}
+
+fn update_replica(file_id: u64, provider: &str, f: impl FnOnce(&mut Replica)) {
+    with_state_mut(|s| {
+        if let Some(replica) = s
+            .file_replication
+            .get_mut(&file_id)
+            .and_then(|info| info.replicas.iter_mut().find(|r| r.provider == provider))
+        {
+            f(replica);
+        }
+    })
+}
+
+/// Streams the blob from the aggregator and hashes it. `Ok(None)` means the blob is gone.
+async fn walrus_blob_hash(aggregator_url: &str, blob_id: &str) -> Result<Option<Vec<u8>>, String> {
+    let mut hasher = Sha256::new();
+    let mut chunk_id = 0;
+    loop {
+        let request = walrus_chunk_request(aggregator_url, blob_id, chunk_id);
+        match fetch_walrus_chunk(&request).await {
+            FileDownloadResponse::FoundFile(data) => {
+                hasher.update(&data.contents);
+                chunk_id += 1;
+                if chunk_id >= data.num_chunks {
+                    return Ok(Some(hasher.finalize().to_vec()));
+                }
+            }
+            FileDownloadResponse::NotFoundFile => return Ok(None),
+            FileDownloadResponse::StorageProviderError(err) => return Err(err),
+            other => return Err(format!("unexpected response {other:?}")),
+        }
+    }
+}
+
+// Largest Walrus blob the verifier copies back onto the canister.
+const MAX_WALRUS_COPY_SIZE: u64 = crate::api::MAX_UPLOAD_SIZE;
+
+// Ingress messages are limited to 2 MiB.
+const MAX_SINGLE_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+// How the canister copy of a file is split: the chunk size, and the number of chunks
+// for a file that already has some here. A copy that only exists on Walrus (`None`)
+// is split into Walrus-sized chunks.
+fn canister_layout(state: &State, file_id: u64) -> Result<(u64, Option<u64>), String> {
+    let file = state.file_data.get(&file_id).ok_or("file was deleted")?;
+    if file.metadata.bucket.is_some() {
+        return Err("file is stored in a bucket canister".to_string());
+    }
+    if crate::api::is_trashed(state, file_id) {
+        return Err("file is in the trash".to_string());
+    }
+    let num_chunks = match &file.content {
+        FileContent::Pending { .. } => return Ok((WALRUS_CHUNK_SIZE, None)),
+        FileContent::PartiallyUploaded { .. } => return Err("file is still being uploaded".to_string()),
+        FileContent::Uploaded { num_chunks, .. } => *num_chunks,
+    };
+    if num_chunks <= 1 {
+        // A single chunk was uploaded in one ingress message.
+        return Ok((MAX_SINGLE_CHUNK_SIZE, Some(1)));
+    }
+    // Every chunk but the last has the same length, so any of them that is left gives it.
+    let chunk_size = (0..num_chunks - 1)
+        .find_map(|chunk_id| state.file_contents.get(state.storage_id(file_id), chunk_id))
+        .map(|chunk| chunk.len() as u64)
+        .filter(|&size| size > 0)
+        .ok_or("no chunk left to tell the chunk size")?;
+    Ok((chunk_size, Some(num_chunks)))
+}
+
+// Stores one chunk restored from Walrus. A chunk that was certified when it was uploaded
+// must come back with the same bytes, so its certified hash and the links stay valid.
+fn restore_chunk(
+    file_id: u64,
+    chunk_id: u64,
+    layout: (u64, Option<u64>),
+    contents: Vec<u8>,
+) -> Result<(), String> {
+    with_state_mut(|s| {
+        if canister_layout(s, file_id)? != layout {
+            return Err("file changed while it was being restored".to_string());
+        }
+        if let Some(expected) = s.certified.chunk_hash(file_id, chunk_id) {
+            if crate::certification::sha256(&contents) != expected {
+                return Err(format!("chunk {chunk_id} doesn't match its certified hash"));
+            }
+        }
+        s.certified.certify_chunk(file_id, chunk_id, &contents);
+        let storage_id = s.storage_id(file_id);
+        s.file_contents.put(storage_id, chunk_id, contents);
+        s.certified.commit();
+        Ok(())
+    })
+}
+
+// Drops the chunks written for a file that had no canister copy before a failed restore.
+fn discard_restored_chunks(file_id: u64, written: u64) {
+    with_state_mut(|s| {
+        let storage_id = s.storage_id(file_id);
+        for chunk_id in 0..written {
+            s.file_contents.delete(storage_id, chunk_id);
+            s.certified.remove_chunk(file_id, chunk_id);
+        }
+        s.certified.commit();
+    })
+}
+
+/// Restores the canister copy of a file from its Walrus replica.
+///
+/// The blob is streamed in Walrus-sized ranges and written chunk by chunk, at the
+/// file's own chunk boundaries, so no more than two chunks are held on the heap.
+async fn copy_walrus_to_icp(file_id: u64, aggregator_url: &str, blob_id: &str) -> Result<(), String> {
+    let layout = with_state(|s| canister_layout(s, file_id))?;
+    let result = stream_walrus_to_icp(file_id, aggregator_url, blob_id, layout).await;
+    let (num_chunks, file_type) = match result {
+        Ok(restored) => restored,
+        Err((err, written)) => {
+            if layout.1.is_none() {
+                discard_restored_chunks(file_id, written);
+            }
+            return Err(err);
+        }
+    };
+
+    with_state_mut(|s| {
+        if canister_layout(s, file_id)? != layout {
+            return Err("file changed while it was being restored".to_string());
+        }
+        let file = s.file_data.get_mut(&file_id).ok_or("file was deleted")?;
+        if let FileContent::Pending { .. } = file.content {
+            file.content = FileContent::Uploaded {
+                num_chunks,
+                file_type,
+                owner_key: vec![],
+            };
+        }
+        // Missing chunks may have come back, so the size is computed again.
+        crate::api::file_index::unindex_file(s, file_id);
+        crate::api::file_index::index_file(s, file_id);
+        crate::certification::certify_file(s, file_id);
+        crate::api::http_gateway::recertify_download_links(s, file_id);
+        Ok(())
+    })
+}
+
+// Copies the blob into chunks of `layout`. Returns the number of chunks and the blob's
+// content type, or the error along with how many chunks were written.
+async fn stream_walrus_to_icp(
+    file_id: u64,
+    aggregator_url: &str,
+    blob_id: &str,
+    layout: (u64, Option<u64>),
+) -> Result<(u64, String), (String, u64)> {
+    let (chunk_size, expected_chunks) = layout;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut written = 0;
+    let mut walrus_chunk = 0;
+    loop {
+        let request = walrus_chunk_request(aggregator_url, blob_id, walrus_chunk);
+        let data = match fetch_walrus_chunk(&request).await {
+            FileDownloadResponse::FoundFile(data) => data,
+            FileDownloadResponse::StorageProviderError(err) => return Err((err, written)),
+            other => return Err((format!("unexpected response {other:?}"), written)),
+        };
+        if data.num_chunks > MAX_WALRUS_COPY_SIZE.div_ceil(WALRUS_CHUNK_SIZE) {
+            return Err(("blob is too large to copy onto the canister".to_string(), written));
+        }
+        pending.extend_from_slice(&data.contents);
+        walrus_chunk += 1;
+        let last = walrus_chunk >= data.num_chunks;
+
+        // Full chunks are written as they arrive. The file's last chunk waits for the end
+        // of the blob, so a blob that doesn't fit the file is rejected before it is written.
+        loop {
+            let final_chunk = expected_chunks.is_some_and(|n| written + 1 >= n);
+            let full = pending.len() as u64 >= chunk_size;
+            if pending.is_empty() || !(last || (full && !final_chunk)) {
+                break;
+            }
+            if final_chunk && pending.len() as u64 > chunk_size {
+                return Err(("blob is larger than the file".to_string(), written));
+            }
+            let rest = pending.split_off((chunk_size as usize).min(pending.len()));
+            let chunk = std::mem::replace(&mut pending, rest);
+            restore_chunk(file_id, written, layout, chunk).map_err(|err| (err, written))?;
+            written += 1;
+        }
+        if pending.len() as u64 > chunk_size {
+            return Err(("blob is larger than the file".to_string(), written));
+        }
+        if last {
+            if written == 0 || expected_chunks.is_some_and(|n| written != n) {
+                return Err(("blob doesn't match the file's chunks".to_string(), written));
+            }
+            return Ok((written, data.file_type));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{File, FileMetadata};
+
+    fn insert_icp_file(state: &mut State, owner: Principal, file_id: u64, chunks: &[&[u8]]) {
+        state.file_data.insert(
+            file_id,
+            File {
+                metadata: FileMetadata {
+                    file_name: "report.pdf".to_string(),
+                    requester_principal: owner,
+                    requested_at: 12345,
+                    uploaded_at: Some(12345),
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: None,
+                    description: String::new(),
+                    tags: vec![],
+                },
+                content: FileContent::Uploaded {
+                    num_chunks: chunks.len() as u64,
+                    file_type: "pdf".to_string(),
+                    owner_key: vec![],
+                },
+            },
+        );
+        for (chunk_id, chunk) in chunks.iter().enumerate() {
+            state.file_contents.put(file_id, chunk_id as u64, chunk.to_vec());
+        }
+        state.file_owners.entry(owner).or_insert_with(Vec::new).push(file_id);
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+
+    #[test]
+    fn policy_marks_missing_providers_for_repair() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_icp_file(&mut state, owner, 0, &[b"abc"]);
+
+        let info = set_replication_policy(
+            owner,
+            SetReplicationPolicyRequest {
+                file_id: 0,
+                policy: ReplicationPolicy::IcpAndWalrus,
+                sha256: sha256(b"abc"),
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        assert_eq!(info.replicas.len(), 2);
+        assert_eq!(info.replicas[0].provider, "icp");
+        assert_eq!(info.replicas[0].health, ReplicaHealth::Unknown);
+        assert_eq!(info.replicas[1].provider, "walrus");
+        assert_eq!(info.replicas[1].health, ReplicaHealth::Missing);
+    }
+
+    #[test]
+    fn only_owner_can_set_policy() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        insert_icp_file(&mut state, owner, 0, &[b"abc"]);
+
+        let result = set_replication_policy(
+            other,
+            SetReplicationPolicyRequest {
+                file_id: 0,
+                policy: ReplicationPolicy::IcpAndWalrus,
+                sha256: sha256(b"abc"),
+            },
+            &mut state,
+        );
+        assert_eq!(result, Err(ReplicationError::PermissionError));
+        assert!(state.file_replication.is_empty());
+    }
+
+    #[test]
+    fn walrus_replicas_need_an_owned_blob_object() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_icp_file(&mut state, owner, 0, &[b"abc"]);
+        let request = SetReplicationPolicyRequest {
+            file_id: 0,
+            policy: ReplicationPolicy::Icp,
+            sha256: sha256(b"abc"),
+        };
+        set_replication_policy(owner, request.clone(), &mut state).unwrap();
+        assert_eq!(
+            walrus_replica_check(owner, 0, None, &state),
+            Err(ReplicationError::ProviderNotInPolicy)
+        );
+
+        let request = SetReplicationPolicyRequest {
+            policy: ReplicationPolicy::IcpAndWalrus,
+            ..request
+        };
+        set_replication_policy(owner, request, &mut state).unwrap();
+        assert_eq!(walrus_replica_check(owner, 0, None, &state), Ok(None));
+
+        state.sui_rpc_url = Some("https://sui.example".to_string());
+        assert_eq!(
+            walrus_replica_check(owner, 0, None, &state),
+            Err(ReplicationError::SuiOwnershipError(SuiOwnershipError::MissingSuiObject))
+        );
+        assert_eq!(
+            walrus_replica_check(owner, 0, Some("0x1".to_string()), &state),
+            Ok(Some(("https://sui.example".to_string(), "0x1".to_string())))
+        );
+
+        let info = record_walrus_replica(owner, 0, "blob".to_string(), &mut state).unwrap();
+        assert_eq!(info.replicas[1].blob_id, Some("blob".to_string()));
+        assert_eq!(info.replicas[1].health, ReplicaHealth::Unknown);
+    }
+
+    #[test]
+    fn icp_hash_covers_all_chunks_in_order() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_icp_file(&mut state, owner, 0, &[b"ab", b"cd"]);
+
+        let hash = icp_content_hash(&state, 0);
+        assert_eq!(health_for_hash(hash.clone(), &sha256(b"abcd")), ReplicaHealth::Healthy);
+        assert_eq!(health_for_hash(hash, &sha256(b"cdab")), ReplicaHealth::Corrupted);
+
+        state.file_contents.delete(0, 1);
+        assert_eq!(health_for_hash(icp_content_hash(&state, 0), &sha256(b"abcd")), ReplicaHealth::Missing);
+    }
+
+    #[test]
+    fn verifier_batches_wrap_around() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        for file_id in 0..7 {
+            insert_icp_file(&mut state, owner, file_id, &[b"x"]);
+            set_replication_policy(
+                owner,
+                SetReplicationPolicyRequest {
+                    file_id,
+                    policy: ReplicationPolicy::Icp,
+                    sha256: sha256(b"x"),
+                },
+                &mut state,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(next_files_to_verify(&state), vec![0, 1, 2, 3, 4]);
+        state.replication_cursor = Some(4);
+        assert_eq!(next_files_to_verify(&state), vec![5, 6, 0, 1, 2]);
+    }
+
+    #[test]
+    fn restores_keep_the_chunk_layout_of_the_canister_copy() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        insert_icp_file(&mut state, owner, 0, &[b"abc", b"def", b"g"]);
+        assert_eq!(canister_layout(&state, 0), Ok((3, Some(3))));
+
+        // A lost first chunk doesn't hide the chunk size.
+        state.file_contents.delete(0, 0);
+        assert_eq!(canister_layout(&state, 0), Ok((3, Some(3))));
+
+        insert_icp_file(&mut state, owner, 1, &[b"abc"]);
+        assert_eq!(canister_layout(&state, 1), Ok((MAX_SINGLE_CHUNK_SIZE, Some(1))));
+
+        state.file_data.get_mut(&1).unwrap().content = FileContent::Pending { alias: String::new() };
+        assert_eq!(canister_layout(&state, 1), Ok((WALRUS_CHUNK_SIZE, None)));
+        assert!(canister_layout(&state, 2).is_err());
+    }
+
+    #[test]
+    fn verifier_guard_is_released_when_dropped() {
+        let guard = VerifierGuard::acquire();
+        assert!(guard.is_some());
+        assert!(VerifierGuard::acquire().is_none());
+        drop(guard);
+        assert!(VerifierGuard::acquire().is_some());
+    }
+}
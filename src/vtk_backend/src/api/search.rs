@@ -0,0 +1,288 @@
+use crate::{PublicFileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Search runs on a token index. File names, tags and transfer titles are split
+// into lowercase alphanumeric tokens, and every suffix of every token is stored
+// under each principal who can read the file. A query term then matches with a
+// single range scan over the caller's entries: a suffix starting with the term
+// means the term is a prefix or a substring of some token.
+//
+// A query never collects every match of a common term. A term matching at most
+// `RARE_TERM_ENTRIES` entries drives the search: its few files are checked
+// against the other terms. When every term is common, the caller's files are
+// walked newest first and the walk stops once the requested page is full.
+
+pub const SEARCH_PAGE_SIZE: usize = 50;
+// Longer tokens are cut, which bounds the number of suffixes per token.
+const MAX_TOKEN_LEN: usize = 32;
+/// Most words a query can have.
+pub const MAX_QUERY_TERMS: usize = 8;
+// A term with at most this many index entries is rare enough to collect them all.
+const RARE_TERM_ENTRIES: usize = 1_000;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SearchIndex {
+    // (reader, token suffix, file_id)
+    entries: BTreeSet<(Principal, String, u64)>,
+    // What each file was indexed under, so it can be removed again.
+    files: BTreeMap<u64, (Vec<Principal>, BTreeSet<String>)>,
+    // (reader, file_id), to walk a reader's files newest first.
+    readable: BTreeSet<(Principal, u64)>,
+}
+
+impl SearchIndex {
+    fn remove(&mut self, file_id: u64) {
+        if let Some((readers, suffixes)) = self.files.remove(&file_id) {
+            for reader in readers {
+                self.readable.remove(&(reader, file_id));
+                for suffix in &suffixes {
+                    self.entries.remove(&(reader, suffix.clone(), file_id));
+                }
+            }
+        }
+    }
+
+    // The files of `reader` matching `term`, or `None` if there are more than `RARE_TERM_ENTRIES`.
+    fn rare_term_files(&self, reader: Principal, term: &str) -> Option<BTreeSet<u64>> {
+        let mut entries = self
+            .entries
+            .range((reader, term.to_string(), 0)..)
+            .take_while(|(r, suffix, _)| *r == reader && suffix.starts_with(term));
+        let files: BTreeSet<u64> =
+            entries.by_ref().take(RARE_TERM_ENTRIES).map(|(_, _, file_id)| *file_id).collect();
+        entries.next().is_none().then_some(files)
+    }
+
+    // Whether some token the file was indexed under contains `term`.
+    fn file_matches(&self, file_id: u64, term: &str) -> bool {
+        self.files.get(&file_id).map_or(false, |(_, suffixes)| {
+            suffixes.range::<str, _>(term..).next().map_or(false, |suffix| suffix.starts_with(term))
+        })
+    }
+}
+
+fn tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase().chars().take(MAX_TOKEN_LEN).collect())
+}
+
+fn suffixes(token: String) -> impl Iterator<Item = String> {
+    let starts: Vec<usize> = token.char_indices().map(|(i, _)| i).collect();
+    starts.into_iter().map(move |i| token[i..].to_string())
+}
+
+/// Reindexes a file for search under its owner and the recipients of its transfer.
+pub(crate) fn index_search(state: &mut State, file_id: u64) {
+    state.search_index.remove(file_id);
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+
+    let mut texts = vec![file.metadata.file_name.clone()];
+    texts.extend(file.metadata.tags.iter().cloned());
+    let mut readers = vec![file.metadata.requester_principal];
+    if let Some(transfer) = state.file_transfers.get(&file_id).and_then(|id| state.transfers.get(id)) {
+        texts.push(transfer.title.clone());
+        readers.extend(transfer.recipients.iter().cloned());
+    }
+    let suffixes: BTreeSet<String> = texts.iter().flat_map(|text| tokens(text)).flat_map(suffixes).collect();
+
+    for reader in &readers {
+        state.search_index.readable.insert((*reader, file_id));
+        for suffix in &suffixes {
+            state.search_index.entries.insert((*reader, suffix.clone(), file_id));
+        }
+    }
+    state.search_index.files.insert(file_id, (readers, suffixes));
+}
+
+pub(crate) fn unindex_search(state: &mut State, file_id: u64) {
+    state.search_index.remove(file_id);
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SearchResults {
+    // Files the caller received in a transfer have `group_alias` set to the transfer ID.
+    pub files: Vec<PublicFileMetadata>,
+    pub page: u64,
+    pub has_more: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SearchError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_query")]
+    InvalidQuery,
+}
+
+// Recipients are indexed when the transfer is created; they lose access once it expires.
+fn can_read(caller: Principal, file_id: u64, now: u64, state: &State) -> bool {
+    state.is_file_owner(caller, file_id)
+        || state
+            .file_transfers
+            .get(&file_id)
+            .and_then(|id| state.transfers.get(id))
+            .map_or(false, |transfer| transfer.can_read(caller, now))
+}
+
+/// Files the caller owns or received whose name, tags or transfer title contain
+/// every word of `query`, newest first. A query has at most `MAX_QUERY_TERMS` words.
+pub fn search_files(caller: Principal, query: String, page: u64, state: &State) -> Result<SearchResults, SearchError> {
+    if caller == Principal::anonymous() {
+        return Err(SearchError::NotAuthenticated);
+    }
+    let mut terms: Vec<String> = tokens(&query).collect();
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() || terms.len() > MAX_QUERY_TERMS {
+        return Err(SearchError::InvalidQuery);
+    }
+
+    let index = &state.search_index;
+    let rare = terms.iter().enumerate().find_map(|(i, term)| Some((i, index.rare_term_files(caller, term)?)));
+    let candidates: Box<dyn Iterator<Item = u64>> = match rare {
+        Some((driver, files)) => {
+            terms.remove(driver);
+            Box::new(files.into_iter().rev())
+        }
+        None => Box::new(
+            index
+                .readable
+                .range((caller, 0)..=(caller, u64::MAX))
+                .rev()
+                .map(|(_, file_id)| *file_id),
+        ),
+    };
+
+    let now = crate::get_time();
+    let skip = (page as usize).saturating_mul(SEARCH_PAGE_SIZE);
+    let mut visible = candidates
+        .filter(|&file_id| terms.iter().all(|term| index.file_matches(file_id, term)))
+        .filter(|&file_id| can_read(caller, file_id, now, state))
+        .skip(skip);
+    let files: Vec<PublicFileMetadata> = visible
+        .by_ref()
+        .take(SEARCH_PAGE_SIZE)
+        .filter_map(|file_id| state.public_file_metadata(file_id))
+        .collect();
+
+    Ok(SearchResults {
+        files,
+        page,
+        has_more: visible.next().is_some(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{
+        create_transfer, update_file_metadata, upload_file_atomic, CreateTransferRequest, UpdateFileMetadataRequest,
+        UploadFileAtomicRequest,
+    };
+
+    fn upload(state: &mut State, owner: Principal, name: &str) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: name.to_string(),
+                content: vec![1, 2, 3],
+                file_type: "application/pdf".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    fn found(state: &State, caller: Principal, query: &str) -> Vec<u64> {
+        search_files(caller, query.to_string(), 0, state)
+            .unwrap()
+            .files
+            .iter()
+            .map(|f| f.file_id)
+            .collect()
+    }
+
+    #[test]
+    fn matches_prefixes_and_substrings_of_names_and_tags() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[3]);
+        let invoice = upload(&mut state, owner, "Invoice_March.pdf");
+        let contract = upload(&mut state, owner, "contract-final.pdf");
+        update_file_metadata(
+            owner,
+            contract,
+            UpdateFileMetadataRequest {
+                file_name: None,
+                description: None,
+                tags: Some(vec!["Legal".to_string()]),
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        assert_eq!(found(&state, owner, "inv"), vec![invoice]);
+        assert_eq!(found(&state, owner, "MARCH"), vec![invoice]);
+        assert_eq!(found(&state, owner, "ract"), vec![contract]);
+        assert_eq!(found(&state, owner, "legal"), vec![contract]);
+        assert_eq!(found(&state, owner, "pdf"), vec![contract, invoice]);
+        assert_eq!(found(&state, owner, "pdf march"), vec![invoice]);
+        assert_eq!(search_files(owner, " - ".to_string(), 0, &state), Err(SearchError::InvalidQuery));
+        assert_eq!(
+            search_files(owner, "a b c d e f g h i".to_string(), 0, &state),
+            Err(SearchError::InvalidQuery)
+        );
+
+        crate::api::delete_file(&mut state, owner, invoice);
+        assert!(found(&state, owner, "invoice").is_empty());
+    }
+
+    #[test]
+    fn common_terms_are_paged_without_collecting_every_match() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[3]);
+        let files: Vec<u64> = (0..RARE_TERM_ENTRIES + 10)
+            .map(|i| upload(&mut state, owner, &format!("scan{i}.pdf")))
+            .collect();
+        let invoice = upload(&mut state, owner, "invoice.pdf");
+        assert!(state.search_index.rare_term_files(owner, "pdf").is_none());
+
+        let newest_first: Vec<u64> = files.iter().rev().copied().collect();
+        let results = search_files(owner, "pdf scan".to_string(), 1, &state).unwrap();
+        let ids: Vec<u64> = results.files.iter().map(|f| f.file_id).collect();
+        assert_eq!(ids, newest_first[SEARCH_PAGE_SIZE..2 * SEARCH_PAGE_SIZE]);
+        assert!(results.has_more);
+        // A rare term drives the search even next to a common one.
+        assert_eq!(found(&state, owner, "pdf invoice"), vec![invoice]);
+    }
+
+    #[test]
+    fn recipients_find_files_by_transfer_title() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let recipient = Principal::from_slice(&[1]);
+        let stranger = Principal::from_slice(&[2]);
+        let file_id = upload(&mut state, sender, "scan.pdf");
+        create_transfer(
+            sender,
+            CreateTransferRequest {
+                title: "Apartment lease".to_string(),
+                message: "".to_string(),
+                recipients: vec![recipient],
+                file_ids: vec![file_id],
+                expires_at: None,
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        assert_eq!(found(&state, recipient, "lease"), vec![file_id]);
+        assert_eq!(found(&state, recipient, "scan"), vec![file_id]);
+        assert_eq!(found(&state, sender, "apart"), vec![file_id]);
+        assert!(found(&state, stranger, "scan").is_empty());
+    }
+}
@@ -0,0 +1,387 @@
+use crate::State;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use candid::{CandidType, Principal};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+type Blake2b256 = Blake2b<U32>;
+
+// Signature scheme flag used by Sui for Ed25519 keys.
+const ED25519_FLAG: u8 = 0x00;
+// Intent prefix Sui wallets put in front of a signed personal message.
+const PERSONAL_MESSAGE_INTENT: [u8; 3] = [3, 0, 0];
+
+const SUI_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+const SUI_OUTCALL_CYCLES: u128 = 2_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct LinkSuiAddressRequest {
+    pub address: String,
+    /// Base64 Sui signature (`flag || signature || public key`) over `sui_link_message`.
+    pub signature: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SuiLinkError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "unsupported_scheme")]
+    UnsupportedScheme,
+    #[serde(rename = "invalid_signature")]
+    InvalidSignature,
+    #[serde(rename = "address_mismatch")]
+    AddressMismatch,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SuiOwnershipError {
+    #[serde(rename = "missing_sui_object")]
+    MissingSuiObject,
+    #[serde(rename = "not_a_blob")]
+    NotABlob,
+    #[serde(rename = "blob_mismatch")]
+    BlobMismatch,
+    #[serde(rename = "owner_not_linked")]
+    OwnerNotLinked,
+    #[serde(rename = "rpc_error")]
+    RpcError(String),
+}
+
+/// The message a user signs with their Sui wallet to prove control of an address.
+pub fn sui_link_message(caller: Principal) -> String {
+    format!("wetkeys: link this Sui address to principal {}", caller.to_text())
+}
+
+pub fn link_sui_address(
+    caller: Principal,
+    request: LinkSuiAddressRequest,
+    state: &mut State,
+) -> Result<(), SuiLinkError> {
+    if caller == Principal::anonymous() {
+        return Err(SuiLinkError::NotAuthenticated);
+    }
+
+    let serialized = STANDARD
+        .decode(request.signature.trim())
+        .map_err(|_| SuiLinkError::InvalidSignature)?;
+    if serialized.len() != 1 + 64 + 32 {
+        return Err(SuiLinkError::InvalidSignature);
+    }
+    if serialized[0] != ED25519_FLAG {
+        return Err(SuiLinkError::UnsupportedScheme);
+    }
+
+    let signature = Signature::from_bytes(serialized[1..65].try_into().unwrap());
+    let public_key: [u8; 32] = serialized[65..].try_into().unwrap();
+
+    let address = sui_address(ED25519_FLAG, &public_key);
+    if address != normalize_sui_address(&request.address) {
+        return Err(SuiLinkError::AddressMismatch);
+    }
+
+    let verifying_key =
+        VerifyingKey::from_bytes(&public_key).map_err(|_| SuiLinkError::InvalidSignature)?;
+    verifying_key
+        .verify(
+            &personal_message_digest(sui_link_message(caller).as_bytes()),
+            &signature,
+        )
+        .map_err(|_| SuiLinkError::InvalidSignature)?;
+
+    let addresses = state.sui_addresses.entry(caller).or_insert_with(Vec::new);
+    if !addresses.contains(&address) {
+        addresses.push(address);
+    }
+    Ok(())
+}
+
+/// Enables blob ownership checks in `register_file`, or disables them with `None`.
+/// Callers must check that the request comes from an admin, see `update_config`.
+pub fn set_sui_rpc_url(url: Option<String>, state: &mut State) -> Result<(), String> {
+    state.sui_rpc_url = match url {
+        Some(url) => {
+            let url = url.trim().trim_end_matches('/').to_string();
+            if !url.starts_with("https://") {
+                return Err("sui rpc url must use https".to_string());
+            }
+            Some(url)
+        }
+        None => None,
+    };
+    Ok(())
+}
+
+/// Fetches the Sui object through the configured JSON-RPC endpoint and checks
+/// that it is the Walrus blob `blob_id`, owned by an address linked to `caller`.
+pub async fn verify_blob_ownership(
+    caller: Principal,
+    rpc_url: String,
+    object_id: String,
+    blob_id: String,
+) -> Result<(), SuiOwnershipError> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "sui_getObject",
+        "params": [object_id, { "showType": true, "showOwner": true, "showContent": true }],
+    });
+
+    let arg = CanisterHttpRequestArgument {
+        url: rpc_url,
+        method: HttpMethod::POST,
+        body: Some(body.to_string().into_bytes()),
+        max_response_bytes: Some(SUI_MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext::from_name(
+            "transform_sui_response".to_string(),
+            vec![],
+        )),
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+    };
+
+    let response = match http_request(arg, SUI_OUTCALL_CYCLES).await {
+        Ok((response,)) => response,
+        Err((code, msg)) => return Err(SuiOwnershipError::RpcError(format!("{code:?}: {msg}"))),
+    };
+    let reply: Value = serde_json::from_slice(&response.body)
+        .map_err(|e| SuiOwnershipError::RpcError(format!("invalid json-rpc reply: {e}")))?;
+
+    crate::with_state(|s| check_blob_object(s, caller, &reply, &blob_id))
+}
+
+/// Checks a `sui_getObject` reply against the caller's linked addresses.
+pub fn check_blob_object(
+    s: &State,
+    caller: Principal,
+    reply: &Value,
+    blob_id: &str,
+) -> Result<(), SuiOwnershipError> {
+    if let Some(error) = reply.get("error").or_else(|| reply.pointer("/result/error")) {
+        return Err(SuiOwnershipError::RpcError(error.to_string()));
+    }
+    let data = reply
+        .pointer("/result/data")
+        .ok_or(SuiOwnershipError::MissingSuiObject)?;
+
+    let is_blob = data
+        .get("type")
+        .and_then(Value::as_str)
+        .map_or(false, |t| t.ends_with("::blob::Blob"));
+    if !is_blob {
+        return Err(SuiOwnershipError::NotABlob);
+    }
+
+    let onchain_blob_id = data
+        .pointer("/content/fields/blob_id")
+        .and_then(Value::as_str);
+    if onchain_blob_id.is_none() || onchain_blob_id != blob_id_to_u256(blob_id).as_deref() {
+        return Err(SuiOwnershipError::BlobMismatch);
+    }
+
+    let owner = data
+        .pointer("/owner/AddressOwner")
+        .and_then(Value::as_str)
+        .map(normalize_sui_address)
+        .ok_or(SuiOwnershipError::OwnerNotLinked)?;
+    let linked = s
+        .sui_addresses
+        .get(&caller)
+        .map_or(false, |addresses| addresses.contains(&owner));
+    if !linked {
+        return Err(SuiOwnershipError::OwnerNotLinked);
+    }
+    Ok(())
+}
+
+/// Drops headers so that all replicas agree on the JSON-RPC response.
+pub fn transform_sui_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+fn personal_message_digest(message: &[u8]) -> [u8; 32] {
+    let mut intent_message = PERSONAL_MESSAGE_INTENT.to_vec();
+    // BCS encoding of `vector<u8>`: ULEB128 length followed by the bytes.
+    let mut len = message.len();
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            intent_message.push(byte);
+            break;
+        }
+        intent_message.push(byte | 0x80);
+    }
+    intent_message.extend_from_slice(message);
+    Blake2b256::digest(&intent_message).into()
+}
+
+fn sui_address(flag: u8, public_key: &[u8]) -> String {
+    let mut hasher = Blake2b256::new();
+    hasher.update([flag]);
+    hasher.update(public_key);
+    format!("0x{}", hex::encode(hasher.finalize()))
+}
+
+fn normalize_sui_address(address: &str) -> String {
+    let hex_part = address.trim().trim_start_matches("0x").to_lowercase();
+    format!("0x{:0>64}", hex_part)
+}
+
+/// Walrus blob IDs are the URL-safe base64 of a little-endian u256, while the
+/// Sui object stores the same value as a decimal string.
+fn blob_id_to_u256(blob_id: &str) -> Option<String> {
+    let mut bytes = URL_SAFE_NO_PAD.decode(blob_id.trim_end_matches('=')).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    bytes.reverse();
+
+    let mut digits = Vec::new();
+    while bytes.iter().any(|&b| b != 0) {
+        let mut remainder = 0u32;
+        for byte in bytes.iter_mut() {
+            let current = (remainder << 8) | *byte as u32;
+            *byte = (current / 10) as u8;
+            remainder = current % 10;
+        }
+        digits.push(b'0' + remainder as u8);
+    }
+    if digits.is_empty() {
+        digits.push(b'0');
+    }
+    digits.reverse();
+    String::from_utf8(digits).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed_link_request(caller: Principal, key: &SigningKey) -> LinkSuiAddressRequest {
+        let digest = personal_message_digest(sui_link_message(caller).as_bytes());
+        let mut serialized = vec![ED25519_FLAG];
+        serialized.extend_from_slice(&key.sign(&digest).to_bytes());
+        serialized.extend_from_slice(key.verifying_key().as_bytes());
+        LinkSuiAddressRequest {
+            address: sui_address(ED25519_FLAG, key.verifying_key().as_bytes()),
+            signature: STANDARD.encode(serialized),
+        }
+    }
+
+    fn blob_object(owner: &str, blob_id: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "data": {
+                    "objectId": "0x4748cd83217b5ce7aa77e7f1ad6fc5f7f694e26a157381b9391ac65c47815faf",
+                    "type": "0xd84704c17fc870b8764832c535aa6b11f21a95cd6f5bb38a9b07d2cf42220c66::blob::Blob",
+                    "owner": { "AddressOwner": owner },
+                    "content": {
+                        "dataType": "moveObject",
+                        "fields": { "blob_id": blob_id_to_u256(blob_id).unwrap() }
+                    }
+                }
+            }
+        })
+    }
+
+    const BLOB_ID: &str = "M4hsZGQ1oCktdzegB6HnI6Mi28S2nqOPHxK-W7_4BUk";
+
+    #[test]
+    fn link_address_with_valid_signature() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let request = signed_link_request(caller, &key);
+        let address = request.address.clone();
+
+        assert_eq!(link_sui_address(caller, request, &mut state), Ok(()));
+        assert_eq!(state.sui_addresses.get(&caller), Some(&vec![address]));
+    }
+
+    #[test]
+    fn signature_for_another_principal_is_rejected() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+
+        let result = link_sui_address(other, signed_link_request(caller, &key), &mut state);
+        assert_eq!(result, Err(SuiLinkError::InvalidSignature));
+        assert!(state.sui_addresses.get(&other).is_none());
+    }
+
+    #[test]
+    fn address_must_match_signing_key() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        let mut request = signed_link_request(caller, &SigningKey::from_bytes(&[7u8; 32]));
+        request.address = sui_address(ED25519_FLAG, SigningKey::from_bytes(&[8u8; 32]).verifying_key().as_bytes());
+
+        assert_eq!(link_sui_address(caller, request, &mut state), Err(SuiLinkError::AddressMismatch));
+    }
+
+    #[test]
+    fn blob_owned_by_linked_address_is_accepted() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        let owner = sui_address(ED25519_FLAG, &[1u8; 32]);
+        state.sui_addresses.insert(caller, vec![owner.clone()]);
+
+        assert_eq!(check_blob_object(&state, caller, &blob_object(&owner, BLOB_ID), BLOB_ID), Ok(()));
+    }
+
+    #[test]
+    fn blob_owned_by_someone_else_is_rejected() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        state.sui_addresses.insert(caller, vec![sui_address(ED25519_FLAG, &[1u8; 32])]);
+        let stranger = sui_address(ED25519_FLAG, &[2u8; 32]);
+
+        assert_eq!(
+            check_blob_object(&state, caller, &blob_object(&stranger, BLOB_ID), BLOB_ID),
+            Err(SuiOwnershipError::OwnerNotLinked)
+        );
+    }
+
+    #[test]
+    fn object_for_another_blob_is_rejected() {
+        let mut state = State::default();
+        let caller = Principal::from_slice(&[1]);
+        let owner = sui_address(ED25519_FLAG, &[1u8; 32]);
+        state.sui_addresses.insert(caller, vec![owner.clone()]);
+        let other_blob = URL_SAFE_NO_PAD.encode([9u8; 32]);
+
+        assert_eq!(
+            check_blob_object(&state, caller, &blob_object(&owner, &other_blob), BLOB_ID),
+            Err(SuiOwnershipError::BlobMismatch)
+        );
+    }
+
+    #[test]
+    fn blob_id_converts_to_little_endian_decimal() {
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert_eq!(blob_id_to_u256(&URL_SAFE_NO_PAD.encode(one)).as_deref(), Some("1"));
+
+        let mut two_fifty_six = [0u8; 32];
+        two_fifty_six[1] = 1;
+        assert_eq!(blob_id_to_u256(&URL_SAFE_NO_PAD.encode(two_fifty_six)).as_deref(), Some("256"));
+    }
+}
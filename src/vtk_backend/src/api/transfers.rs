@@ -0,0 +1,394 @@
+use crate::api::download_file::file_chunk;
+use crate::api::DeleteFileResult;
+use crate::{FileContent, FileDownloadResponse, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+// A transfer is what users send: several files plus a note to the recipients.
+// The files stay owned by the sender; recipients can download them through the
+// transfer until it expires or the sender deletes it.
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_MESSAGE_LEN: usize = 5_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub transfer_id: u64,
+    pub title: String,
+    pub message: String,
+    pub sender: Principal,
+    pub recipients: Vec<Principal>,
+    pub file_ids: Vec<u64>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl Transfer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+
+    // The sender always has access; recipients only until the transfer expires.
+    pub(crate) fn can_read(&self, caller: Principal, now: u64) -> bool {
+        caller == self.sender || (self.recipients.contains(&caller) && !self.is_expired(now))
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CreateTransferRequest {
+    pub title: String,
+    pub message: String,
+    pub recipients: Vec<Principal>,
+    pub file_ids: Vec<u64>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_input")]
+    InvalidInput,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "permission_error")]
+    PermissionError,
+    #[serde(rename = "file_not_uploaded")]
+    FileNotUploaded(u64),
+    #[serde(rename = "file_already_in_transfer")]
+    FileAlreadyInTransfer(u64),
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+/// Transfers the caller sent or received, newest first. Expired transfers are only listed to their sender.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransferList {
+    pub sent: Vec<Transfer>,
+    pub received: Vec<Transfer>,
+}
+
+// Who may read the chunks of a transfer's files in their buckets: the sender, and the
+// recipients until the transfer expires.
+fn bucket_readers(transfer: &Transfer, now: u64) -> Vec<Principal> {
+    let mut readers = vec![transfer.sender];
+    if !transfer.is_expired(now) {
+        readers.extend(transfer.recipients.iter().cloned());
+    }
+    readers
+}
+
+// Sets who may read the chunks of the transfer's files stored in bucket canisters.
+fn update_bucket_readers(state: &State, transfer: &Transfer, readers: Vec<Principal>) {
+    for file_id in &transfer.file_ids {
+        if let Some(bucket) = state.file_data.get(file_id).and_then(|f| f.metadata.bucket) {
+            crate::api::buckets::set_bucket_readers(bucket, *file_id, readers.clone());
+        }
+    }
+}
+
+/// Resets the bucket readers of a transfer's files after its recipients or files changed.
+pub(crate) fn refresh_bucket_readers(state: &State, transfer_id: u64, now: u64) {
+    if let Some(transfer) = state.transfers.get(&transfer_id) {
+        update_bucket_readers(state, transfer, bucket_readers(transfer, now));
+    }
+}
+
+pub fn create_transfer(
+    caller: Principal,
+    request: CreateTransferRequest,
+    state: &mut State,
+) -> Result<Transfer, TransferError> {
+    if caller == Principal::anonymous() {
+        return Err(TransferError::NotAuthenticated);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(TransferError::Suspended);
+    }
+    let now = crate::get_time();
+    let title = request.title.trim().to_string();
+    if title.is_empty()
+        || title.len() > MAX_TITLE_LEN
+        || request.message.len() > MAX_MESSAGE_LEN
+        || request.file_ids.is_empty()
+        || request.expires_at.map_or(false, |expires_at| expires_at <= now)
+    {
+        return Err(TransferError::InvalidInput);
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut file_ids = request.file_ids;
+    file_ids.retain(|&file_id| seen.insert(file_id));
+    for &file_id in &file_ids {
+        if !state.is_file_owner(caller, file_id) {
+            return Err(TransferError::PermissionError);
+        }
+        if !matches!(state.file_data.get(&file_id).map(|f| &f.content), Some(FileContent::Uploaded { .. })) {
+            return Err(TransferError::FileNotUploaded(file_id));
+        }
+        if state.file_transfers.contains_key(&file_id) {
+            return Err(TransferError::FileAlreadyInTransfer(file_id));
+        }
+    }
+    let mut recipients = request.recipients;
+    recipients.retain(|&r| r != caller && r != Principal::anonymous());
+    recipients.sort();
+    recipients.dedup();
+
+    let transfer_id = state.transfer_count;
+    state.transfer_count += 1;
+    let transfer = Transfer {
+        transfer_id,
+        title,
+        message: request.message,
+        sender: caller,
+        recipients,
+        file_ids,
+        created_at: now,
+        expires_at: request.expires_at,
+    };
+
+    update_bucket_readers(state, &transfer, bucket_readers(&transfer, now));
+    for &file_id in &transfer.file_ids {
+        state.file_transfers.insert(file_id, transfer_id);
+    }
+    if let Some(expires_at) = transfer.expires_at {
+        state.transfer_expiry.insert((expires_at, transfer_id));
+    }
+    state.transfers.insert(transfer_id, transfer.clone());
+    for &file_id in &transfer.file_ids {
+        crate::api::search::index_search(state, file_id);
+        crate::certification::certify_file(state, file_id);
+    }
+    Ok(transfer)
+}
+
+pub fn list_transfers(caller: Principal, state: &State) -> TransferList {
+    let now = crate::get_time();
+    let mut list = TransferList {
+        sent: vec![],
+        received: vec![],
+    };
+    for transfer in state.transfers.values().rev() {
+        if transfer.sender == caller {
+            list.sent.push(transfer.clone());
+        } else if transfer.can_read(caller, now) {
+            list.received.push(transfer.clone());
+        }
+    }
+    list
+}
+
+pub fn get_transfer(caller: Principal, transfer_id: u64, state: &State) -> Result<Transfer, TransferError> {
+    match state.transfers.get(&transfer_id) {
+        Some(transfer) if transfer.can_read(caller, crate::get_time()) => Ok(transfer.clone()),
+        Some(_) => Err(TransferError::PermissionError),
+        None => Err(TransferError::NotFound),
+    }
+}
+
+/// Downloads a chunk of one of the files of a transfer the caller sent or received.
+pub fn download_transfer_file(
+    state: &State,
+    caller: Principal,
+    transfer_id: u64,
+    file_id: u64,
+    chunk_id: u64,
+) -> FileDownloadResponse {
+    match state.transfers.get(&transfer_id) {
+        Some(transfer) if transfer.file_ids.contains(&file_id) => {
+            if caller == Principal::anonymous() || !transfer.can_read(caller, crate::get_time()) {
+                return FileDownloadResponse::PermissionError;
+            }
+            if crate::api::admin::downloads_blocked(state, caller) {
+                return FileDownloadResponse::Suspended;
+            }
+            file_chunk(state, file_id, chunk_id)
+        }
+        _ => FileDownloadResponse::NotFoundFile,
+    }
+}
+
+/// Deletes a transfer and moves all of its files to the trash. Only the sender can do this.
+pub fn delete_transfer(caller: Principal, transfer_id: u64, state: &mut State) -> Result<(), TransferError> {
+    let transfer = state.transfers.get(&transfer_id).ok_or(TransferError::NotFound)?;
+    if transfer.sender != caller {
+        return Err(TransferError::PermissionError);
+    }
+    // Checked up front, so a file that can't be trashed leaves the transfer as it was.
+    if !transfer.file_ids.iter().all(|&file_id| state.is_file_owner(caller, file_id)) {
+        return Err(TransferError::PermissionError);
+    }
+    let transfer = state.transfers.remove(&transfer_id).expect("transfer exists");
+    if let Some(expires_at) = transfer.expires_at {
+        state.transfer_expiry.remove(&(expires_at, transfer_id));
+    }
+    update_bucket_readers(state, &transfer, vec![caller]);
+    for file_id in transfer.file_ids {
+        state.file_transfers.remove(&file_id);
+        let result = crate::api::delete_file(state, caller, file_id);
+        assert_eq!(result, DeleteFileResult::Ok, "failed to trash file {file_id}");
+    }
+    Ok(())
+}
+
+/// Drops a deleted file from the transfer it was part of. The recipients lose access
+/// to its chunks in its bucket; its owner keeps it, to restore the file from the trash.
+pub(crate) fn remove_file_from_transfer(state: &mut State, file_id: u64) {
+    if let Some(transfer_id) = state.file_transfers.remove(&file_id) {
+        if let Some(transfer) = state.transfers.get_mut(&transfer_id) {
+            transfer.file_ids.retain(|&id| id != file_id);
+        }
+        // Purged files are already gone, and `release_from_bucket` revoked every reader.
+        if let Some(file) = state.file_data.get(&file_id) {
+            if let Some(bucket) = file.metadata.bucket {
+                let owner = file.metadata.requester_principal;
+                crate::api::buckets::set_bucket_readers(bucket, file_id, vec![owner]);
+            }
+        }
+    }
+}
+
+/// Revokes the recipients' access to the files of transfers that expired by `now`,
+/// in the buckets holding them. Run by a timer.
+pub fn revoke_expired_transfers(state: &mut State, now: u64) {
+    let expired: Vec<(u64, u64)> = state
+        .transfer_expiry
+        .iter()
+        .take_while(|(expires_at, _)| *expires_at <= now)
+        .copied()
+        .collect();
+    for entry in expired {
+        state.transfer_expiry.remove(&entry);
+        refresh_bucket_readers(state, entry.1, now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{list_files, upload_file_atomic, UploadFileAtomicRequest};
+
+    fn upload(state: &mut State, owner: Principal, name: &str) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: name.to_string(),
+                content: vec![1, 2, 3],
+                file_type: "application/pdf".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    fn request(file_ids: Vec<u64>, recipients: Vec<Principal>) -> CreateTransferRequest {
+        CreateTransferRequest {
+            title: "Contracts".to_string(),
+            message: "Please sign both".to_string(),
+            recipients,
+            file_ids,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn recipients_download_files_of_a_transfer() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let recipient = Principal::from_slice(&[1]);
+        let stranger = Principal::from_slice(&[2]);
+        let a = upload(&mut state, sender, "a.pdf");
+        let b = upload(&mut state, sender, "b.pdf");
+
+        let transfer = create_transfer(sender, request(vec![a, b], vec![recipient]), &mut state).unwrap();
+        assert_eq!(transfer.file_ids, vec![a, b]);
+
+        assert!(matches!(
+            download_transfer_file(&state, recipient, transfer.transfer_id, b, 0),
+            FileDownloadResponse::FoundFile(_)
+        ));
+        assert_eq!(
+            download_transfer_file(&state, stranger, transfer.transfer_id, b, 0),
+            FileDownloadResponse::PermissionError
+        );
+        assert_eq!(list_transfers(recipient, &state).received, vec![transfer.clone()]);
+        assert_eq!(list_transfers(sender, &state).sent, vec![transfer]);
+
+        let listed = list_files(sender, &state);
+        assert_eq!(listed[0].group_name, "Contracts");
+        assert_eq!(listed[0].group_alias, Some("0".to_string()));
+    }
+
+    #[test]
+    fn transfers_validate_their_files() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let other = Principal::from_slice(&[1]);
+        let a = upload(&mut state, sender, "a.pdf");
+        let theirs = upload(&mut state, other, "c.pdf");
+
+        assert_eq!(create_transfer(sender, request(vec![], vec![]), &mut state), Err(TransferError::InvalidInput));
+        assert_eq!(
+            create_transfer(sender, request(vec![theirs], vec![]), &mut state),
+            Err(TransferError::PermissionError)
+        );
+        create_transfer(sender, request(vec![a], vec![other]), &mut state).unwrap();
+        assert_eq!(
+            create_transfer(sender, request(vec![a], vec![]), &mut state),
+            Err(TransferError::FileAlreadyInTransfer(a))
+        );
+    }
+
+    #[test]
+    fn deleting_a_transfer_deletes_its_files() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let recipient = Principal::from_slice(&[1]);
+        let a = upload(&mut state, sender, "a.pdf");
+        let transfer = create_transfer(sender, request(vec![a], vec![recipient]), &mut state).unwrap();
+
+        assert_eq!(delete_transfer(recipient, transfer.transfer_id, &mut state), Err(TransferError::PermissionError));
+        assert_eq!(delete_transfer(sender, transfer.transfer_id, &mut state), Ok(()));
+        assert!(!state.is_file_owner(sender, a));
+        assert_eq!(crate::api::list_trash(sender, &state)[0].file_id, a);
+        assert!(state.transfers.is_empty());
+        assert!(state.file_transfers.is_empty());
+    }
+
+    #[test]
+    fn transfers_with_files_the_sender_lost_are_kept() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let a = upload(&mut state, sender, "a.pdf");
+        let transfer = create_transfer(sender, request(vec![a], vec![]), &mut state).unwrap();
+        state.file_owners.remove(&sender);
+
+        assert_eq!(delete_transfer(sender, transfer.transfer_id, &mut state), Err(TransferError::PermissionError));
+        assert!(state.transfers.contains_key(&transfer.transfer_id));
+        assert_eq!(state.file_transfers.get(&a), Some(&transfer.transfer_id));
+    }
+
+    #[test]
+    fn expired_transfers_are_revoked_once() {
+        let mut state = State::default();
+        let sender = Principal::from_slice(&[3]);
+        let recipient = Principal::from_slice(&[1]);
+        let a = upload(&mut state, sender, "a.pdf");
+        let b = upload(&mut state, sender, "b.pdf");
+        let expiring = CreateTransferRequest {
+            expires_at: Some(crate::get_time() + 10),
+            ..request(vec![a], vec![recipient])
+        };
+        let transfer = create_transfer(sender, expiring, &mut state).unwrap();
+        create_transfer(sender, request(vec![b], vec![recipient]), &mut state).unwrap();
+        assert_eq!(state.transfer_expiry.len(), 1);
+
+        revoke_expired_transfers(&mut state, crate::get_time());
+        assert_eq!(state.transfer_expiry.len(), 1);
+        revoke_expired_transfers(&mut state, crate::get_time() + 10);
+        assert!(state.transfer_expiry.is_empty());
+        assert!(!state.transfers[&transfer.transfer_id].can_read(recipient, crate::get_time() + 10));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::State;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Deleted files go to their owner's trash first. They disappear from listings,
+// search and downloads, but keep their chunks, and still count towards the
+// owner's storage, until they are restored or purged.
+
+/// How long a file stays in the trash before it is purged.
+pub const TRASH_RETENTION_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+/// How many expired files a single run of `purge_expired_trash` removes.
+pub const TRASH_PURGE_BATCH: usize = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TrashedFile {
+    pub file_id: u64,
+    pub file_name: String,
+    pub size: u64,
+    pub deleted_at: u64,
+    pub purge_at: u64,
+    // Where the file was, to put it back on restore.
+    pub folder: Option<u64>,
+    pub transfer: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TrashError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+}
+
+/// Moves a file the caller owns to their trash.
+pub(crate) fn trash_file(state: &mut State, owner: Principal, file_id: u64) {
+    let Some(file) = state.file_data.get(&file_id) else {
+        return;
+    };
+    let now = crate::get_time();
+    let trashed = TrashedFile {
+        file_id,
+        file_name: file.metadata.file_name.clone(),
+        size: state.chunks_for(file_id).size(state.storage_id(file_id)),
+        deleted_at: now,
+        purge_at: now.saturating_add(TRASH_RETENTION_NANOS),
+        folder: state.file_folders.get(&file_id).copied(),
+        transfer: state.file_transfers.get(&file_id).copied(),
+    };
+
+    if let Some(files) = state.file_owners.get_mut(&owner) {
+        files.retain(|&id| id != file_id);
+    }
+    crate::api::file_metadata::remove_file_tags(state, file_id);
+    crate::api::file_index::unindex_file(state, file_id);
+    crate::api::folders::remove_file_from_folder(state, file_id);
+    crate::api::transfers::remove_file_from_transfer(state, file_id);
+    crate::api::http_gateway::remove_download_links(state, file_id);
+    state.upload_sessions.remove(&file_id);
+    crate::api::versions::discard_staged_versions(state, file_id);
+    state.certified.remove_file(owner, file_id);
+    state.certified.commit();
+
+    state.trash_expiry.insert((trashed.purge_at, file_id));
+    state.trash.entry(owner).or_default().insert(file_id, trashed);
+}
+
+/// Forgets a file's trash entry, once it has been restored or purged.
+pub(crate) fn remove_from_trash(state: &mut State, owner: Principal, file_id: u64) -> Option<TrashedFile> {
+    let files = state.trash.get_mut(&owner)?;
+    let trashed = files.remove(&file_id)?;
+    if files.is_empty() {
+        state.trash.remove(&owner);
+    }
+    state.trash_expiry.remove(&(trashed.purge_at, file_id));
+    Some(trashed)
+}
+
+/// True if the file is in its owner's trash.
+pub(crate) fn is_trashed(state: &State, file_id: u64) -> bool {
+    state.file_data.get(&file_id).is_some_and(|file| {
+        state
+            .trash
+            .get(&file.metadata.requester_principal)
+            .is_some_and(|files| files.contains_key(&file_id))
+    })
+}
+
+/// The caller's trashed files, most recently deleted first.
+pub fn list_trash(caller: Principal, state: &State) -> Vec<TrashedFile> {
+    let mut files: Vec<TrashedFile> = state
+        .trash
+        .get(&caller)
+        .map(|files| files.values().cloned().collect())
+        .unwrap_or_default();
+    files.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(b.file_id.cmp(&a.file_id)));
+    files
+}
+
+/// Takes a file out of the trash, back into its folder and transfer if they still exist.
+pub fn restore_file(caller: Principal, file_id: u64, state: &mut State) -> Result<(), TrashError> {
+    if caller == Principal::anonymous() {
+        return Err(TrashError::NotAuthenticated);
+    }
+    let trashed = remove_from_trash(state, caller, file_id).ok_or(TrashError::NotFound)?;
+
+    state.file_owners.entry(caller).or_default().push(file_id);
+    if let Some(folder) = trashed.folder.filter(|folder| state.folders.contains_key(folder)) {
+        state.file_folders.insert(file_id, folder);
+        state.folder_files.entry(folder).or_default().insert(file_id);
+    }
+    if let Some(transfer) = trashed.transfer.and_then(|id| state.transfers.get_mut(&id)) {
+        transfer.file_ids.push(file_id);
+        let transfer_id = transfer.transfer_id;
+        state.file_transfers.insert(file_id, transfer_id);
+        crate::api::transfers::refresh_bucket_readers(state, transfer_id, crate::get_time());
+    }
+    crate::api::file_metadata::add_file_tags(state, file_id);
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+    Ok(())
+}
+
+/// Permanently deletes every file in the caller's trash.
+pub fn empty_trash(caller: Principal, state: &mut State) -> Result<(), TrashError> {
+    if caller == Principal::anonymous() {
+        return Err(TrashError::NotAuthenticated);
+    }
+    let file_ids: Vec<u64> = state
+        .trash
+        .get(&caller)
+        .map(|files| files.keys().copied().collect())
+        .unwrap_or_default();
+    for file_id in file_ids {
+        crate::api::delete_file::purge_file(state, file_id);
+    }
+    Ok(())
+}
+
+/// Purges up to `TRASH_PURGE_BATCH` files whose retention period ended. Run by a timer.
+pub fn purge_expired_trash(state: &mut State, now: u64) {
+    let expired: Vec<u64> = state
+        .trash_expiry
+        .iter()
+        .take_while(|(purge_at, _)| *purge_at <= now)
+        .take(TRASH_PURGE_BATCH)
+        .map(|(_, file_id)| *file_id)
+        .collect();
+    for file_id in expired {
+        crate::api::delete_file::purge_file(state, file_id);
+    }
+}
+
+/// Total size of the caller's trashed files, which still counts as storage used.
+pub(crate) fn trashed_bytes(state: &State, owner: Principal) -> u64 {
+    state
+        .trash
+        .get(&owner)
+        .map_or(0, |files| files.values().map(|f| f.size).sum())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{create_folder, delete_file, list_files, move_file, upload_file_atomic, UploadFileAtomicRequest};
+
+    fn upload(state: &mut State, owner: Principal) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "report.pdf".to_string(),
+                content: vec![1, 2, 3],
+                file_type: "application/pdf".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    #[test]
+    fn deleted_files_can_be_restored() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let folder = create_folder(owner, "work".to_string(), None, &mut state).unwrap();
+        let file_id = upload(&mut state, owner);
+        move_file(owner, file_id, Some(folder.folder_id), &mut state).unwrap();
+
+        delete_file(&mut state, owner, file_id);
+        assert!(list_files(owner, &state).is_empty());
+        assert_eq!(list_trash(owner, &state)[0].size, 3);
+        assert_eq!(trashed_bytes(&state, owner), 3);
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![1, 2, 3]));
+
+        assert_eq!(restore_file(Principal::from_slice(&[9]), file_id, &mut state), Err(TrashError::NotFound));
+        assert_eq!(restore_file(owner, file_id, &mut state), Ok(()));
+        assert_eq!(list_files(owner, &state).len(), 1);
+        assert_eq!(state.file_folders.get(&file_id), Some(&folder.folder_id));
+        assert!(list_trash(owner, &state).is_empty());
+    }
+
+    #[test]
+    fn trashed_files_reject_uploads() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "photos.zip".to_string(),
+                content: vec![0],
+                file_type: "application/zip".to_string(),
+                num_chunks: 2,
+            },
+            &mut state,
+        ).unwrap();
+        delete_file(&mut state, owner, file_id);
+        assert!(is_trashed(&state, file_id));
+
+        assert!(matches!(
+            crate::api::upload_chunks(owner, file_id, vec![(1, vec![1])], &mut state),
+            Err(crate::UploadFileError::NotAuthenticated)
+        ));
+        let request = crate::UploadFileContinueRequest {
+            file_id,
+            chunk_id: 1,
+            contents: vec![1],
+        };
+        assert!(matches!(
+            crate::api::upload_file_continue(owner, request, &mut state),
+            Err(crate::UploadFileError::NotAuthenticated)
+        ));
+        assert_eq!(state.file_contents.get(file_id, 1), None);
+
+        // Indexing it again, as metadata edits do, doesn't list it either.
+        crate::api::file_index::index_file(&mut state, file_id);
+        let request = crate::api::ListFilesRequest {
+            cursor: None,
+            limit: None,
+            sort_by: crate::api::FileSortKey::Name,
+            order: crate::api::SortOrder::Asc,
+            status_filter: None,
+            provider_filter: None,
+            name_prefix: None,
+        };
+        assert!(crate::api::list_files_v2(owner, request, &state).unwrap().files.is_empty());
+        assert!(list_trash(owner, &state).iter().any(|f| f.file_id == file_id));
+    }
+
+    #[test]
+    fn trash_is_purged_when_emptied_or_expired() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let a = upload(&mut state, owner);
+        delete_file(&mut state, owner, a);
+
+        let purge_at = list_trash(owner, &state)[0].purge_at;
+        purge_expired_trash(&mut state, purge_at - 1);
+        assert_eq!(list_trash(owner, &state).len(), 1);
+        purge_expired_trash(&mut state, purge_at);
+        assert!(list_trash(owner, &state).is_empty());
+        assert_eq!(state.file_contents.get(a, 0), None);
+
+        let b = upload(&mut state, owner);
+        delete_file(&mut state, owner, b);
+        empty_trash(owner, &mut state).unwrap();
+        assert!(state.file_data.is_empty());
+        assert!(state.trash.is_empty());
+        assert!(state.trash_expiry.is_empty());
+    }
+
+    #[test]
+    fn chunks_of_large_files_are_freed_in_batches() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let num_chunks = crate::api::DELETE_BATCH_CHUNKS + 10;
+        let file_id = upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "backup.tar".to_string(),
+                content: vec![0],
+                file_type: "application/x-tar".to_string(),
+                num_chunks,
+            },
+            &mut state,
+        ).unwrap();
+        for chunk_id in 1..num_chunks {
+            let request = crate::UploadFileContinueRequest {
+                file_id,
+                chunk_id,
+                contents: vec![0],
+            };
+            crate::api::upload_file_continue(owner, request, &mut state).unwrap();
+        }
+
+        delete_file(&mut state, owner, file_id);
+        empty_trash(owner, &mut state).unwrap();
+        assert!(state.file_data.is_empty());
+        assert_eq!(state.file_contents.chunk_count(file_id), 10);
+        assert_eq!(state.deleting[&file_id].next_chunk, crate::api::DELETE_BATCH_CHUNKS);
+
+        crate::api::reclaim_deleted_chunks(&mut state);
+        assert_eq!(state.file_contents.chunk_count(file_id), 0);
+        assert!(state.deleting.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{with_state_mut, FileContent, State, UploadFileError};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// Outcome of storing one chunk of an `upload_chunks` batch.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ChunkUploadResult {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "invalid_chunk_id")]
+    InvalidChunkId,
+    #[serde(rename = "already_uploaded")]
+    AlreadyUploaded,
+    #[serde(rename = "invalid_chunk_length")]
+    InvalidChunkLength,
+    /// The file's bucket didn't store the chunk; it can be uploaded again.
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+/// Stores several chunks of a partially uploaded file in one call.
+///
+/// Chunks are validated one by one, so a bad chunk doesn't reject the rest of the
+/// batch; the results are returned in the order of `chunks`. The batch is bounded
+/// by the ingress message limit (2 MiB).
+pub fn upload_chunks(
+    caller: Principal,
+    file_id: u64,
+    chunks: Vec<(u64, Vec<u8>)>,
+    state: &mut State,
+) -> Result<Vec<ChunkUploadResult>, UploadFileError> {
+    if caller == Principal::anonymous() {
+        return Err(UploadFileError::NotAuthenticated);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(UploadFileError::Suspended);
+    }
+
+    let file = state.file_data.get(&file_id).ok_or(UploadFileError::NotRequested)?;
+    // Staged versions have no entry in `file_owners`; trashed files lost theirs.
+    let owned = if crate::api::versions::is_staged_version(state, file_id) {
+        file.metadata.requester_principal == caller
+    } else {
+        state.is_file_owner(caller, file_id)
+    };
+    if !owned {
+        return Err(UploadFileError::NotAuthenticated);
+    }
+    let num_chunks = match &file.content {
+        FileContent::PartiallyUploaded { num_chunks, .. } => *num_chunks,
+        FileContent::Uploaded { .. } => return Err(UploadFileError::AlreadyUploaded),
+        FileContent::Pending { .. } => return Err(UploadFileError::NotRequested),
+    };
+
+    let mut results = Vec::with_capacity(chunks.len());
+    for (chunk_id, contents) in chunks {
+        if chunk_id >= num_chunks {
+            results.push(ChunkUploadResult::InvalidChunkId);
+        } else if state.has_chunk(file_id, chunk_id) {
+            results.push(ChunkUploadResult::AlreadyUploaded);
+        } else if !chunk_len_fits(state, file_id, chunk_id, num_chunks, contents.len() as u64) {
+            results.push(ChunkUploadResult::InvalidChunkLength);
+        } else {
+            state.certified.certify_chunk(file_id, chunk_id, &contents);
+            state.store_chunk(file_id, chunk_id, contents);
+            results.push(ChunkUploadResult::Ok);
+        }
+    }
+    // Committed here: `complete_if_stored` doesn't commit for staged versions.
+    state.certified.commit();
+    complete_if_stored(state, file_id);
+    Ok(results)
+}
+
+/// `upload_chunks` for the canister endpoint: also waits for the file's bucket to
+/// store the chunks, and reports the ones it didn't as `StorageError`.
+pub async fn upload_chunks_and_store(
+    caller: Principal,
+    file_id: u64,
+    chunks: Vec<(u64, Vec<u8>)>,
+) -> Result<Vec<ChunkUploadResult>, UploadFileError> {
+    let chunk_ids: Vec<u64> = chunks.iter().map(|(chunk_id, _)| *chunk_id).collect();
+    let mut results = with_state_mut(|s| upload_chunks(caller, file_id, chunks, s))?;
+    let failed = crate::api::send_bucket_writes().await;
+    for (result, chunk_id) in results.iter_mut().zip(chunk_ids) {
+        if *result != ChunkUploadResult::Ok {
+            continue;
+        }
+        if let Some(error) = failed.get(&(file_id, chunk_id)) {
+            *result = ChunkUploadResult::StorageError(error.clone());
+        }
+    }
+    Ok(results)
+}
+
+/// Checks the length of a new chunk. In an upload session it must be the session's
+/// chunk size, or the rest of the file for the last chunk. Otherwise it is checked
+/// against the first chunk: every chunk but the last must have the same length, and
+/// the last at most that, so the HTTP gateway can map byte offsets onto chunks.
+/// This passes while the first chunk isn't known yet.
+pub(crate) fn chunk_len_fits(state: &State, file_id: u64, chunk_id: u64, num_chunks: u64, len: u64) -> bool {
+    if let Some(session) = state.upload_sessions.get(&file_id) {
+        return len == crate::api::upload_session::expected_chunk_len(session, chunk_id);
+    }
+    let Some(chunk_size) = state.chunk_len(file_id, 0).filter(|_| chunk_id != 0) else {
+        return true;
+    };
+    if chunk_id + 1 < num_chunks {
+        len == chunk_size
+    } else {
+        len <= chunk_size
+    }
+}
+
+/// Marks a partially uploaded file as uploaded once all of its chunks are stored, and
+/// reindexes and recertifies it. Files of an upload session only complete through
+/// `finalize_upload`.
+pub(crate) fn complete_if_stored(state: &mut State, file_id: u64) {
+    let in_session = state.upload_sessions.contains_key(&file_id);
+    let stored = state.chunks_for(file_id).chunk_count(file_id);
+    if let Some(file) = state.file_data.get_mut(&file_id) {
+        if let FileContent::PartiallyUploaded { num_chunks, .. } = file.content {
+            if !in_session && stored == num_chunks {
+                // Imported files are created without an upload time.
+                file.metadata.uploaded_at.get_or_insert(crate::get_time());
+                let content = std::mem::replace(&mut file.content, FileContent::Pending { alias: String::new() });
+                file.content = match content {
+                    FileContent::PartiallyUploaded { num_chunks, file_type, owner_key } => FileContent::Uploaded {
+                        num_chunks,
+                        file_type,
+                        owner_key,
+                    },
+                    other => other,
+                };
+            }
+        }
+    }
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{upload_file_atomic, UploadFileAtomicRequest};
+
+    fn start_upload(state: &mut State, owner: Principal, num_chunks: u64) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "photos.zip".to_string(),
+                content: vec![0],
+                file_type: "application/zip".to_string(),
+                num_chunks,
+            },
+            state,
+        ).unwrap()
+    }
+
+    #[test]
+    fn batch_reports_each_chunk_and_completes_file() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = start_upload(&mut state, owner, 3);
+
+        let results = upload_chunks(
+            owner,
+            file_id,
+            vec![(1, vec![1]), (0, vec![9]), (7, vec![7]), (1, vec![1])],
+            &mut state,
+        );
+        assert_eq!(
+            results.unwrap(),
+            vec![
+                ChunkUploadResult::Ok,
+                ChunkUploadResult::AlreadyUploaded,
+                ChunkUploadResult::InvalidChunkId,
+                ChunkUploadResult::AlreadyUploaded,
+            ]
+        );
+        assert!(matches!(state.file_data[&file_id].content, FileContent::PartiallyUploaded { .. }));
+
+        assert_eq!(upload_chunks(owner, file_id, vec![(2, vec![2])], &mut state).unwrap(), vec![ChunkUploadResult::Ok]);
+        assert!(matches!(state.file_data[&file_id].content, FileContent::Uploaded { .. }));
+        assert_eq!(state.file_contents.get(file_id, 2), Some(vec![2]));
+    }
+
+    #[test]
+    fn chunks_must_match_the_first_chunk_length() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = start_upload(&mut state, owner, 3);
+
+        let results = upload_chunks(owner, file_id, vec![(1, vec![1, 1]), (2, vec![2, 2])], &mut state);
+        assert_eq!(
+            results.unwrap(),
+            vec![ChunkUploadResult::InvalidChunkLength, ChunkUploadResult::InvalidChunkLength]
+        );
+        let results = upload_chunks(owner, file_id, vec![(1, vec![1]), (2, vec![])], &mut state);
+        assert_eq!(results.unwrap(), vec![ChunkUploadResult::Ok, ChunkUploadResult::Ok]);
+    }
+
+    #[test]
+    fn batch_rejected_for_other_callers_and_finished_files() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = start_upload(&mut state, owner, 1);
+
+        assert!(matches!(
+            upload_chunks(Principal::from_slice(&[9]), file_id, vec![], &mut state),
+            Err(UploadFileError::NotAuthenticated)
+        ));
+        assert!(matches!(
+            upload_chunks(owner, file_id, vec![(0, vec![1])], &mut state),
+            Err(UploadFileError::AlreadyUploaded)
+        ));
+        assert!(matches!(
+            upload_chunks(owner, 42, vec![], &mut state),
+            Err(UploadFileError::NotRequested)
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{File, FileContent, FileMetadata, State};
+use crate::{with_state_mut, File, FileContent, FileMetadata, State, UploadFileError};
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
@@ -76,27 +76,29 @@ pub fn upload_file_atomic(
     caller: Principal,
     request: UploadFileAtomicRequest,
     state: &mut State,
-) -> u64 {
+) -> Result<u64, UploadFileError> {
     if caller == Principal::anonymous() {
-        panic!("Not authenticated");
+        return Err(UploadFileError::NotAuthenticated);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(UploadFileError::Suspended);
     }
 
     let file_id = state.generate_file_id();
-    let content = if request.num_chunks == 1 {
-        FileContent::Uploaded {
-            num_chunks: request.num_chunks,
-            file_type: request.file_type.clone(),
-            owner_key: vec![], // Empty owner key for now
-        }
-    } else {
-        FileContent::PartiallyUploaded {
-            num_chunks: request.num_chunks,
-            file_type: request.file_type.clone(),
-            owner_key: vec![], // Empty owner key for now
-        }
+    // A single-chunk file is complete as soon as its chunk is stored, see `complete_if_stored`.
+    let content = FileContent::PartiallyUploaded {
+        num_chunks: request.num_chunks,
+        file_type: request.file_type.clone(),
+        owner_key: vec![], // Empty owner key for now
     };
-    
-    state.file_contents.insert((file_id, 0), request.content.clone());
+
+    // Place the file in the caller's dedicated canister, or a shared bucket with room for it.
+    let estimated_size = (request.content.len() as u64).saturating_mul(request.num_chunks);
+    let bucket = crate::api::buckets::pick_bucket(state, caller, estimated_size);
+    if let Some(bucket) = bucket {
+        crate::api::buckets::reserve_in_bucket(state, bucket, file_id, estimated_size, caller);
+    }
+
     state.file_data.insert(
         file_id,
         File {
@@ -107,10 +109,15 @@ pub fn upload_file_atomic(
                 uploaded_at: Some(crate::get_time()),
                 storage_provider: "icp".to_string(),
                 blob_id: None,
+                bucket,
+                description: String::new(),
+                tags: vec![],
             },
             content,
         },
     );
+    crate::certification::certify_chunk(state, file_id, 0, &request.content);
+    state.store_chunk(file_id, 0, request.content);
 
     // Add the caller as the owner of this file
     state
@@ -119,13 +126,28 @@ pub fn upload_file_atomic(
         .or_insert_with(Vec::new)
         .push(file_id);
 
-    file_id
+    crate::api::upload_chunks::complete_if_stored(state, file_id);
+    Ok(file_id)
+}
+
+/// `upload_file_atomic` for the canister endpoint: also waits for the file's bucket
+/// to store the first chunk. The file is dropped if it didn't, as the caller never
+/// learns its ID.
+pub async fn upload_file_atomic_and_store(
+    caller: Principal,
+    request: UploadFileAtomicRequest,
+) -> Result<u64, UploadFileError> {
+    let file_id = with_state_mut(|s| upload_file_atomic(caller, request, s))?;
+    if let Some(error) = crate::api::send_bucket_writes().await.into_values().next() {
+        with_state_mut(|s| crate::api::delete_file::purge_file(s, file_id));
+        return Err(UploadFileError::StorageError(error));
+    }
+    Ok(file_id)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{File, FileContent, FileMetadata, State};
 
     fn make_request(name: &str, content: Vec<u8>, file_type: &str, num_chunks: u64) -> UploadFileAtomicRequest {
         UploadFileAtomicRequest {
@@ -140,32 +162,32 @@ mod test {
     fn upload_single_chunk_file() {
         let mut state = State::default();
         let req = make_request("file1.txt", vec![1, 2, 3], "txt", 1);
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
-        let file_id = upload_file_atomic(test_principal, req, &mut state);
+        let test_principal = Principal::from_slice(&[1]);
+        let file_id = upload_file_atomic(test_principal, req, &mut state).unwrap();
         let file = state.file_data.get(&file_id).unwrap();
         assert_eq!(file.metadata.file_name, "file1.txt");
-        assert!(matches!(&file.content, FileContent::Uploaded { num_chunks: 1, file_type: ref ft } if ft == "txt"));
-        assert_eq!(state.file_contents.get(&(file_id, 0)).map(|v| v.clone()), Some(vec![1, 2, 3]));
+        assert!(matches!(&file.content, FileContent::Uploaded { num_chunks: 1, file_type: ref ft, .. } if ft == "txt"));
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![1, 2, 3]));
     }
 
     #[test]
     fn upload_multi_chunk_file_first_chunk() {
         let mut state = State::default();
         let req = make_request("bigfile.bin", vec![10, 20, 30], "bin", 3);
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
-        let file_id = upload_file_atomic(test_principal, req, &mut state);
+        let test_principal = Principal::from_slice(&[1]);
+        let file_id = upload_file_atomic(test_principal, req, &mut state).unwrap();
         let file = state.file_data.get(&file_id).unwrap();
         assert_eq!(file.metadata.file_name, "bigfile.bin");
-        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft } if ft == "bin"));
-        assert_eq!(state.file_contents.get(&(file_id, 0)).map(|v| v.clone()), Some(vec![10, 20, 30]));
+        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft, .. } if ft == "bin"));
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![10, 20, 30]));
     }
 
     #[test]
     fn file_id_increments() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
-        let id1 = upload_file_atomic(test_principal, make_request("a", vec![1], "txt", 1), &mut state);
-        let id2 = upload_file_atomic(test_principal, make_request("b", vec![2], "txt", 1), &mut state);
+        let test_principal = Principal::from_slice(&[1]);
+        let id1 = upload_file_atomic(test_principal, make_request("a", vec![1], "txt", 1), &mut state).unwrap();
+        let id2 = upload_file_atomic(test_principal, make_request("b", vec![2], "txt", 1), &mut state).unwrap();
         assert_ne!(id1, id2);
         assert_eq!(id2, id1 + 1);
     }
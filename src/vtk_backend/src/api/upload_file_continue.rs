@@ -1,4 +1,4 @@
-use crate::{FileContent, State, UploadFileContinueRequest, UploadFileError};
+use crate::{with_state_mut, FileContent, State, UploadFileContinueRequest, UploadFileError};
 use candid::Principal;
 
 pub fn upload_file_continue(
@@ -10,58 +10,58 @@ pub fn upload_file_continue(
     if caller == Principal::anonymous() {
         return Err(UploadFileError::NotAuthenticated);
     }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(UploadFileError::Suspended);
+    }
 
     let file_id = request.file_id;
     let chunk_id = request.chunk_id;
 
-    let updated_file_data = match state.file_data.remove(&file_id) {
-        Some(mut file) => {
-            // Check if the caller is the owner of this file
-            if file.metadata.requester_principal != caller {
-                return Err(UploadFileError::NotAuthenticated);
-            }
-
-            let updated_contents = match file.content {
-                FileContent::PartiallyUploaded { num_chunks, file_type, owner_key } => {
-                    assert!(chunk_id < num_chunks, "invalid chunk id");
-                    assert!(
-                        !state.file_contents.contains_key(&(file_id, chunk_id)),
-                        "chunk already uploaded"
-                    );
-                    state.file_contents.insert((file_id, chunk_id), request.contents);
-                    if state.file_contents
-                        .range((file_id, 0)..=(file_id, num_chunks - 1))
-                        .count() as u64
-                        == num_chunks
-                    {
-                        FileContent::Uploaded {
-                            num_chunks,
-                            file_type,
-                            owner_key,
-                        }
-                    } else {
-                        FileContent::PartiallyUploaded {
-                            num_chunks,
-                            file_type,
-                            owner_key,
-                        }
-                    }
-                }
-                f => panic!("expected a partially uploaded file. Found: {f:?}"),
-            };
-            file.content = updated_contents;
-            file
+    // Check if the caller is the owner of this file. Staged versions have no entry
+    // in `file_owners`; trashed files lost theirs.
+    let owned = match state.file_data.get(&file_id) {
+        Some(file) if crate::api::versions::is_staged_version(state, file_id) => {
+            file.metadata.requester_principal == caller
         }
+        Some(_) => state.is_file_owner(caller, file_id),
         None => panic!("file doesn't exist"),
     };
-    assert_eq!(state.file_data.insert(file_id, updated_file_data), None);
+    if !owned {
+        return Err(UploadFileError::NotAuthenticated);
+    }
+
+    let num_chunks = match &state.file_data[&file_id].content {
+        FileContent::PartiallyUploaded { num_chunks, .. } => *num_chunks,
+        f => panic!("expected a partially uploaded file. Found: {f:?}"),
+    };
+    assert!(chunk_id < num_chunks, "invalid chunk id");
+    assert!(!state.has_chunk(file_id, chunk_id), "chunk already uploaded");
+    let len = request.contents.len() as u64;
+    if !crate::api::upload_chunks::chunk_len_fits(state, file_id, chunk_id, num_chunks, len) {
+        return Err(UploadFileError::InvalidChunkLength);
+    }
+    crate::certification::certify_chunk(state, file_id, chunk_id, &request.contents);
+    state.store_chunk(file_id, chunk_id, request.contents);
+    crate::api::upload_chunks::complete_if_stored(state, file_id);
     Ok(())
 }
 
+/// `upload_file_continue` for the canister endpoint: also waits for the file's
+/// bucket to store the chunk.
+pub async fn upload_file_continue_and_store(
+    caller: Principal,
+    request: UploadFileContinueRequest,
+) -> Result<(), UploadFileError> {
+    with_state_mut(|s| upload_file_continue(caller, request, s))?;
+    match crate::api::send_bucket_writes().await.into_values().next() {
+        Some(error) => Err(UploadFileError::StorageError(error)),
+        None => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{File, FileContent, FileMetadata, State};
     use crate::api::UploadFileAtomicRequest;
 
     fn make_atomic_request(name: &str, content: Vec<u8>, file_type: &str, num_chunks: u64) -> UploadFileAtomicRequest {
@@ -76,13 +76,13 @@ mod test {
     #[test]
     fn upload_file_continue_transitions() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
         // First chunk (atomic)
         let req = make_atomic_request("bigfile.bin", vec![1, 2, 3], "bin", 3);
         let file_id = crate::api::upload_file_atomic(test_principal, req, &mut state).unwrap();
         // Should be PartiallyUploaded
         let file = state.file_data.get(&file_id).unwrap();
-        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft } if ft == "bin"));
+        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft, .. } if ft == "bin"));
         // Second chunk
         upload_file_continue(
             test_principal,
@@ -94,7 +94,7 @@ mod test {
             &mut state,
         ).unwrap();
         let file = state.file_data.get(&file_id).unwrap();
-        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft } if ft == "bin"));
+        assert!(matches!(&file.content, FileContent::PartiallyUploaded { num_chunks: 3, file_type: ref ft, .. } if ft == "bin"));
         // Third chunk
         upload_file_continue(
             test_principal,
@@ -106,17 +106,34 @@ mod test {
             &mut state,
         ).unwrap();
         let file = state.file_data.get(&file_id).unwrap();
-        assert!(matches!(&file.content, FileContent::Uploaded { num_chunks: 3, file_type: ref ft } if ft == "bin"));
+        assert!(matches!(&file.content, FileContent::Uploaded { num_chunks: 3, file_type: ref ft, .. } if ft == "bin"));
         // Check chunk data
-        assert_eq!(state.file_contents.get(&(file_id, 0)).map(|v| v.clone()), Some(vec![1, 2, 3]));
-        assert_eq!(state.file_contents.get(&(file_id, 1)).map(|v| v.clone()), Some(vec![4, 5, 6]));
-        assert_eq!(state.file_contents.get(&(file_id, 2)).map(|v| v.clone()), Some(vec![7, 8, 9]));
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![1, 2, 3]));
+        assert_eq!(state.file_contents.get(file_id, 1), Some(vec![4, 5, 6]));
+        assert_eq!(state.file_contents.get(file_id, 2), Some(vec![7, 8, 9]));
+    }
+
+    #[test]
+    fn uneven_chunks_are_rejected() {
+        let mut state = State::default();
+        let test_principal = Principal::from_slice(&[1]);
+        let req = make_atomic_request("bigfile.bin", vec![1, 2, 3], "bin", 3);
+        let file_id = crate::api::upload_file_atomic(test_principal, req, &mut state).unwrap();
+
+        let continue_with = |chunk_id, contents, state: &mut State| {
+            upload_file_continue(test_principal, UploadFileContinueRequest { file_id, chunk_id, contents }, state)
+        };
+        assert!(matches!(continue_with(1, vec![4, 5], &mut state), Err(UploadFileError::InvalidChunkLength)));
+        assert!(matches!(continue_with(2, vec![7, 8, 9, 10], &mut state), Err(UploadFileError::InvalidChunkLength)));
+        assert!(continue_with(1, vec![4, 5, 6], &mut state).is_ok());
+        assert!(continue_with(2, vec![7], &mut state).is_ok());
+        assert!(matches!(state.file_data[&file_id].content, FileContent::Uploaded { .. }));
     }
 
     #[test]
     fn anonymous_user_cannot_continue_upload() {
         let mut state = State::default();
-        let test_principal = Principal::from_text("2vxsx-fae").unwrap();
+        let test_principal = Principal::from_slice(&[1]);
         // First create a file with authenticated user
         let req = make_atomic_request("bigfile.bin", vec![1, 2, 3], "bin", 3);
         let file_id = crate::api::upload_file_atomic(test_principal, req, &mut state).unwrap();
@@ -137,8 +154,8 @@ mod test {
     #[test]
     fn wrong_user_cannot_continue_upload() {
         let mut state = State::default();
-        let test_principal1 = Principal::from_text("2vxsx-fae").unwrap();
-        let test_principal2 = Principal::from_text("3vxsx-fae").unwrap(); // Different principal
+        let test_principal1 = Principal::from_slice(&[1]);
+        let test_principal2 = Principal::from_slice(&[2]); // Different principal
         // First create a file with authenticated user
         let req = make_atomic_request("bigfile.bin", vec![1, 2, 3], "bin", 3);
         let file_id = crate::api::upload_file_atomic(test_principal1, req, &mut state).unwrap();
@@ -0,0 +1,287 @@
+use crate::certification::sha256;
+use crate::{File, FileContent, FileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// Chunk size handed out to upload sessions. Leaves room for the candid
+/// overhead of `upload_chunks` within the 2 MiB ingress limit.
+pub const UPLOAD_CHUNK_SIZE: u64 = 1_900_000;
+pub const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BeginUploadRequest {
+    pub name: String,
+    pub file_type: String,
+    pub total_size: u64,
+}
+
+/// An upload in progress: the file it creates and how its contents must be split.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UploadSession {
+    pub file_id: u64,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub num_chunks: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FinalizeUploadRequest {
+    pub file_id: u64,
+    // SHA-256 of the concatenated SHA-256 hashes of every chunk, in order.
+    pub sha256: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UploadSessionError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_size")]
+    InvalidSize,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "incomplete")]
+    Incomplete { received_chunks: u64, num_chunks: u64 },
+    #[serde(rename = "size_mismatch")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[serde(rename = "hash_mismatch")]
+    HashMismatch,
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+/// Creates a file and an upload session for it. Chunks are then sent with
+/// `upload_chunks` or `upload_file_continue`, split at the returned chunk size.
+pub fn begin_upload(
+    caller: Principal,
+    request: BeginUploadRequest,
+    state: &mut State,
+) -> Result<UploadSession, UploadSessionError> {
+    if caller == Principal::anonymous() {
+        return Err(UploadSessionError::NotAuthenticated);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(UploadSessionError::Suspended);
+    }
+    if request.total_size == 0 || request.total_size > MAX_UPLOAD_SIZE {
+        return Err(UploadSessionError::InvalidSize);
+    }
+
+    let file_id = state.generate_file_id();
+    let num_chunks = request.total_size.div_ceil(UPLOAD_CHUNK_SIZE);
+
+    // Place the file in the caller's dedicated canister, or a shared bucket with room for it.
+    let bucket = crate::api::buckets::pick_bucket(state, caller, request.total_size);
+    if let Some(bucket) = bucket {
+        crate::api::buckets::reserve_in_bucket(state, bucket, file_id, request.total_size, caller);
+    }
+
+    state.file_data.insert(
+        file_id,
+        File {
+            metadata: FileMetadata {
+                file_name: request.name,
+                requester_principal: caller,
+                requested_at: crate::get_time(),
+                uploaded_at: None,
+                storage_provider: "icp".to_string(),
+                blob_id: None,
+                bucket,
+                description: String::new(),
+                tags: vec![],
+            },
+            content: FileContent::PartiallyUploaded {
+                num_chunks,
+                file_type: request.file_type,
+                owner_key: vec![],
+            },
+        },
+    );
+    state
+        .file_owners
+        .entry(caller)
+        .or_insert_with(Vec::new)
+        .push(file_id);
+
+    let session = UploadSession {
+        file_id,
+        total_size: request.total_size,
+        chunk_size: UPLOAD_CHUNK_SIZE,
+        num_chunks,
+    };
+    state.upload_sessions.insert(file_id, session.clone());
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+    Ok(session)
+}
+
+/// Length chunk `chunk_id` of a session must have: the session's chunk size, or
+/// what is left of the file for the last chunk.
+pub(crate) fn expected_chunk_len(session: &UploadSession, chunk_id: u64) -> u64 {
+    if chunk_id + 1 < session.num_chunks {
+        session.chunk_size
+    } else {
+        session.total_size - (session.num_chunks - 1) * session.chunk_size
+    }
+}
+
+/// Checks that every chunk arrived, their total length and hash, then marks the file uploaded.
+///
+/// On a size or hash mismatch the session stays open; delete the file to abandon it.
+pub fn finalize_upload(
+    caller: Principal,
+    request: FinalizeUploadRequest,
+    state: &mut State,
+) -> Result<(), UploadSessionError> {
+    if caller == Principal::anonymous() {
+        return Err(UploadSessionError::NotAuthenticated);
+    }
+    let file_id = request.file_id;
+    let staged_version = crate::api::versions::is_staged_version(state, file_id);
+    let owned = match state.file_data.get(&file_id) {
+        Some(file) if staged_version => file.metadata.requester_principal == caller,
+        _ => state.is_file_owner(caller, file_id),
+    };
+    let session = match state.upload_sessions.get(&file_id) {
+        Some(session) if owned => session.clone(),
+        _ => return Err(UploadSessionError::NotFound),
+    };
+
+    let chunks = state.chunks_for(file_id);
+    let received_chunks = chunks.chunk_count(file_id);
+    if received_chunks != session.num_chunks {
+        return Err(UploadSessionError::Incomplete {
+            received_chunks,
+            num_chunks: session.num_chunks,
+        });
+    }
+    let actual = chunks.size(file_id);
+    if actual != session.total_size {
+        return Err(UploadSessionError::SizeMismatch {
+            expected: session.total_size,
+            actual,
+        });
+    }
+    let chunk_hashes: Vec<u8> = (0..session.num_chunks)
+        .flat_map(|chunk_id| state.certified.chunk_hash(file_id, chunk_id).unwrap_or_default())
+        .collect();
+    if sha256(&chunk_hashes).as_slice() != request.sha256.as_slice() {
+        return Err(UploadSessionError::HashMismatch);
+    }
+
+    if let Some(file) = state.file_data.get_mut(&file_id) {
+        let content = std::mem::replace(&mut file.content, FileContent::Pending { alias: String::new() });
+        file.content = match content {
+            FileContent::PartiallyUploaded { num_chunks, file_type, owner_key } => FileContent::Uploaded {
+                num_chunks,
+                file_type,
+                owner_key,
+            },
+            other => other,
+        };
+        file.metadata.uploaded_at = Some(crate::get_time());
+    }
+    state.upload_sessions.remove(&file_id);
+    if staged_version {
+        crate::api::versions::commit_version(state, file_id);
+        return Ok(());
+    }
+    crate::api::file_index::index_file(state, file_id);
+    crate::certification::certify_file(state, file_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{upload_chunks, ChunkUploadResult};
+
+    fn session_hash(chunks: &[&[u8]]) -> Vec<u8> {
+        let hashes: Vec<u8> = chunks.iter().flat_map(|chunk| sha256(chunk)).collect();
+        sha256(&hashes).to_vec()
+    }
+
+    fn begin(state: &mut State, owner: Principal, total_size: u64) -> UploadSession {
+        let request = BeginUploadRequest {
+            name: "backup.tar".to_string(),
+            file_type: "application/x-tar".to_string(),
+            total_size,
+        };
+        begin_upload(owner, request, state).unwrap()
+    }
+
+    #[test]
+    fn session_splits_by_canister_chunk_size() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let session = begin(&mut state, owner, 2 * UPLOAD_CHUNK_SIZE + 1);
+        assert_eq!(session.chunk_size, UPLOAD_CHUNK_SIZE);
+        assert_eq!(session.num_chunks, 3);
+
+        let too_big = BeginUploadRequest {
+            name: "huge".to_string(),
+            file_type: "".to_string(),
+            total_size: MAX_UPLOAD_SIZE + 1,
+        };
+        assert_eq!(begin_upload(owner, too_big, &mut state), Err(UploadSessionError::InvalidSize));
+    }
+
+    #[test]
+    fn finalize_verifies_chunks_size_and_hash() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let session = begin(&mut state, owner, 5);
+        let file_id = session.file_id;
+        let finalize = |sha256: Vec<u8>| FinalizeUploadRequest { file_id, sha256 };
+
+        assert_eq!(
+            finalize_upload(owner, finalize(vec![]), &mut state),
+            Err(UploadSessionError::Incomplete { received_chunks: 0, num_chunks: 1 })
+        );
+
+        // Uploads already reject chunks that don't fit the session; a short file
+        // can only come from the store itself.
+        state.file_contents.put(file_id, 0, vec![1, 2, 3]);
+        assert_eq!(
+            finalize_upload(owner, finalize(session_hash(&[&[1, 2, 3]])), &mut state),
+            Err(UploadSessionError::SizeMismatch { expected: 5, actual: 3 })
+        );
+    }
+
+    #[test]
+    fn chunks_are_split_at_the_session_chunk_size() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let session = begin(&mut state, owner, UPLOAD_CHUNK_SIZE + 2);
+        assert_eq!(expected_chunk_len(&session, 0), UPLOAD_CHUNK_SIZE);
+        assert_eq!(expected_chunk_len(&session, 1), 2);
+        let file_id = session.file_id;
+
+        let results = upload_chunks(owner, file_id, vec![(1, vec![1]), (1, vec![1, 2, 3])], &mut state).unwrap();
+        assert_eq!(results, vec![ChunkUploadResult::InvalidChunkLength, ChunkUploadResult::InvalidChunkLength]);
+        let results = upload_chunks(owner, file_id, vec![(0, vec![0; 10]), (1, vec![1, 2])], &mut state).unwrap();
+        assert_eq!(results, vec![ChunkUploadResult::InvalidChunkLength, ChunkUploadResult::Ok]);
+
+        // Sessions don't complete on their own once all chunks are in.
+        let results = upload_chunks(owner, file_id, vec![(0, vec![0; UPLOAD_CHUNK_SIZE as usize])], &mut state).unwrap();
+        assert_eq!(results, vec![ChunkUploadResult::Ok]);
+        assert!(matches!(state.file_data[&file_id].content, FileContent::PartiallyUploaded { .. }));
+    }
+
+    #[test]
+    fn finalize_marks_file_uploaded() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = begin(&mut state, owner, 3).file_id;
+        upload_chunks(owner, file_id, vec![(0, vec![1, 2, 3])], &mut state).unwrap();
+
+        let wrong = FinalizeUploadRequest { file_id, sha256: session_hash(&[&[3, 2, 1]]) };
+        assert_eq!(finalize_upload(owner, wrong, &mut state), Err(UploadSessionError::HashMismatch));
+
+        let other = Principal::from_slice(&[9]);
+        let right = FinalizeUploadRequest { file_id, sha256: session_hash(&[&[1, 2, 3]]) };
+        assert_eq!(finalize_upload(other, right.clone(), &mut state), Err(UploadSessionError::NotFound));
+        assert_eq!(finalize_upload(owner, right, &mut state), Ok(()));
+        assert!(matches!(state.file_data[&file_id].content, FileContent::Uploaded { .. }));
+        assert!(state.upload_sessions.is_empty());
+    }
+}
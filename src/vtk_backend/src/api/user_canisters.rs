@@ -0,0 +1,339 @@
+use crate::api::buckets::{create_and_install_bucket, unregister_bucket, Bucket};
+use crate::api::{require_admin, AdminError};
+use crate::{with_state, with_state_mut, CreateUserRequest, State, UserResponse};
+use candid::{CandidType, Principal};
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::api::management_canister::main::{
+    delete_canister, deposit_cycles, stop_canister, update_settings, CanisterIdRecord, CanisterSettings,
+    UpdateSettingsArgument,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+// Users that opt into dedicated storage get a bucket canister of their own. It
+// runs the same wasm as the shared buckets, but only ever holds that user's
+// chunks; the index keeps the metadata and routes uploads and downloads to it.
+// Each canister is funded by the index, so only users an admin allowed can
+// provision one, and the total number is capped.
+
+/// Upper bound on the dedicated canisters the index creates and keeps topped up.
+pub const MAX_USER_CANISTERS: u64 = 100;
+
+thread_local! {
+    // Users whose canister is being created, so concurrent calls don't create two.
+    static PROVISIONING: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum UserCanisterError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "not_allowed")]
+    NotAllowed,
+    #[serde(rename = "suspended")]
+    Suspended,
+    #[serde(rename = "limit_reached")]
+    LimitReached,
+    #[serde(rename = "already_provisioned")]
+    AlreadyProvisioned,
+    #[serde(rename = "no_dedicated_canister")]
+    NoDedicatedCanister,
+    #[serde(rename = "call_failed")]
+    CallFailed(String),
+}
+
+/// The dedicated storage canister of `user`, if they have one.
+pub(crate) fn dedicated_canister(state: &State, user: Principal) -> Option<Principal> {
+    state.user_canisters.get(&user)
+}
+
+/// Registers the dedicated canisters recorded in stable memory as buckets again,
+/// marking those `restore_buckets` already restored as dedicated.
+pub fn restore_user_canisters(state: &mut State) {
+    let canisters: Vec<(Principal, Principal)> = state.user_canisters.iter().collect();
+    for (user, canister_id) in canisters {
+        state
+            .buckets
+            .entry(canister_id)
+            .or_insert_with(|| Bucket::new(canister_id, None))
+            .dedicated_to = Some(user);
+    }
+}
+
+/// Lets `user` provision a dedicated canister, or takes that permission back.
+/// A canister that was already created is kept.
+pub fn allow_dedicated_storage(
+    caller: Principal,
+    is_controller: bool,
+    user: Principal,
+    allowed: bool,
+    state: &mut State,
+) -> Result<(), AdminError> {
+    require_admin(caller, is_controller, state)?;
+    if allowed {
+        state.dedicated_storage_allowed.insert(user);
+    } else {
+        state.dedicated_storage_allowed.remove(&user);
+    }
+    Ok(())
+}
+
+// `in_flight` counts the canisters being created right now.
+fn check_can_provision(caller: Principal, in_flight: u64, state: &State) -> Result<(), UserCanisterError> {
+    if caller == Principal::anonymous() {
+        return Err(UserCanisterError::NotAuthenticated);
+    }
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(UserCanisterError::Suspended);
+    }
+    if !state.user_profiles.contains_key(&caller) {
+        return Err(UserCanisterError::NotFound);
+    }
+    if state.user_canisters.contains_key(&caller) {
+        return Err(UserCanisterError::AlreadyProvisioned);
+    }
+    if !state.dedicated_storage_allowed.contains(&caller) {
+        return Err(UserCanisterError::NotAllowed);
+    }
+    if state.user_canisters.len() + in_flight >= MAX_USER_CANISTERS {
+        return Err(UserCanisterError::LimitReached);
+    }
+    Ok(())
+}
+
+fn record_user_canister(caller: Principal, canister_id: Principal, state: &mut State) {
+    state.user_canisters.insert(caller, canister_id);
+    if let Some(profile) = state.user_profiles.get_mut(&caller) {
+        profile.storage_canister = Some(canister_id);
+    }
+}
+
+/// Creates a storage canister used only by the caller. Files uploaded afterwards are stored there.
+pub async fn provision_user_canister(caller: Principal) -> Result<Principal, UserCanisterError> {
+    let in_flight = PROVISIONING.with(|p| p.borrow().len() as u64);
+    with_state(|s| check_can_provision(caller, in_flight, s))?;
+    if PROVISIONING.with(|p| !p.borrow_mut().insert(caller)) {
+        return Err(UserCanisterError::AlreadyProvisioned);
+    }
+
+    let result = create_and_install_bucket(Some(caller)).await;
+    PROVISIONING.with(|p| p.borrow_mut().remove(&caller));
+
+    let canister_id = result.map_err(UserCanisterError::CallFailed)?;
+    with_state_mut(|s| record_user_canister(caller, canister_id, s));
+    Ok(canister_id)
+}
+
+/// Creates the caller's profile and, when they asked for it, their dedicated canister.
+///
+/// The profile is kept if provisioning fails; the user can retry with `provision_user_canister`.
+pub async fn create_user_profile_with_storage(caller: Principal, request: CreateUserRequest) -> UserResponse {
+    let dedicated = request.dedicated_storage.unwrap_or(false);
+    let response = with_state_mut(|s| crate::api::create_user_profile(caller, request, s));
+    if !dedicated || !matches!(response, UserResponse::Ok(_)) {
+        return response;
+    }
+
+    if let Err(err) = provision_user_canister(caller).await {
+        ic_cdk::println!("provisioning a canister for {caller} failed: {err:?}");
+    }
+    with_state(|s| crate::api::get_user_profile(caller, s))
+}
+
+/// Forgets `user`'s dedicated canister, with any file still stored in it, and queues the
+/// canister for `delete_removed_canisters`. Called once their account is erased. Returns
+/// how many files were deleted.
+pub(crate) fn remove_user_canister(state: &mut State, user: Principal) -> u64 {
+    state.dedicated_storage_allowed.remove(&user);
+    let Some(canister_id) = state.user_canisters.remove(&user) else {
+        return 0;
+    };
+    // The bucket goes first: deleting the canister frees its chunks, so the files
+    // below don't send a delete for each of them.
+    let files: Vec<u64> = unregister_bucket(state, canister_id)
+        .map(|bucket| bucket.reservations.into_keys().collect())
+        .unwrap_or_default();
+    for &file_id in &files {
+        crate::api::delete_file::purge_file(state, file_id);
+    }
+    state.deleting.retain(|_, deleting| deleting.bucket != Some(canister_id));
+    state.removed_canisters.push(canister_id);
+    files.len() as u64
+}
+
+/// Stops and deletes the dedicated canisters `remove_user_canister` queued.
+pub async fn delete_removed_canisters() {
+    let canisters = with_state_mut(|s| std::mem::take(&mut s.removed_canisters));
+    for canister_id in canisters {
+        delete_user_canister(canister_id).await;
+    }
+}
+
+async fn delete_user_canister(canister_id: Principal) {
+    if let Err((code, msg)) = stop_canister(CanisterIdRecord { canister_id }).await {
+        ic_cdk::println!("stopping canister {canister_id} failed: {code:?}: {msg}");
+        return;
+    }
+    if let Err((code, msg)) = delete_canister(CanisterIdRecord { canister_id }).await {
+        ic_cdk::println!("deleting canister {canister_id} failed: {code:?}: {msg}");
+    }
+}
+
+fn caller_canister(caller: Principal) -> Result<Principal, UserCanisterError> {
+    if caller == Principal::anonymous() {
+        return Err(UserCanisterError::NotAuthenticated);
+    }
+    with_state(|s| dedicated_canister(s, caller)).ok_or(UserCanisterError::NoDedicatedCanister)
+}
+
+/// Forwards the cycles attached to the call to the caller's canister. Returns the amount deposited.
+///
+/// The cycles are only accepted once the deposit went through; otherwise they go back to the caller.
+pub async fn top_up_user_canister(caller: Principal) -> Result<u128, UserCanisterError> {
+    let canister_id = caller_canister(caller)?;
+    let cycles = msg_cycles_available128();
+    if cycles == 0 {
+        return Ok(0);
+    }
+    deposit_cycles(CanisterIdRecord { canister_id }, cycles)
+        .await
+        .map_err(|(code, msg)| UserCanisterError::CallFailed(format!("deposit_cycles failed: {code:?}: {msg}")))?;
+    Ok(msg_cycles_accept128(cycles))
+}
+
+/// Makes the caller a controller of their canister, next to the index, so they can
+/// snapshot it, download its data or take it over entirely.
+pub async fn export_user_canister(caller: Principal) -> Result<Principal, UserCanisterError> {
+    let canister_id = caller_canister(caller)?;
+    update_settings(UpdateSettingsArgument {
+        canister_id,
+        settings: CanisterSettings {
+            controllers: Some(vec![ic_cdk::id(), caller]),
+            ..Default::default()
+        },
+    })
+    .await
+    .map_err(|(code, msg)| UserCanisterError::CallFailed(format!("update_settings failed: {code:?}: {msg}")))?;
+    Ok(canister_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::buckets::pick_bucket;
+
+    fn state_with_user(user: Principal) -> State {
+        let mut state = State::default();
+        let request = CreateUserRequest {
+            username: "tenant".to_string(),
+            display_name: None,
+            email: None,
+            dedicated_storage: Some(true),
+        };
+        crate::api::create_user_profile(user, request, &mut state);
+        state
+    }
+
+    #[test]
+    fn provisioning_requires_a_profile_without_canister() {
+        let user = Principal::from_slice(&[2]);
+        let mut state = state_with_user(user);
+        state.dedicated_storage_allowed.insert(user);
+
+        assert_eq!(
+            check_can_provision(Principal::anonymous(), 0, &state),
+            Err(UserCanisterError::NotAuthenticated)
+        );
+        assert_eq!(
+            check_can_provision(Principal::from_slice(&[9]), 0, &state),
+            Err(UserCanisterError::NotFound)
+        );
+        assert_eq!(check_can_provision(user, 0, &state), Ok(()));
+
+        record_user_canister(user, Principal::from_slice(&[1]), &mut state);
+        assert_eq!(check_can_provision(user, 0, &state), Err(UserCanisterError::AlreadyProvisioned));
+        assert_eq!(
+            state.user_profiles[&user].storage_canister,
+            Some(Principal::from_slice(&[1]))
+        );
+    }
+
+    #[test]
+    fn provisioning_needs_an_admin_and_a_free_slot() {
+        let admin = Principal::from_slice(&[7]);
+        let user = Principal::from_slice(&[2]);
+        let mut state = state_with_user(user);
+        state.admins.insert(admin, ());
+        assert_eq!(check_can_provision(user, 0, &state), Err(UserCanisterError::NotAllowed));
+
+        assert_eq!(allow_dedicated_storage(user, false, user, true, &mut state), Err(AdminError::NotAuthorized));
+        allow_dedicated_storage(admin, false, user, true, &mut state).unwrap();
+        assert_eq!(check_can_provision(user, 0, &state), Ok(()));
+        assert_eq!(
+            check_can_provision(user, MAX_USER_CANISTERS, &state),
+            Err(UserCanisterError::LimitReached)
+        );
+
+        crate::api::suspend_user(admin, false, user, "abuse report".to_string(), &mut state).unwrap();
+        assert_eq!(check_can_provision(user, 0, &state), Err(UserCanisterError::Suspended));
+    }
+
+    #[test]
+    fn dedicated_canister_only_receives_its_users_files() {
+        let user = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[9]);
+        let dedicated = Principal::from_slice(&[1]);
+        let mut state = state_with_user(user);
+        record_user_canister(user, dedicated, &mut state);
+        restore_user_canisters(&mut state);
+
+        assert_eq!(state.buckets[&dedicated].dedicated_to, Some(user));
+        assert_eq!(pick_bucket(&state, user, 10), Some(dedicated));
+        assert_eq!(pick_bucket(&state, other, 10), None);
+    }
+
+    #[test]
+    fn erased_users_lose_their_canister_and_its_files() {
+        let user = Principal::from_slice(&[2]);
+        let dedicated = Principal::from_slice(&[1]);
+        let mut state = state_with_user(user);
+        state.dedicated_storage_allowed.insert(user);
+        record_user_canister(user, dedicated, &mut state);
+        restore_user_canisters(&mut state);
+        state.file_data.insert(
+            0,
+            crate::File {
+                metadata: crate::FileMetadata {
+                    file_name: "backup.tar".to_string(),
+                    requester_principal: user,
+                    requested_at: 12345,
+                    uploaded_at: None,
+                    storage_provider: "icp".to_string(),
+                    blob_id: None,
+                    bucket: Some(dedicated),
+                    description: String::new(),
+                    tags: vec![],
+                },
+                content: crate::FileContent::PartiallyUploaded {
+                    num_chunks: 2,
+                    file_type: "application/x-tar".to_string(),
+                    owner_key: vec![],
+                },
+            },
+        );
+        state.file_owners.insert(user, vec![0]);
+        state.buckets.get_mut(&dedicated).unwrap().reservations.insert(0, 10);
+
+        assert_eq!(remove_user_canister(&mut state, user), 1);
+        assert_eq!(dedicated_canister(&state, user), None);
+        assert!(state.buckets.is_empty());
+        assert_eq!(state.removed_canisters, vec![dedicated]);
+        assert!(state.file_data.is_empty());
+        assert!(state.deleting.is_empty());
+        assert!(!state.dedicated_storage_allowed.contains(&user));
+        assert_eq!(remove_user_canister(&mut state, user), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{State, UserProfile, CreateUserRequest, UpdateUserRequest, UserResponse, UserListResponse};
+use crate::api::DeleteAccountMode;
 use candid::Principal;
 
 pub fn create_user_profile(
@@ -10,6 +11,9 @@ pub fn create_user_profile(
     if caller == Principal::anonymous() {
         return UserResponse::NotAuthenticated;
     }
+    if crate::api::admin::is_suspended(state, caller) {
+        return UserResponse::Suspended;
+    }
 
     // Validate input
     if request.username.trim().is_empty() || request.username.len() > 50 {
@@ -39,6 +43,9 @@ pub fn create_user_profile(
         storage_used: 0,
         file_count: 0,
         is_active: true,
+        storage_canister: None,
+        suspended_at: None,
+        suspension_reason: None,
     };
 
     // Store user profile
@@ -113,7 +120,13 @@ pub fn update_user_profile(
     UserResponse::Ok(profile)
 }
 
-pub fn delete_user_profile(caller: Principal, state: &mut State) -> UserResponse {
+/// Deletes the caller's profile. With `EraseEverything` their files and shares are
+/// erased too, by a background job; see `crate::api::account_deletion`.
+pub fn delete_user_profile(
+    caller: Principal,
+    mode: Option<DeleteAccountMode>,
+    state: &mut State,
+) -> UserResponse {
     // Check if caller is authenticated (not anonymous)
     if caller == Principal::anonymous() {
         return UserResponse::NotAuthenticated;
@@ -132,17 +145,25 @@ pub fn delete_user_profile(caller: Principal, state: &mut State) -> UserResponse
     state.user_profiles.remove(&caller);
     state.user_count -= 1;
 
-    // Note: We don't delete user's files here - they should be handled separately
-    // to avoid data loss. Files can be cleaned up in a separate operation.
+    // Files are kept unless the user asks for everything to be erased.
+    crate::api::account_deletion::start_account_deletion(
+        state,
+        caller,
+        mode.unwrap_or(DeleteAccountMode::KeepFiles),
+    );
 
     UserResponse::Ok(profile)
 }
 
-pub fn list_users(caller: Principal, state: &State) -> UserListResponse {
+pub fn list_users(caller: Principal, is_controller: bool, state: &State) -> UserListResponse {
     // Check if caller is authenticated (not anonymous)
     if caller == Principal::anonymous() {
         return UserListResponse::NotAuthenticated;
     }
+    // Only admins see every user's profile.
+    if crate::api::require_admin(caller, is_controller, state).is_err() {
+        return UserListResponse::NotAuthorized;
+    }
 
     let users: Vec<UserProfile> = state.user_profiles.values().cloned().collect();
     UserListResponse::Ok(users)
@@ -167,14 +188,10 @@ pub fn get_user_stats(caller: Principal, state: &State) -> UserResponse {
     for &file_id in owned_files {
         if let Some(file) = state.file_data.get(&file_id) {
             match &file.content {
-                crate::FileContent::Uploaded { num_chunks, .. } |
-                crate::FileContent::PartiallyUploaded { num_chunks, .. } => {
+                crate::FileContent::Uploaded { .. } |
+                crate::FileContent::PartiallyUploaded { .. } => {
                     // Calculate storage used by this file
-                    for chunk_id in 0..*num_chunks {
-                        if let Some(chunk_data) = state.file_contents.get(&(file_id, chunk_id)) {
-                            total_storage += chunk_data.len() as u64;
-                        }
-                    }
+                    total_storage += state.chunks_for(file_id).size(state.storage_id(file_id));
                 }
                 crate::FileContent::Pending { .. } => {
                     // Pending files don't use storage yet
@@ -183,6 +200,9 @@ pub fn get_user_stats(caller: Principal, state: &State) -> UserResponse {
         }
     }
 
+    // Files in the trash keep their chunks until purged
+    total_storage += crate::api::trash::trashed_bytes(state, caller);
+
     // Update profile with current stats
     profile.storage_used = total_storage;
     profile.file_count = owned_files.len() as u64;
@@ -196,19 +216,20 @@ mod tests {
     use super::*;
     use crate::State;
 
-    fn create_test_principal(id: &str) -> Principal {
-        Principal::from_text(id).unwrap()
+    fn create_test_principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
     }
 
     #[test]
     fn test_create_user_profile() {
         let mut state = State::default();
-        let principal = create_test_principal("2vxsx-fae");
+        let principal = create_test_principal(1);
         
         let request = CreateUserRequest {
             username: "testuser".to_string(),
             display_name: Some("Test User".to_string()),
             email: Some("test@example.com".to_string()),
+            dedicated_storage: None,
         };
 
         let result = create_user_profile(principal, request, &mut state);
@@ -230,6 +251,7 @@ mod tests {
             username: "testuser".to_string(),
             display_name: None,
             email: None,
+            dedicated_storage: None,
         };
 
         let result = create_user_profile(Principal::anonymous(), request, &mut state);
@@ -239,13 +261,14 @@ mod tests {
     #[test]
     fn test_duplicate_username_rejected() {
         let mut state = State::default();
-        let principal1 = create_test_principal("2vxsx-fae");
-        let principal2 = create_test_principal("3vxsx-fae");
+        let principal1 = create_test_principal(1);
+        let principal2 = create_test_principal(2);
         
         let request = CreateUserRequest {
             username: "testuser".to_string(),
             display_name: None,
             email: None,
+            dedicated_storage: None,
         };
 
         // Create first user
@@ -260,13 +283,14 @@ mod tests {
     #[test]
     fn test_update_user_profile() {
         let mut state = State::default();
-        let principal = create_test_principal("2vxsx-fae");
+        let principal = create_test_principal(1);
         
         // Create user first
         let create_request = CreateUserRequest {
             username: "testuser".to_string(),
             display_name: Some("Test User".to_string()),
             email: None,
+            dedicated_storage: None,
         };
         create_user_profile(principal, create_request, &mut state);
 
@@ -0,0 +1,462 @@
+use crate::api::upload_session::{BeginUploadRequest, UploadSession, MAX_UPLOAD_SIZE, UPLOAD_CHUNK_SIZE};
+use crate::{File, FileContent, FileData, FileMetadata, State};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// A file's ID always refers to its current version, so download links,
+// transfers and folders keep working when a new version is uploaded. Previous
+// versions keep their chunks under an internal storage ID of their own, which
+// is how `(file_id, version_id, chunk_id)` is resolved.
+//
+// A new version is uploaded like any session upload, into a hidden staging file
+// (`begin_version_upload`, then `upload_chunks` and `finalize_upload`). When it
+// is finalized no chunk is copied: the archive takes over the storage ID of the
+// current chunks, the file's chunks are looked up under the staging ID from then
+// on (`State::storage_id`), and only the certified hashes move to the file's ID.
+// Versioning is only available for files stored in this canister.
+
+/// How many previous versions a file keeps unless its owner sets another limit.
+pub const DEFAULT_MAX_OLD_VERSIONS: u32 = 10;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileVersion {
+    pub version_id: u64,
+    pub file_name: String,
+    pub file_type: String,
+    pub num_chunks: u64,
+    pub size: u64,
+    pub uploaded_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct ArchivedVersion {
+    version: FileVersion,
+    // Where the chunks of this version are kept.
+    storage_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileVersions {
+    current_version: u64,
+    // Oldest first.
+    archived: Vec<ArchivedVersion>,
+    max_old_versions: u32,
+}
+
+impl Default for FileVersions {
+    fn default() -> Self {
+        Self {
+            current_version: 0,
+            archived: vec![],
+            max_old_versions: DEFAULT_MAX_OLD_VERSIONS,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum VersionError {
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "not_uploaded")]
+    NotUploaded,
+    #[serde(rename = "stored_in_bucket")]
+    StoredInBucket,
+    #[serde(rename = "invalid_size")]
+    InvalidSize,
+    #[serde(rename = "version_not_found")]
+    VersionNotFound,
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+fn check_owner(caller: Principal, file_id: u64, state: &State) -> Result<(), VersionError> {
+    if caller == Principal::anonymous() {
+        return Err(VersionError::NotAuthenticated);
+    }
+    if !state.is_file_owner(caller, file_id) || !state.file_data.contains_key(&file_id) {
+        return Err(VersionError::NotFound);
+    }
+    Ok(())
+}
+
+/// True for the hidden files new versions are uploaded into.
+pub(crate) fn is_staged_version(state: &State, file_id: u64) -> bool {
+    state.version_uploads.contains_key(&file_id)
+}
+
+/// Starts uploading a new version of one of the caller's files. The returned
+/// session's `file_id` is the staging file to send chunks to and finalize.
+pub fn begin_version_upload(
+    caller: Principal,
+    file_id: u64,
+    request: BeginUploadRequest,
+    state: &mut State,
+) -> Result<UploadSession, VersionError> {
+    check_owner(caller, file_id, state)?;
+    if crate::api::admin::is_suspended(state, caller) {
+        return Err(VersionError::Suspended);
+    }
+    let file = &state.file_data[&file_id];
+    if file.metadata.bucket.is_some() {
+        return Err(VersionError::StoredInBucket);
+    }
+    if !matches!(file.content, FileContent::Uploaded { .. }) {
+        return Err(VersionError::NotUploaded);
+    }
+    if request.total_size == 0 || request.total_size > MAX_UPLOAD_SIZE {
+        return Err(VersionError::InvalidSize);
+    }
+    // Only one new version can be in flight per file; starting over drops the previous attempt.
+    discard_staged_versions(state, file_id);
+
+    let staging_id = state.generate_file_id();
+    let num_chunks = request.total_size.div_ceil(UPLOAD_CHUNK_SIZE);
+    state.file_data.insert(
+        staging_id,
+        File {
+            metadata: FileMetadata {
+                file_name: request.name,
+                requester_principal: caller,
+                requested_at: crate::get_time(),
+                uploaded_at: None,
+                storage_provider: "icp".to_string(),
+                blob_id: None,
+                bucket: None,
+                description: String::new(),
+                tags: vec![],
+            },
+            content: FileContent::PartiallyUploaded {
+                num_chunks,
+                file_type: request.file_type,
+                owner_key: vec![],
+            },
+        },
+    );
+    let session = UploadSession {
+        file_id: staging_id,
+        total_size: request.total_size,
+        chunk_size: UPLOAD_CHUNK_SIZE,
+        num_chunks,
+    };
+    state.upload_sessions.insert(staging_id, session.clone());
+    state.version_uploads.insert(staging_id, file_id);
+    Ok(session)
+}
+
+fn delete_chunks(state: &mut State, storage_id: u64, num_chunks: u64) {
+    crate::api::delete_file::reclaim_chunks(state, storage_id, None, num_chunks);
+}
+
+/// Replaces a file's contents with a finalized staging upload, archiving the
+/// current version. Called by `finalize_upload`.
+pub(crate) fn commit_version(state: &mut State, staging_id: u64) {
+    let Some(file_id) = state.version_uploads.remove(&staging_id) else {
+        return;
+    };
+    let Some(staged) = state.file_data.remove(&staging_id) else {
+        return;
+    };
+    let FileContent::Uploaded { num_chunks, file_type, owner_key } = staged.content else {
+        return;
+    };
+    // A trashed file keeps the contents it was deleted with.
+    let file = match state.file_data.get(&file_id) {
+        Some(file) if !crate::api::trash::is_trashed(state, file_id) => file,
+        _ => {
+            delete_chunks(state, staging_id, num_chunks);
+            return;
+        }
+    };
+    let (old_chunks, old_type) = match &file.content {
+        FileContent::Uploaded { num_chunks, file_type, .. } => (*num_chunks, file_type.clone()),
+        _ => (0, String::new()),
+    };
+    let old_name = file.metadata.file_name.clone();
+    let old_uploaded_at = file.metadata.uploaded_at.unwrap_or(file.metadata.requested_at);
+
+    let archive_id = state.storage_id(file_id);
+    let old_size = state.file_contents.size(archive_id);
+    state.file_storage.insert(file_id, staging_id);
+    for chunk_id in 0..num_chunks.max(old_chunks) {
+        state.certified.move_chunk(staging_id, file_id, chunk_id);
+    }
+
+    let versions = state.file_versions.entry(file_id).or_default();
+    versions.archived.push(ArchivedVersion {
+        version: FileVersion {
+            version_id: versions.current_version,
+            file_name: old_name,
+            file_type: old_type,
+            num_chunks: old_chunks,
+            size: old_size,
+            uploaded_at: old_uploaded_at,
+        },
+        storage_id: archive_id,
+    });
+    versions.current_version += 1;
+
+    let file = state.file_data.get_mut(&file_id).expect("file exists");
+    file.metadata.file_name = staged.metadata.file_name;
+    file.metadata.uploaded_at = Some(crate::get_time());
+    file.content = FileContent::Uploaded {
+        num_chunks,
+        file_type,
+        owner_key,
+    };
+
+    apply_retention(state, file_id);
+    // The size changed, so index from scratch.
+    crate::api::file_index::unindex_file(state, file_id);
+    crate::api::file_index::index_file(state, file_id);
+    crate::api::http_gateway::recertify_download_links(state, file_id);
+    crate::certification::certify_file(state, file_id);
+}
+
+// Drops the oldest versions beyond the file's limit.
+fn apply_retention(state: &mut State, file_id: u64) {
+    let Some(versions) = state.file_versions.get_mut(&file_id) else {
+        return;
+    };
+    let excess = versions.archived.len().saturating_sub(versions.max_old_versions as usize);
+    let dropped: Vec<ArchivedVersion> = versions.archived.drain(..excess).collect();
+    for archived in dropped {
+        delete_chunks(state, archived.storage_id, archived.version.num_chunks);
+    }
+    state.certified.commit();
+}
+
+fn discard_staged_version(state: &mut State, staging_id: u64) {
+    state.version_uploads.remove(&staging_id);
+    state.upload_sessions.remove(&staging_id);
+    if let Some(staged) = state.file_data.remove(&staging_id) {
+        if let FileContent::PartiallyUploaded { num_chunks, .. } | FileContent::Uploaded { num_chunks, .. } =
+            staged.content
+        {
+            delete_chunks(state, staging_id, num_chunks);
+        }
+    }
+}
+
+/// Deletes the previous versions of a file and any new version being uploaded.
+pub(crate) fn purge_versions(state: &mut State, file_id: u64) {
+    if let Some(versions) = state.file_versions.remove(&file_id) {
+        for archived in versions.archived {
+            delete_chunks(state, archived.storage_id, archived.version.num_chunks);
+        }
+    }
+    discard_staged_versions(state, file_id);
+}
+
+/// Drops any new version of a file that is still being uploaded.
+pub(crate) fn discard_staged_versions(state: &mut State, file_id: u64) {
+    let staged: Vec<u64> = state
+        .version_uploads
+        .iter()
+        .filter(|(_, &target)| target == file_id)
+        .map(|(&staging_id, _)| staging_id)
+        .collect();
+    for staging_id in staged {
+        discard_staged_version(state, staging_id);
+    }
+}
+
+/// Every version of a file, newest (the current one) first.
+pub fn list_versions(caller: Principal, file_id: u64, state: &State) -> Result<Vec<FileVersion>, VersionError> {
+    check_owner(caller, file_id, state)?;
+    let file = &state.file_data[&file_id];
+    let (num_chunks, file_type) = match &file.content {
+        FileContent::Uploaded { num_chunks, file_type, .. } => (*num_chunks, file_type.clone()),
+        _ => return Err(VersionError::NotUploaded),
+    };
+    let versions = state.file_versions.get(&file_id);
+    let mut list = vec![FileVersion {
+        version_id: versions.map_or(0, |v| v.current_version),
+        file_name: file.metadata.file_name.clone(),
+        file_type,
+        num_chunks,
+        size: state.chunks_for(file_id).size(state.storage_id(file_id)),
+        uploaded_at: file.metadata.uploaded_at.unwrap_or(file.metadata.requested_at),
+    }];
+    if let Some(versions) = versions {
+        list.extend(versions.archived.iter().rev().map(|archived| archived.version.clone()));
+    }
+    Ok(list)
+}
+
+/// Downloads a chunk of a given version of one of the caller's files.
+pub fn download_file_version(
+    caller: Principal,
+    file_id: u64,
+    version_id: u64,
+    chunk_id: u64,
+    state: &State,
+) -> Result<FileData, VersionError> {
+    check_owner(caller, file_id, state)?;
+    if crate::api::admin::downloads_blocked(state, caller) {
+        return Err(VersionError::Suspended);
+    }
+    let versions = state.file_versions.get(&file_id);
+    if versions.map_or(0, |v| v.current_version) == version_id {
+        return match crate::api::download_file::file_chunk(state, file_id, chunk_id) {
+            crate::FileDownloadResponse::FoundFile(data) => Ok(data),
+            crate::FileDownloadResponse::NotUploadedFile => Err(VersionError::NotUploaded),
+            crate::FileDownloadResponse::StoredInBucket(_) => Err(VersionError::StoredInBucket),
+            _ => Err(VersionError::NotFound),
+        };
+    }
+    let archived = versions
+        .and_then(|v| v.archived.iter().find(|a| a.version.version_id == version_id))
+        .ok_or(VersionError::VersionNotFound)?;
+    let contents = state
+        .file_contents
+        .get(archived.storage_id, chunk_id)
+        .ok_or(VersionError::NotFound)?;
+    Ok(FileData {
+        contents,
+        file_type: archived.version.file_type.clone(),
+        num_chunks: archived.version.num_chunks,
+    })
+}
+
+/// Sets how many previous versions of a file are kept, dropping the oldest ones beyond it.
+pub fn set_version_retention(
+    caller: Principal,
+    file_id: u64,
+    max_old_versions: u32,
+    state: &mut State,
+) -> Result<(), VersionError> {
+    check_owner(caller, file_id, state)?;
+    state.file_versions.entry(file_id).or_default().max_old_versions = max_old_versions;
+    apply_retention(state, file_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{
+        download_file, finalize_upload, upload_chunks, upload_file_atomic, FinalizeUploadRequest,
+        UploadFileAtomicRequest,
+    };
+    use crate::certification::sha256;
+    use crate::FileDownloadResponse;
+
+    fn upload(state: &mut State, owner: Principal, content: Vec<u8>) -> u64 {
+        upload_file_atomic(
+            owner,
+            UploadFileAtomicRequest {
+                name: "contract.pdf".to_string(),
+                content,
+                file_type: "application/pdf".to_string(),
+                num_chunks: 1,
+            },
+            state,
+        ).unwrap()
+    }
+
+    // Returns the ID of the staging file the version was uploaded to.
+    fn new_version(state: &mut State, owner: Principal, file_id: u64, content: Vec<u8>) -> u64 {
+        let request = BeginUploadRequest {
+            name: "contract-v2.pdf".to_string(),
+            file_type: "application/pdf".to_string(),
+            total_size: content.len() as u64,
+        };
+        let session = begin_version_upload(owner, file_id, request, state).unwrap();
+        let sha256 = sha256(&sha256(&content)).to_vec();
+        upload_chunks(owner, session.file_id, vec![(0, content)], state).unwrap();
+        finalize_upload(owner, FinalizeUploadRequest { file_id: session.file_id, sha256 }, state).unwrap();
+        session.file_id
+    }
+
+    #[test]
+    fn new_versions_keep_the_file_id() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, vec![1, 1]);
+        new_version(&mut state, owner, file_id, vec![2, 2, 2]);
+
+        match download_file(&state, owner, file_id, 0) {
+            FileDownloadResponse::FoundFile(data) => assert_eq!(data.contents, vec![2, 2, 2]),
+            other => panic!("unexpected response: {other:?}"),
+        }
+        assert_eq!(crate::api::list_files(owner, &state).len(), 1);
+
+        let versions = list_versions(owner, file_id, &state).unwrap();
+        assert_eq!(versions.iter().map(|v| v.version_id).collect::<Vec<_>>(), vec![1, 0]);
+        assert_eq!(versions[0].file_name, "contract-v2.pdf");
+        assert_eq!(versions[1].size, 2);
+        assert_eq!(download_file_version(owner, file_id, 0, 0, &state).unwrap().contents, vec![1, 1]);
+        assert_eq!(download_file_version(owner, file_id, 1, 0, &state).unwrap().contents, vec![2, 2, 2]);
+        assert_eq!(
+            download_file_version(Principal::from_slice(&[9]), file_id, 0, 0, &state),
+            Err(VersionError::NotFound)
+        );
+    }
+
+    #[test]
+    fn committing_a_version_swaps_storage_ids() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, vec![1, 1]);
+        let staging_id = new_version(&mut state, owner, file_id, vec![2, 2, 2]);
+
+        // Neither version was copied: the old one stays under the file's ID.
+        assert_eq!(state.storage_id(file_id), staging_id);
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![1, 1]));
+        assert_eq!(state.file_contents.get(staging_id, 0), Some(vec![2, 2, 2]));
+        // Clients still verify the current chunks under the file's ID.
+        assert_eq!(state.certified.chunk_hash(file_id, 0), Some(sha256(&[2, 2, 2])));
+        assert_eq!(state.certified.chunk_hash(staging_id, 0), None);
+    }
+
+    #[test]
+    fn trashing_a_file_drops_its_staged_version() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, vec![1]);
+        let request = BeginUploadRequest {
+            name: "contract-v2.pdf".to_string(),
+            file_type: "application/pdf".to_string(),
+            total_size: 1,
+        };
+        let session = begin_version_upload(owner, file_id, request, &mut state).unwrap();
+        upload_chunks(owner, session.file_id, vec![(0, vec![2])], &mut state).unwrap();
+
+        crate::api::delete_file(&mut state, owner, file_id);
+        let sha256 = sha256(&sha256(&[2])).to_vec();
+        assert!(finalize_upload(owner, FinalizeUploadRequest { file_id: session.file_id, sha256 }, &mut state).is_err());
+        assert!(state.version_uploads.is_empty());
+        assert!(!state.file_data.contains_key(&session.file_id));
+        assert_eq!(state.file_contents.chunk_count(session.file_id), 0);
+
+        crate::api::restore_file(owner, file_id, &mut state).unwrap();
+        assert_eq!(list_versions(owner, file_id, &state).unwrap().len(), 1);
+        assert_eq!(state.file_contents.get(file_id, 0), Some(vec![1]));
+    }
+
+    #[test]
+    fn retention_drops_the_oldest_versions() {
+        let mut state = State::default();
+        let owner = Principal::from_slice(&[1]);
+        let file_id = upload(&mut state, owner, vec![0]);
+        set_version_retention(owner, file_id, 1, &mut state).unwrap();
+        new_version(&mut state, owner, file_id, vec![1]);
+        new_version(&mut state, owner, file_id, vec![2]);
+
+        let versions = list_versions(owner, file_id, &state).unwrap();
+        assert_eq!(versions.iter().map(|v| v.version_id).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(download_file_version(owner, file_id, 0, 0, &state), Err(VersionError::VersionNotFound));
+        // The dropped version was stored under the file's ID, which certifies the current one.
+        assert_eq!(state.file_contents.chunk_count(file_id), 0);
+        assert_eq!(state.certified.chunk_hash(file_id, 0), Some(sha256(&[2])));
+
+        crate::api::delete_file::purge_file(&mut state, file_id);
+        assert!(state.file_versions.is_empty());
+        assert!(state.file_storage.is_empty());
+        assert_eq!(state.certified.chunk_hash(file_id, 0), None);
+        // The file and the two staging uploads its versions were stored under.
+        assert!((0..3).all(|id| state.file_contents.chunk_count(id) == 0));
+    }
+}
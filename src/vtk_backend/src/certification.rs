@@ -0,0 +1,280 @@
+use crate::{ChunkId, FileId, PublicFileMetadata, State};
+use candid::Principal;
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree};
+use sha2::{Digest, Sha256};
+
+/// The data certified by this canister, so query responses can be verified without an update call.
+///
+/// The root hash is set as the canister's certified data after every change. It covers:
+///   - `chunks/<file_id><chunk_id>`: SHA-256 of each stored chunk (both IDs big-endian).
+///   - `files/<owner>/<file_id>`: SHA-256 of the candid-encoded `PublicFileMetadata`.
+///   - `http_assets/<path>`: SHA-256 of the full body served at a download link, as the HTTP
+///     gateway expects.
+///   - `http_expr/f/<link>/<$>/<expr_hash>//<response_hash>`: the range responses of a download
+///     link, one per chunk, in the layout of the gateway's v2 response certification.
+pub struct CertifiedTree {
+    chunks: RbTree<Vec<u8>, Hash>,
+    files: RbTree<Vec<u8>, RbTree<Vec<u8>, Hash>>,
+    http_assets: RbTree<Vec<u8>, Hash>,
+    http_expr: RbTree<Vec<u8>, ResponsesByPath>,
+}
+
+// `<$>` -> expression hash -> request hash (empty, the request isn't certified) -> response
+// hash -> empty leaf.
+type ResponsesByPath = RbTree<Vec<u8>, RbTree<Vec<u8>, RbTree<Vec<u8>, RbTree<Vec<u8>, Vec<u8>>>>>;
+
+// The label under which the certified paths of download links are nested in `http_expr`.
+const EXPR_LINK_SEGMENT: &str = "f";
+// Marks the end of a certified path, for an exact match.
+const EXPR_PATH_END: &str = "<$>";
+
+impl Default for CertifiedTree {
+    fn default() -> Self {
+        Self {
+            chunks: RbTree::new(),
+            files: RbTree::new(),
+            http_assets: RbTree::new(),
+            http_expr: RbTree::new(),
+        }
+    }
+}
+
+fn chunk_key(file_id: FileId, chunk_id: ChunkId) -> Vec<u8> {
+    [file_id.to_be_bytes(), chunk_id.to_be_bytes()].concat()
+}
+
+pub fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+impl CertifiedTree {
+    pub fn root_hash(&self) -> Hash {
+        fork_hash(
+            &fork_hash(
+                &labeled_hash(b"chunks", &self.chunks.root_hash()),
+                &labeled_hash(b"files", &self.files.root_hash()),
+            ),
+            &fork_hash(
+                &labeled_hash(b"http_assets", &self.http_assets.root_hash()),
+                &labeled_hash(b"http_expr", &self.http_expr_hash()),
+            ),
+        )
+    }
+
+    fn http_expr_hash(&self) -> Hash {
+        labeled_hash(EXPR_LINK_SEGMENT.as_bytes(), &self.http_expr.root_hash())
+    }
+
+    pub fn certify_chunk(&mut self, file_id: FileId, chunk_id: ChunkId, contents: &[u8]) {
+        self.chunks.insert(chunk_key(file_id, chunk_id), sha256(contents));
+    }
+
+    /// The certified SHA-256 of a chunk.
+    pub fn chunk_hash(&self, file_id: FileId, chunk_id: ChunkId) -> Option<Hash> {
+        self.chunks.get(&chunk_key(file_id, chunk_id)).copied()
+    }
+
+    pub fn remove_chunk(&mut self, file_id: FileId, chunk_id: ChunkId) {
+        self.chunks.delete(&chunk_key(file_id, chunk_id));
+    }
+
+    /// Moves the certified hash of a chunk to another file ID, or removes the one there if
+    /// `from` has none.
+    pub fn move_chunk(&mut self, from: FileId, to: FileId, chunk_id: ChunkId) {
+        let hash = self.chunk_hash(from, chunk_id);
+        self.chunks.delete(&chunk_key(from, chunk_id));
+        match hash {
+            Some(hash) => self.chunks.insert(chunk_key(to, chunk_id), hash),
+            None => self.chunks.delete(&chunk_key(to, chunk_id)),
+        }
+    }
+
+    pub fn certify_file(&mut self, owner: Principal, file_id: FileId, metadata_hash: Hash) {
+        if self.files.get(owner.as_slice()).is_none() {
+            self.files.insert(owner.as_slice().to_vec(), RbTree::new());
+        }
+        self.files
+            .modify(owner.as_slice(), |files| files.insert(file_id.to_be_bytes().to_vec(), metadata_hash));
+    }
+
+    pub fn remove_file(&mut self, owner: Principal, file_id: FileId) {
+        self.files
+            .modify(owner.as_slice(), |files| files.delete(&file_id.to_be_bytes()));
+    }
+
+    pub fn certify_asset(&mut self, path: &str, body_hash: Hash) {
+        self.http_assets.insert(path.as_bytes().to_vec(), body_hash);
+    }
+
+    pub fn remove_asset(&mut self, path: &str) {
+        self.http_assets.delete(path.as_bytes());
+    }
+
+    /// Certifies the range responses of the download link `link`, replacing any certified before.
+    pub fn certify_range_responses(&mut self, link: &str, expr_hash: Hash, response_hashes: &[Hash]) {
+        let mut responses = RbTree::new();
+        for hash in response_hashes {
+            responses.insert(hash.to_vec(), vec![]);
+        }
+        let mut by_request = RbTree::new();
+        by_request.insert(vec![], responses);
+        let mut by_expr = RbTree::new();
+        by_expr.insert(expr_hash.to_vec(), by_request);
+        let mut by_path = RbTree::new();
+        by_path.insert(EXPR_PATH_END.as_bytes().to_vec(), by_expr);
+        self.http_expr.insert(link.as_bytes().to_vec(), by_path);
+    }
+
+    pub fn remove_range_responses(&mut self, link: &str) {
+        self.http_expr.delete(link.as_bytes());
+    }
+
+    fn witness<'a>(
+        &'a self,
+        chunks: Option<HashTree<'a>>,
+        files: Option<HashTree<'a>>,
+        assets: Option<HashTree<'a>>,
+        expr: Option<HashTree<'a>>,
+    ) -> HashTree<'a> {
+        let chunks = chunks.map_or_else(|| HashTree::Pruned(labeled_hash(b"chunks", &self.chunks.root_hash())), |t| labeled(b"chunks", t));
+        let files = files.map_or_else(|| HashTree::Pruned(labeled_hash(b"files", &self.files.root_hash())), |t| labeled(b"files", t));
+        let assets = assets.map_or_else(|| HashTree::Pruned(labeled_hash(b"http_assets", &self.http_assets.root_hash())), |t| labeled(b"http_assets", t));
+        let expr = expr.map_or_else(
+            || HashTree::Pruned(labeled_hash(b"http_expr", &self.http_expr_hash())),
+            |t| labeled(b"http_expr", labeled(EXPR_LINK_SEGMENT.as_bytes(), t)),
+        );
+        fork(fork(chunks, files), fork(assets, expr))
+    }
+
+    /// Witness for the hash of one chunk.
+    pub fn chunk_witness(&self, file_id: FileId, chunk_id: ChunkId) -> HashTree<'_> {
+        self.witness(Some(self.chunks.witness(&chunk_key(file_id, chunk_id))), None, None, None)
+    }
+
+    /// Witness for the metadata hashes of all files of `owner`.
+    pub fn files_witness(&self, owner: Principal) -> HashTree<'_> {
+        let files = self.files.nested_witness(owner.as_slice(), |files| files.as_hash_tree());
+        self.witness(None, Some(files), None, None)
+    }
+
+    /// Witness for the body hash served at `path`.
+    pub fn asset_witness(&self, path: &str) -> HashTree<'_> {
+        self.witness(None, None, Some(self.http_assets.witness(path.as_bytes())), None)
+    }
+
+    /// Witness for one range response of the download link `link`.
+    pub fn range_response_witness(&self, link: &str, expr_hash: Hash, response_hash: Hash) -> HashTree<'_> {
+        let expr = self.http_expr.nested_witness(link.as_bytes(), |by_path| {
+            by_path.nested_witness(EXPR_PATH_END.as_bytes(), |by_expr| {
+                by_expr.nested_witness(&expr_hash, |by_request| {
+                    by_request.nested_witness(&[], |responses| responses.witness(&response_hash))
+                })
+            })
+        });
+        self.witness(None, None, None, Some(expr))
+    }
+
+    /// Sets the root hash as the canister's certified data.
+    pub fn commit(&self) {
+        #[cfg(target_arch = "wasm32")]
+        ic_cdk::api::set_certified_data(&self.root_hash());
+    }
+}
+
+/// CBOR encoding of a witness, with the self-describe tag the IC expects.
+pub fn encode_witness(tree: &HashTree<'_>) -> Vec<u8> {
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(&ciborium::tag::Required::<_, 55799>(tree), &mut bytes)
+        .expect("failed to encode witness");
+    bytes
+}
+
+/// CBOR encoding of the certified path of a download link's range responses, for the
+/// `expr_path` field of a v2 `IC-Certificate` header.
+pub fn encode_expr_path(link: &str) -> Vec<u8> {
+    let path = ["http_expr", EXPR_LINK_SEGMENT, link, EXPR_PATH_END];
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(&ciborium::tag::Required::<_, 55799>(path), &mut bytes)
+        .expect("failed to encode expression path");
+    bytes
+}
+
+fn metadata_hash(metadata: &PublicFileMetadata) -> Hash {
+    sha256(&candid::encode_one(metadata).expect("failed to encode metadata"))
+}
+
+/// Recomputes the certified metadata of a file after it changed.
+pub(crate) fn certify_file(state: &mut State, file_id: FileId) {
+    // New versions being uploaded aren't listed until they replace the file's contents,
+    // and trashed files aren't listed until they are restored.
+    if crate::api::is_staged_version(state, file_id) || crate::api::is_trashed(state, file_id) {
+        return;
+    }
+    if let (Some(file), Some(metadata)) = (state.file_data.get(&file_id), state.public_file_metadata(file_id)) {
+        let owner = file.metadata.requester_principal;
+        let hash = metadata_hash(&metadata);
+        state.certified.certify_file(owner, file_id, hash);
+        state.certified.commit();
+    }
+}
+
+pub(crate) fn certify_chunk(state: &mut State, file_id: FileId, chunk_id: ChunkId, contents: &[u8]) {
+    state.certified.certify_chunk(file_id, chunk_id, contents);
+    state.certified.commit();
+}
+
+/// Removes the certified metadata of a deleted file. Its chunk hashes go as the chunks are freed.
+pub(crate) fn uncertify_file(state: &mut State, owner: Principal, file_id: FileId) {
+    state.certified.remove_file(owner, file_id);
+    state.certified.commit();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn root_hash_changes_with_every_leaf() {
+        let owner = Principal::from_slice(&[1]);
+        let mut tree = CertifiedTree::default();
+        let empty = tree.root_hash();
+
+        tree.certify_chunk(0, 0, b"ciphertext");
+        let with_chunk = tree.root_hash();
+        assert_ne!(with_chunk, empty);
+
+        tree.certify_file(owner, 0, sha256(b"metadata"));
+        let with_file = tree.root_hash();
+        assert_ne!(with_file, with_chunk);
+
+        tree.certify_asset("/f/abc", sha256(b"ciphertext"));
+        assert_ne!(tree.root_hash(), with_file);
+
+        tree.remove_asset("/f/abc");
+        assert_eq!(tree.root_hash(), with_file);
+
+        tree.certify_range_responses("abc", sha256(b"expr"), &[sha256(b"response")]);
+        assert_ne!(tree.root_hash(), with_file);
+
+        tree.remove_range_responses("abc");
+        assert_eq!(tree.root_hash(), with_file);
+    }
+
+    #[test]
+    fn witnesses_reconstruct_the_root_hash() {
+        let owner = Principal::from_slice(&[1]);
+        let mut tree = CertifiedTree::default();
+        tree.certify_chunk(0, 0, b"a");
+        tree.certify_chunk(0, 1, b"b");
+        tree.certify_file(owner, 0, sha256(b"metadata"));
+        tree.certify_asset("/f/abc", sha256(b"ab"));
+        tree.certify_range_responses("abc", sha256(b"expr"), &[sha256(b"a"), sha256(b"b")]);
+
+        let root = tree.root_hash();
+        assert_eq!(tree.chunk_witness(0, 1).reconstruct(), root);
+        assert_eq!(tree.files_witness(owner).reconstruct(), root);
+        assert_eq!(tree.asset_witness("/f/abc").reconstruct(), root);
+        assert_eq!(tree.range_response_witness("abc", sha256(b"expr"), sha256(b"b")).reconstruct(), root);
+        assert!(!encode_witness(&tree.chunk_witness(0, 1)).is_empty());
+    }
+}
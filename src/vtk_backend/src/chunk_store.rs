@@ -0,0 +1,431 @@
+use crate::memory::Memory;
+use crate::{ChunkId, FileId};
+use candid::Principal;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// The chunks stored for files, addressed by `(file_id, chunk_id)`.
+///
+/// Every store can tell which chunks it holds and drop them, including
+/// `RemoteBucketStore`, whose contents live in another canister. Reading and
+/// writing contents takes a `LocalChunkStore`.
+pub trait ChunkStore {
+    fn delete(&mut self, file_id: FileId, chunk_id: ChunkId);
+
+    fn contains(&self, file_id: FileId, chunk_id: ChunkId) -> bool;
+
+    /// Length in bytes of a stored chunk.
+    fn chunk_len(&self, file_id: FileId, chunk_id: ChunkId) -> Option<u64>;
+
+    /// Number of chunks stored for a file.
+    fn chunk_count(&self, file_id: FileId) -> u64;
+
+    /// Total size in bytes of the chunks stored for a file.
+    fn size(&self, file_id: FileId) -> u64;
+
+    /// True if no chunk of any file is stored.
+    fn is_empty(&self) -> bool;
+}
+
+/// A store holding chunk contents in this canister, so they can be read and
+/// written synchronously. The api functions only go through this trait, so a
+/// new storage target only needs a new implementation here.
+pub trait LocalChunkStore: ChunkStore {
+    /// Stores a chunk, replacing any previous contents.
+    fn put(&mut self, file_id: FileId, chunk_id: ChunkId, contents: Vec<u8>);
+
+    fn get(&self, file_id: FileId, chunk_id: ChunkId) -> Option<Vec<u8>>;
+
+    /// The stored chunks of a file whose IDs fall within `chunks`, in order.
+    fn range(&self, file_id: FileId, chunks: Range<ChunkId>) -> Vec<(ChunkId, Vec<u8>)>;
+}
+
+/// Chunks kept in the canister's stable memory. This is the default store.
+pub struct StableChunkStore {
+    chunks: StableBTreeMap<(FileId, ChunkId), Vec<u8>, Memory>,
+}
+
+impl StableChunkStore {
+    pub fn init(memory: Memory) -> Self {
+        Self {
+            chunks: StableBTreeMap::init(memory),
+        }
+    }
+}
+
+impl ChunkStore for StableChunkStore {
+    fn delete(&mut self, file_id: FileId, chunk_id: ChunkId) {
+        self.chunks.remove(&(file_id, chunk_id));
+    }
+
+    fn contains(&self, file_id: FileId, chunk_id: ChunkId) -> bool {
+        self.chunks.contains_key(&(file_id, chunk_id))
+    }
+
+    fn chunk_len(&self, file_id: FileId, chunk_id: ChunkId) -> Option<u64> {
+        self.chunks.get(&(file_id, chunk_id)).map(|contents| contents.len() as u64)
+    }
+
+    fn chunk_count(&self, file_id: FileId) -> u64 {
+        self.chunks
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .count() as u64
+    }
+
+    fn size(&self, file_id: FileId) -> u64 {
+        self.chunks
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .map(|(_, contents)| contents.len() as u64)
+            .sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+impl LocalChunkStore for StableChunkStore {
+    fn put(&mut self, file_id: FileId, chunk_id: ChunkId, contents: Vec<u8>) {
+        self.chunks.insert((file_id, chunk_id), contents);
+    }
+
+    fn get(&self, file_id: FileId, chunk_id: ChunkId) -> Option<Vec<u8>> {
+        self.chunks.get(&(file_id, chunk_id))
+    }
+
+    fn range(&self, file_id: FileId, chunks: Range<ChunkId>) -> Vec<(ChunkId, Vec<u8>)> {
+        self.chunks
+            .range((file_id, chunks.start)..(file_id, chunks.end))
+            .map(|((_, chunk_id), contents)| (chunk_id, contents))
+            .collect()
+    }
+}
+
+pub type ChunkHash = [u8; 32];
+
+/// Chunks kept in stable memory once per distinct contents.
+///
+/// Each `(file_id, chunk_id)` points to the SHA-256 of its contents, and the
+/// contents are stored under that hash with a count of the chunks pointing to
+/// them. Uploading the same ciphertext again only adds a reference, and deleting
+/// a chunk only frees its contents once no other chunk uses them.
+pub struct DedupChunkStore {
+    refs: StableBTreeMap<(FileId, ChunkId), ChunkHash, Memory>,
+    blobs: StableBTreeMap<ChunkHash, Vec<u8>, Memory>,
+    ref_counts: StableBTreeMap<ChunkHash, u64, Memory>,
+}
+
+impl DedupChunkStore {
+    pub fn init(refs: Memory, blobs: Memory, ref_counts: Memory) -> Self {
+        Self {
+            refs: StableBTreeMap::init(refs),
+            blobs: StableBTreeMap::init(blobs),
+            ref_counts: StableBTreeMap::init(ref_counts),
+        }
+    }
+
+    /// Number of distinct chunk contents actually stored.
+    pub fn unique_chunks(&self) -> u64 {
+        self.blobs.len()
+    }
+
+    fn release(&mut self, hash: ChunkHash) {
+        match self.ref_counts.get(&hash) {
+            Some(count) if count > 1 => {
+                self.ref_counts.insert(hash, count - 1);
+            }
+            _ => {
+                self.ref_counts.remove(&hash);
+                self.blobs.remove(&hash);
+            }
+        }
+    }
+}
+
+impl ChunkStore for DedupChunkStore {
+    fn delete(&mut self, file_id: FileId, chunk_id: ChunkId) {
+        if let Some(hash) = self.refs.remove(&(file_id, chunk_id)) {
+            self.release(hash);
+        }
+    }
+
+    fn contains(&self, file_id: FileId, chunk_id: ChunkId) -> bool {
+        self.refs.contains_key(&(file_id, chunk_id))
+    }
+
+    fn chunk_len(&self, file_id: FileId, chunk_id: ChunkId) -> Option<u64> {
+        let hash = self.refs.get(&(file_id, chunk_id))?;
+        self.blobs.get(&hash).map(|contents| contents.len() as u64)
+    }
+
+    fn chunk_count(&self, file_id: FileId) -> u64 {
+        self.refs
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .count() as u64
+    }
+
+    fn size(&self, file_id: FileId) -> u64 {
+        self.refs
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .filter_map(|(_, hash)| self.blobs.get(&hash))
+            .map(|contents| contents.len() as u64)
+            .sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.refs.is_empty()
+    }
+}
+
+impl LocalChunkStore for DedupChunkStore {
+    fn put(&mut self, file_id: FileId, chunk_id: ChunkId, contents: Vec<u8>) {
+        let hash: ChunkHash = Sha256::digest(&contents).into();
+        match self.refs.insert((file_id, chunk_id), hash) {
+            Some(previous) if previous == hash => return,
+            Some(previous) => self.release(previous),
+            None => {}
+        }
+        let count = self.ref_counts.get(&hash).unwrap_or(0);
+        if count == 0 {
+            self.blobs.insert(hash, contents);
+        }
+        self.ref_counts.insert(hash, count + 1);
+    }
+
+    fn get(&self, file_id: FileId, chunk_id: ChunkId) -> Option<Vec<u8>> {
+        let hash = self.refs.get(&(file_id, chunk_id))?;
+        self.blobs.get(&hash)
+    }
+
+    fn range(&self, file_id: FileId, chunks: Range<ChunkId>) -> Vec<(ChunkId, Vec<u8>)> {
+        self.refs
+            .range((file_id, chunks.start)..(file_id, chunks.end))
+            .filter_map(|((_, chunk_id), hash)| self.blobs.get(&hash).map(|contents| (chunk_id, contents)))
+            .collect()
+    }
+}
+
+// Which store holds the local chunks, kept in stable memory so upgrades keep using it.
+const MODE_PLAIN: u8 = 0;
+const MODE_DEDUP: u8 = 1;
+
+fn mode_cell() -> StableCell<u8, Memory> {
+    StableCell::init(crate::memory::get_chunk_store_mode_memory(), MODE_PLAIN)
+        .expect("failed to read the chunk store mode")
+}
+
+/// Whether local chunks are deduplicated with a `DedupChunkStore`.
+pub fn dedup_enabled() -> bool {
+    *mode_cell().get() == MODE_DEDUP
+}
+
+pub(crate) fn set_dedup_enabled(enabled: bool) {
+    let mode = if enabled { MODE_DEDUP } else { MODE_PLAIN };
+    mode_cell()
+        .set(mode)
+        .expect("failed to write the chunk store mode");
+}
+
+/// Chunks kept on the heap. Lost on upgrade, so only meant for tests and tooling.
+#[derive(Default)]
+pub struct HeapChunkStore {
+    chunks: BTreeMap<(FileId, ChunkId), Vec<u8>>,
+}
+
+impl ChunkStore for HeapChunkStore {
+    fn delete(&mut self, file_id: FileId, chunk_id: ChunkId) {
+        self.chunks.remove(&(file_id, chunk_id));
+    }
+
+    fn contains(&self, file_id: FileId, chunk_id: ChunkId) -> bool {
+        self.chunks.contains_key(&(file_id, chunk_id))
+    }
+
+    fn chunk_len(&self, file_id: FileId, chunk_id: ChunkId) -> Option<u64> {
+        self.chunks.get(&(file_id, chunk_id)).map(|contents| contents.len() as u64)
+    }
+
+    fn chunk_count(&self, file_id: FileId) -> u64 {
+        self.chunks
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .count() as u64
+    }
+
+    fn size(&self, file_id: FileId) -> u64 {
+        self.chunks
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .map(|(_, contents)| contents.len() as u64)
+            .sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+impl LocalChunkStore for HeapChunkStore {
+    fn put(&mut self, file_id: FileId, chunk_id: ChunkId, contents: Vec<u8>) {
+        self.chunks.insert((file_id, chunk_id), contents);
+    }
+
+    fn get(&self, file_id: FileId, chunk_id: ChunkId) -> Option<Vec<u8>> {
+        self.chunks.get(&(file_id, chunk_id)).cloned()
+    }
+
+    fn range(&self, file_id: FileId, chunks: Range<ChunkId>) -> Vec<(ChunkId, Vec<u8>)> {
+        self.chunks
+            .range((file_id, chunks.start)..(file_id, chunks.end))
+            .map(|(&(_, chunk_id), contents)| (chunk_id, contents.clone()))
+            .collect()
+    }
+}
+
+/// Chunks kept in a bucket canister exposing `bucket_put_chunk` and friends.
+///
+/// Only the sizes of the chunks the bucket confirmed are tracked here: a canister
+/// can't read or write another canister's data synchronously. Chunks are sent with
+/// `buckets::send_bucket_writes` and recorded once the bucket accepted them, and
+/// callers must check `FileMetadata::bucket` and send clients to `bucket_get_chunk`.
+pub struct RemoteBucketStore {
+    bucket: Principal,
+    sizes: BTreeMap<(FileId, ChunkId), u64>,
+}
+
+impl RemoteBucketStore {
+    pub fn new(bucket: Principal) -> Self {
+        Self {
+            bucket,
+            sizes: BTreeMap::new(),
+        }
+    }
+
+    pub fn bucket(&self) -> Principal {
+        self.bucket
+    }
+
+    /// Records a chunk the bucket confirmed it stored.
+    pub(crate) fn record(&mut self, file_id: FileId, chunk_id: ChunkId, size: u64) {
+        self.sizes.insert((file_id, chunk_id), size);
+    }
+}
+
+impl ChunkStore for RemoteBucketStore {
+    fn delete(&mut self, file_id: FileId, chunk_id: ChunkId) {
+        if self.sizes.remove(&(file_id, chunk_id)).is_some() {
+            // A lost delete only leaks space in the bucket, so don't fail the caller.
+            let _ = ic_cdk::notify(self.bucket, "bucket_delete_chunk", (file_id, chunk_id));
+        }
+    }
+
+    fn contains(&self, file_id: FileId, chunk_id: ChunkId) -> bool {
+        self.sizes.contains_key(&(file_id, chunk_id))
+    }
+
+    fn chunk_len(&self, file_id: FileId, chunk_id: ChunkId) -> Option<u64> {
+        self.sizes.get(&(file_id, chunk_id)).copied()
+    }
+
+    fn chunk_count(&self, file_id: FileId) -> u64 {
+        self.sizes
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .count() as u64
+    }
+
+    fn size(&self, file_id: FileId) -> u64 {
+        self.sizes
+            .range((file_id, 0)..=(file_id, ChunkId::MAX))
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exercise(store: &mut dyn LocalChunkStore) {
+        assert!(store.is_empty());
+        store.put(1, 0, vec![1, 2, 3]);
+        store.put(1, 1, vec![4, 5]);
+        store.put(2, 0, vec![6]);
+
+        assert_eq!(store.get(1, 1), Some(vec![4, 5]));
+        assert!(store.contains(2, 0));
+        assert_eq!(store.chunk_len(1, 0), Some(3));
+        assert_eq!(store.chunk_count(1), 2);
+        assert_eq!(store.size(1), 5);
+        assert_eq!(store.range(1, 1..5), vec![(1, vec![4, 5])]);
+
+        store.delete(1, 0);
+        assert_eq!(store.get(1, 0), None);
+        assert_eq!(store.chunk_len(1, 0), None);
+        assert_eq!(store.chunk_count(1), 1);
+        assert_eq!(store.size(2), 1);
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn stable_store_round_trip() {
+        exercise(&mut StableChunkStore::init(crate::memory::get_file_contents_memory()));
+    }
+
+    #[test]
+    fn heap_store_round_trip() {
+        exercise(&mut HeapChunkStore::default());
+    }
+
+    fn dedup_store() -> DedupChunkStore {
+        DedupChunkStore::init(
+            crate::memory::get_chunk_refs_memory(),
+            crate::memory::get_chunk_blobs_memory(),
+            crate::memory::get_chunk_ref_counts_memory(),
+        )
+    }
+
+    #[test]
+    fn dedup_store_round_trip() {
+        exercise(&mut dedup_store());
+    }
+
+    #[test]
+    fn dedup_store_keeps_shared_contents_until_last_reference_is_gone() {
+        let mut store = dedup_store();
+        store.put(1, 0, vec![7; 10]);
+        store.put(2, 0, vec![7; 10]);
+        store.put(2, 1, vec![8]);
+        assert_eq!(store.unique_chunks(), 2);
+        assert_eq!(store.size(2), 11);
+
+        store.delete(1, 0);
+        assert_eq!(store.get(2, 0), Some(vec![7; 10]));
+        assert_eq!(store.unique_chunks(), 2);
+
+        // Overwriting releases the old contents.
+        store.put(2, 0, vec![9]);
+        assert_eq!(store.unique_chunks(), 2);
+        store.delete(2, 0);
+        store.delete(2, 1);
+        assert_eq!(store.unique_chunks(), 0);
+    }
+
+    #[test]
+    fn dedup_mode_only_switches_while_no_chunk_is_stored() {
+        // Like right after an upgrade: no files on the heap, but chunks in stable memory.
+        let mut state = crate::State::default();
+        state.file_contents.put(1, 0, vec![1]);
+        assert!(state.file_data.is_empty());
+        assert!(!state.set_chunk_dedup(true));
+        assert!(!dedup_enabled());
+
+        state.file_contents.delete(1, 0);
+        assert!(state.set_chunk_dedup(true));
+        assert!(dedup_enabled());
+        assert!(state.file_contents.is_empty());
+    }
+}
@@ -1,18 +1,18 @@
 pub mod api;
+pub mod certification;
+pub mod chunk_store;
 mod memory;
 
 use candid::CandidType;
 use candid::Principal;
-use ic_stable_structures::{
-    // memory_manager::MemoryId,
-    // storable::Storable, // Import Bound from storable submodule
-    StableBTreeMap,
-};
-
-use memory::Memory; // Only Memory is needed for file storage
+use api::{AccountDeletionRecord, Bucket, BucketWrite, DeletingFile, ErasureJob, FileIndex, FileVersions, Folder, ReplicationInfo, SearchIndex, Suspension, Transfer, TrashedFile, UploadSession};
+use certification::CertifiedTree;
+use chunk_store::{ChunkStore, DedupChunkStore, LocalChunkStore, StableChunkStore};
+use ic_stable_structures::StableBTreeMap;
+use memory::Memory;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 // use std::ops::Bound::{Included, Excluded};
 
 thread_local! {
@@ -20,8 +20,26 @@ thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::new(&get_randomness_seed()[..]));
 }
 
-type FileId = u64;
-type ChunkId = u64;
+pub type FileId = u64;
+pub type ChunkId = u64;
+
+/// Walrus Testnet aggregator run by Mysten Labs, used until a controller configures another one.
+pub const DEFAULT_WALRUS_AGGREGATOR_URL: &str = "https://aggregator.walrus-testnet.walrus.space";
+
+/// How much file data a bucket canister accepts before new files go elsewhere.
+pub const DEFAULT_BUCKET_CAPACITY_BYTES: u64 = 100 * 1024 * 1024 * 1024;
+
+/// Arguments passed when installing or upgrading the canister.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    /// Set when the canister is installed as a storage bucket of this index canister.
+    pub index_canister: Option<Principal>,
+    pub bucket_capacity_bytes: Option<u64>,
+    /// Store identical chunks once. Can only change while the canister holds no files.
+    pub dedup_chunks: Option<bool>,
+    /// Principals allowed to call the admin endpoints, usually the canister's controllers.
+    pub admins: Option<Vec<Principal>>,
+}
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct FileInfo {
@@ -38,6 +56,9 @@ pub struct FileMetadata {
     pub uploaded_at: Option<u64>,
     pub storage_provider: String, // "icp" or "walrus"
     pub blob_id: Option<String>,  // Only for Walrus files
+    pub bucket: Option<Principal>, // Only for files placed in a bucket canister
+    pub description: String,
+    pub tags: Vec<String>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -62,6 +83,8 @@ pub struct PublicFileMetadata {
     pub group_alias: Option<String>,
     pub file_status: FileStatus,
     pub shared_with: Vec<()>,
+    pub description: String,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -70,6 +93,33 @@ pub struct File {
     pub content: FileContent,
 }
 
+impl File {
+    /// The metadata returned to the owner by `list_files`.
+    pub fn public_metadata(&self, file_id: u64) -> PublicFileMetadata {
+        let file_status = match &self.content {
+            FileContent::Pending { alias } => FileStatus::Pending {
+                alias: alias.clone(),
+                requested_at: self.metadata.requested_at,
+            },
+            FileContent::PartiallyUploaded { .. } => FileStatus::PartiallyUploaded,
+            FileContent::Uploaded { .. } => FileStatus::Uploaded {
+                uploaded_at: self.metadata.uploaded_at.unwrap_or(self.metadata.requested_at),
+            },
+        };
+
+        PublicFileMetadata {
+            file_id,
+            file_name: self.metadata.file_name.clone(),
+            group_name: "".to_string(),          // Fill this if you use groups
+            group_alias: None,                   // Or Some(...) if available
+            file_status,
+            shared_with: vec![],                 // Empty vector for now since we don't support sharing yet
+            description: self.metadata.description.clone(),
+            tags: self.metadata.tags.clone(),
+        }
+    }
+}
+
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FileContent {
@@ -106,6 +156,20 @@ pub enum FileDownloadResponse {
     PermissionError,
     #[serde(rename = "found_file")]
     FoundFile(FileData),
+    #[serde(rename = "storage_provider_error")]
+    StorageProviderError(String),
+    #[serde(rename = "stored_in_bucket")]
+    StoredInBucket(BucketLocation),
+    #[serde(rename = "suspended")]
+    Suspended,
+}
+
+/// Where to fetch the chunks of a file that lives in a bucket canister.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BucketLocation {
+    pub canister_id: Principal,
+    pub file_type: String,
+    pub num_chunks: u64,
 }
 
 #[derive(Debug, CandidType, Serialize, Deserialize)]
@@ -116,6 +180,14 @@ pub enum UploadFileError {
     AlreadyUploaded,
     #[serde(rename = "not_authenticated")]
     NotAuthenticated,
+    #[serde(rename = "suspended")]
+    Suspended,
+    /// The file's bucket didn't store the chunk; it can be uploaded again.
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+    /// Every chunk but the last must have the length of the first one, and the last at most that.
+    #[serde(rename = "invalid_chunk_length")]
+    InvalidChunkLength,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug, PartialEq)]
@@ -141,14 +213,127 @@ pub struct State {
     // Mapping between a user's principal and the list of files that are owned by the user.
     pub file_owners: BTreeMap<Principal, Vec<u64>>,
 
-    /// The contents of the file (stored in stable memory).
+    /// The contents of the file (stored in stable memory by default).
     #[serde(skip, default = "init_file_contents")]
-    pub file_contents: StableBTreeMap<(FileId, ChunkId), Vec<u8>, Memory>,
+    pub file_contents: Box<dyn LocalChunkStore>,
 
     // User management
     pub user_profiles: BTreeMap<Principal, UserProfile>,
     pub username_to_principal: BTreeMap<String, Principal>, // For username uniqueness
     pub user_count: u64,
+
+    /// Base URL of the Walrus aggregator used to proxy downloads of Walrus files.
+    pub walrus_aggregator_url: String,
+
+    /// Sui JSON-RPC endpoint used to verify Walrus blob ownership; checks are off when unset.
+    pub sui_rpc_url: Option<String>,
+
+    // Sui addresses each principal has proven control of by signing `sui_link_message`.
+    pub sui_addresses: BTreeMap<Principal, Vec<String>>,
+
+    /// Replication policy and replica health for files that have one.
+    pub file_replication: BTreeMap<u64, ReplicationInfo>,
+
+    // Last file checked by the replication verifier; the next run continues after it.
+    pub replication_cursor: Option<u64>,
+
+    /// When this canister is a storage bucket, the index canister allowed to manage its chunks.
+    pub index_canister: Option<Principal>,
+
+    // Principals each file's chunks may be read by, when this canister is a bucket.
+    pub bucket_readers: BTreeMap<u64, Vec<Principal>>,
+
+    /// Bucket canisters created by this index canister, rebuilt from `bucket_registry`
+    /// and `bucket_reservations` by `api::restore_buckets` after an upgrade.
+    #[serde(skip)]
+    pub buckets: BTreeMap<Principal, Bucket>,
+
+    // When each bucket was created, kept in stable memory.
+    #[serde(skip, default = "init_bucket_registry")]
+    pub bucket_registry: StableBTreeMap<Principal, u64, Memory>,
+
+    // Bytes set aside in a bucket for each file placed there, kept in stable memory.
+    #[serde(skip, default = "init_bucket_reservations")]
+    pub bucket_reservations: StableBTreeMap<(Principal, u64), u64, Memory>,
+
+    // Chunks queued for buckets by the current call, sent by `api::send_bucket_writes`.
+    #[serde(skip)]
+    pub bucket_writes: Vec<BucketWrite>,
+
+    // Wasm installed into new buckets; scaling out is disabled until a controller sets it.
+    pub bucket_wasm: Option<Vec<u8>>,
+
+    pub bucket_capacity_bytes: u64,
+
+    // Users an admin allowed to provision a dedicated storage canister.
+    pub dedicated_storage_allowed: BTreeSet<Principal>,
+
+    /// Dedicated storage canister of each user that opted into one, kept in stable memory.
+    #[serde(skip, default = "init_user_canisters")]
+    pub user_canisters: StableBTreeMap<Principal, Principal, Memory>,
+
+    // Dedicated canisters of erased accounts, deleted by `api::delete_removed_canisters`.
+    pub removed_canisters: Vec<Principal>,
+
+    // Download link tokens served by `http_request` at `/f/<token>`, and the file each one opens.
+    pub download_links: BTreeMap<String, u64>,
+
+    /// Hashes of file metadata, chunks and download link bodies, certified after every change.
+    #[serde(skip)]
+    pub certified: CertifiedTree,
+
+    // Uploads started with `begin_upload` that haven't been finalized, by file ID.
+    pub upload_sessions: BTreeMap<u64, UploadSession>,
+
+    pub transfers: BTreeMap<u64, Transfer>,
+    pub transfer_count: u64,
+    // The transfer each file was sent in; a file belongs to at most one.
+    pub file_transfers: BTreeMap<u64, u64>,
+    // Transfers whose recipients still have to lose bucket access, by expiry time.
+    pub transfer_expiry: BTreeSet<(u64, u64)>,
+
+    pub folders: BTreeMap<u64, Folder>,
+    pub folder_count: u64,
+    // The folders directly under each user's folder (`None` is the root).
+    pub subfolders: BTreeMap<(Principal, Option<u64>), BTreeSet<u64>>,
+    // Files moved into a folder; files without an entry are in the root.
+    pub file_folders: BTreeMap<u64, u64>,
+    pub folder_files: BTreeMap<u64, BTreeSet<u64>>,
+
+    // The files each user tagged with a tag.
+    pub file_tags: BTreeMap<(Principal, String), BTreeSet<u64>>,
+
+    // Each user's files by name, upload time and size, for `list_files_v2`.
+    pub file_index: FileIndex,
+    // Token suffixes of file names, tags and transfer titles, for `search_files`.
+    pub search_index: SearchIndex,
+
+    // Each user's deleted files, by file ID, and when they are due to be purged.
+    pub trash: BTreeMap<Principal, BTreeMap<u64, TrashedFile>>,
+    pub trash_expiry: BTreeSet<(u64, u64)>,
+
+    // Previous versions of files that have been updated.
+    pub file_versions: BTreeMap<u64, FileVersions>,
+    // Hidden staging files receiving a new version, and the file they update.
+    pub version_uploads: BTreeMap<u64, u64>,
+    // Files whose current chunks are stored under another ID, the staging file of
+    // their latest version. Other files store their chunks under their own ID.
+    pub file_storage: BTreeMap<u64, u64>,
+    // Deleted files, and archived versions, whose chunks are still being freed.
+    pub deleting: BTreeMap<u64, DeletingFile>,
+
+    // Deleted accounts, and what is left to erase of those that asked for it.
+    pub account_deletions: BTreeMap<Principal, AccountDeletionRecord>,
+    pub erasure_jobs: BTreeMap<Principal, ErasureJob>,
+
+    /// Principals allowed to call the admin endpoints, besides the controllers. Kept in
+    /// stable memory so admins added with `add_admin` survive upgrades.
+    #[serde(skip, default = "init_admins")]
+    pub admins: StableBTreeMap<Principal, (), Memory>,
+    // Suspended principals, whether or not they have a profile.
+    pub suspensions: BTreeMap<Principal, Suspension>,
+    // Whether suspended users are also blocked from downloading their files.
+    pub block_suspended_downloads: bool,
 }
 
 impl State {
@@ -168,9 +353,141 @@ impl State {
             user_profiles: BTreeMap::new(),
             username_to_principal: BTreeMap::new(),
             user_count: 0,
+            walrus_aggregator_url: DEFAULT_WALRUS_AGGREGATOR_URL.to_string(),
+            sui_rpc_url: None,
+            sui_addresses: BTreeMap::new(),
+            file_replication: BTreeMap::new(),
+            replication_cursor: None,
+            index_canister: None,
+            bucket_readers: BTreeMap::new(),
+            buckets: BTreeMap::new(),
+            bucket_registry: init_bucket_registry(),
+            bucket_reservations: init_bucket_reservations(),
+            bucket_writes: Vec::new(),
+            bucket_wasm: None,
+            bucket_capacity_bytes: DEFAULT_BUCKET_CAPACITY_BYTES,
+            dedicated_storage_allowed: BTreeSet::new(),
+            user_canisters: init_user_canisters(),
+            removed_canisters: Vec::new(),
+            download_links: BTreeMap::new(),
+            certified: CertifiedTree::default(),
+            upload_sessions: BTreeMap::new(),
+            transfers: BTreeMap::new(),
+            transfer_count: 0,
+            file_transfers: BTreeMap::new(),
+            transfer_expiry: BTreeSet::new(),
+            folders: BTreeMap::new(),
+            folder_count: 0,
+            subfolders: BTreeMap::new(),
+            file_folders: BTreeMap::new(),
+            folder_files: BTreeMap::new(),
+            file_tags: BTreeMap::new(),
+            file_index: FileIndex::default(),
+            search_index: SearchIndex::default(),
+            trash: BTreeMap::new(),
+            trash_expiry: BTreeSet::new(),
+            file_versions: BTreeMap::new(),
+            version_uploads: BTreeMap::new(),
+            file_storage: BTreeMap::new(),
+            deleting: BTreeMap::new(),
+            account_deletions: BTreeMap::new(),
+            erasure_jobs: BTreeMap::new(),
+            admins: init_admins(),
+            suspensions: BTreeMap::new(),
+            block_suspended_downloads: false,
+        }
+    }
+
+    /// Creates an empty state whose file contents live in `store` instead of stable memory.
+    pub fn with_chunk_store(store: Box<dyn LocalChunkStore>) -> Self {
+        Self {
+            file_contents: store,
+            ..State::default()
         }
     }
 
+    /// Switches the local chunks between the plain and the deduplicating store.
+    /// Returns false if the current store holds chunks: the other store wouldn't find them.
+    /// The store itself is checked, as `file_data` is still empty when `post_upgrade` calls this.
+    pub fn set_chunk_dedup(&mut self, enabled: bool) -> bool {
+        if chunk_store::dedup_enabled() == enabled {
+            return true;
+        }
+        if !self.file_contents.is_empty() {
+            return false;
+        }
+        chunk_store::set_dedup_enabled(enabled);
+        self.file_contents = init_file_contents();
+        true
+    }
+
+    /// The store holding the chunks of `file_id`: its bucket if it has one, local memory otherwise.
+    pub(crate) fn chunks_for(&self, file_id: u64) -> &dyn ChunkStore {
+        let bucket = self.file_data.get(&file_id).and_then(|f| f.metadata.bucket);
+        match bucket.and_then(|b| self.buckets.get(&b)) {
+            Some(bucket) => &bucket.store,
+            None => self.file_contents.as_ref(),
+        }
+    }
+
+    /// The ID the current chunks of `file_id` are stored under. Chunks are certified
+    /// under the file's own ID regardless.
+    pub(crate) fn storage_id(&self, file_id: u64) -> u64 {
+        self.file_storage.get(&file_id).copied().unwrap_or(file_id)
+    }
+
+    /// Stores a chunk of `file_id` in local memory, or queues it for the file's bucket.
+    /// Queued chunks only count as stored once `api::send_bucket_writes` got them in.
+    pub(crate) fn store_chunk(&mut self, file_id: u64, chunk_id: u64, contents: Vec<u8>) {
+        let bucket = self.file_data.get(&file_id).and_then(|f| f.metadata.bucket);
+        match bucket.filter(|b| self.buckets.contains_key(b)) {
+            Some(bucket) => self.bucket_writes.push(BucketWrite {
+                bucket,
+                file_id,
+                chunk_id,
+                contents,
+            }),
+            None => {
+                let storage_id = self.storage_id(file_id);
+                self.file_contents.put(storage_id, chunk_id, contents)
+            }
+        }
+    }
+
+    /// Length of a chunk of `file_id` that is stored, or queued for its bucket by the current call.
+    pub(crate) fn chunk_len(&self, file_id: u64, chunk_id: u64) -> Option<u64> {
+        self.chunks_for(file_id).chunk_len(self.storage_id(file_id), chunk_id).or_else(|| {
+            self.bucket_writes
+                .iter()
+                .find(|w| w.file_id == file_id && w.chunk_id == chunk_id)
+                .map(|w| w.contents.len() as u64)
+        })
+    }
+
+    /// True if a chunk of `file_id` is stored, or queued for its bucket by the current call.
+    pub(crate) fn has_chunk(&self, file_id: u64, chunk_id: u64) -> bool {
+        self.chunks_for(file_id).contains(self.storage_id(file_id), chunk_id)
+            || self.bucket_writes.iter().any(|w| w.file_id == file_id && w.chunk_id == chunk_id)
+    }
+
+    /// The metadata of a file as listed to its owner, grouped by the transfer it was sent in.
+    pub fn public_file_metadata(&self, file_id: u64) -> Option<PublicFileMetadata> {
+        let file = self.file_data.get(&file_id)?;
+        let mut metadata = file.public_metadata(file_id);
+        if let Some(transfer) = self.file_transfers.get(&file_id).and_then(|id| self.transfers.get(id)) {
+            metadata.group_name = transfer.title.clone();
+            metadata.group_alias = Some(transfer.transfer_id.to_string());
+        }
+        Some(metadata)
+    }
+
+    /// Returns true if `caller` owns the file with the given ID.
+    pub(crate) fn is_file_owner(&self, caller: Principal, file_id: u64) -> bool {
+        self.file_owners
+            .get(&caller)
+            .map_or(false, |files| files.contains(&file_id))
+    }
+
     // pub(crate) fn num_chunks_uploaded(&self, file_id: u64) -> u64 {
     //     self.file_contents
     //         .range((Included((file_id, 0u64)), Excluded(((file_id + 1), 0u64))))
@@ -240,8 +557,31 @@ pub fn ceil_division(dividend: usize, divisor: usize) -> usize {
     }
 }
 
-fn init_file_contents() -> StableBTreeMap<(FileId, ChunkId), Vec<u8>, Memory> {
-    StableBTreeMap::init(crate::memory::get_file_contents_memory())
+fn init_file_contents() -> Box<dyn LocalChunkStore> {
+    if chunk_store::dedup_enabled() {
+        return Box::new(DedupChunkStore::init(
+            crate::memory::get_chunk_refs_memory(),
+            crate::memory::get_chunk_blobs_memory(),
+            crate::memory::get_chunk_ref_counts_memory(),
+        ));
+    }
+    Box::new(StableChunkStore::init(crate::memory::get_file_contents_memory()))
+}
+
+fn init_bucket_registry() -> StableBTreeMap<Principal, u64, Memory> {
+    StableBTreeMap::init(crate::memory::get_buckets_memory())
+}
+
+fn init_bucket_reservations() -> StableBTreeMap<(Principal, u64), u64, Memory> {
+    StableBTreeMap::init(crate::memory::get_bucket_reservations_memory())
+}
+
+fn init_user_canisters() -> StableBTreeMap<Principal, Principal, Memory> {
+    StableBTreeMap::init(crate::memory::get_user_canisters_memory())
+}
+
+fn init_admins() -> StableBTreeMap<Principal, (), Memory> {
+    StableBTreeMap::init(crate::memory::get_admins_memory())
 }
 
 #[ic_cdk::query]
@@ -260,7 +600,10 @@ pub struct UserProfile {
     pub last_login: u64,
     pub storage_used: u64, // in bytes
     pub file_count: u64,
-    pub is_active: bool,
+    pub is_active: bool, // false while the account is suspended
+    pub storage_canister: Option<Principal>, // set when the user has a dedicated canister
+    pub suspended_at: Option<u64>, // set while suspended, from `suspensions`
+    pub suspension_reason: Option<String>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -268,6 +611,7 @@ pub struct CreateUserRequest {
     pub username: String,
     pub display_name: Option<String>,
     pub email: Option<String>,
+    pub dedicated_storage: Option<bool>, // provision a storage canister used only by this user
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -284,10 +628,12 @@ pub enum UserResponse {
     AlreadyExists,
     InvalidInput,
     NotAuthenticated,
+    Suspended,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum UserListResponse {
     Ok(Vec<UserProfile>),
     NotAuthenticated,
+    NotAuthorized,
 }
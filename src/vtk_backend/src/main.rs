@@ -1,28 +1,148 @@
 // use ic_cdk_macros::{post_upgrade, pre_upgrade, query, update};
-use ic_cdk_macros::{query, update};
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use std::time::Duration;
 use vtk_backend::*;
 use vtk_backend::api::UploadFileAtomicRequest;
 use vtk_backend::api::DeleteFileResult;
 use candid::Principal;
-use vtk_backend::api::{RegisterFileRequest, RegisterFileResponse};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use vtk_backend::api::{RegisterFileError, RegisterFileRequest, RegisterFileResponse};
+use vtk_backend::api::{LinkSuiAddressRequest, SuiLinkError};
+use vtk_backend::api::{ReplicationError, ReplicationInfo, SetReplicationPolicyRequest};
+use vtk_backend::api::{BucketError, BucketInfo, UserCanisterError};
+use vtk_backend::api::{DownloadLinkError, GatewayRequest, GatewayResponse};
+use vtk_backend::api::{StreamingCallbackResponse, StreamingCallbackToken};
+use vtk_backend::api::{CertifiedDownloadResponse, CertifiedFileList};
+use vtk_backend::api::{ListFilesError, ListFilesPage, ListFilesRequest};
+use vtk_backend::api::{SearchError, SearchResults};
+use vtk_backend::api::{ChunkUploadResult, DownloadChunksResponse, FileManifestResponse};
+use vtk_backend::api::{CreateTransferRequest, Transfer, TransferError, TransferList};
+use vtk_backend::api::{Folder, FolderError, FolderListing, FolderLocator};
+use vtk_backend::api::{UpdateFileMetadataError, UpdateFileMetadataRequest};
+use vtk_backend::api::{TrashError, TrashedFile};
+use vtk_backend::api::{FileVersion, VersionError};
+use vtk_backend::api::{AccountDeletionRecord, DeleteAccountMode};
+use vtk_backend::api::{DataExport, DataExportError, ExportChunkPage, ImportResult};
+use vtk_backend::api::{AdminError, CanisterConfig, GlobalStats, Suspension, UpdateConfigRequest};
+use vtk_backend::api::{BeginUploadRequest, FinalizeUploadRequest, UploadSession, UploadSessionError};
 use vtk_backend::{CreateUserRequest, UpdateUserRequest, UserResponse, UserListResponse};
 
+// How often the replication verifier checks the next batch of files.
+const REPLICATION_VERIFIER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often bucket canisters are topped up and checked for free capacity.
+const BUCKET_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often files past their trash retention period are purged, and recipients of
+// expired transfers lose access to their files in buckets.
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often chunks of deleted files are freed, a batch at a time.
+const CHUNK_RECLAIM_INTERVAL: Duration = Duration::from_secs(10);
+// How often deleted accounts that asked for their data to be erased make progress.
+const ACCOUNT_ERASURE_INTERVAL: Duration = Duration::from_secs(30);
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(REPLICATION_VERIFIER_INTERVAL, || {
+        ic_cdk::spawn(vtk_backend::api::run_replication_verifier())
+    });
+    ic_cdk_timers::set_timer_interval(BUCKET_MAINTENANCE_INTERVAL, || {
+        ic_cdk::spawn(vtk_backend::api::run_bucket_maintenance())
+    });
+    ic_cdk_timers::set_timer_interval(TRASH_PURGE_INTERVAL, || {
+        with_state_mut(|s| {
+            vtk_backend::api::purge_expired_trash(s, get_time());
+            vtk_backend::api::revoke_expired_transfers(s, get_time());
+        })
+    });
+    ic_cdk_timers::set_timer_interval(CHUNK_RECLAIM_INTERVAL, || {
+        with_state_mut(vtk_backend::api::reclaim_deleted_chunks)
+    });
+    ic_cdk_timers::set_timer_interval(ACCOUNT_ERASURE_INTERVAL, || {
+        with_state_mut(vtk_backend::api::run_account_erasures);
+        ic_cdk::spawn(vtk_backend::api::delete_removed_canisters())
+    });
+}
+
+#[init]
+fn init(args: Option<InitArgs>) {
+    with_state_mut(|s| vtk_backend::api::apply_init_args(args.unwrap_or_default(), s));
+    start_timers();
+}
+
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    with_state_mut(|s| {
+        vtk_backend::api::apply_init_args(args.unwrap_or_default(), s);
+        vtk_backend::api::restore_buckets(s);
+        vtk_backend::api::restore_user_canisters(s);
+    });
+    start_timers();
+}
+
+#[update]
+async fn upload_file_atomic(request: UploadFileAtomicRequest) -> Result<u64, UploadFileError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::upload_file_atomic_and_store(caller, request).await
+}
+
+#[update]
+async fn upload_file_continue(request: UploadFileContinueRequest) -> Result<(), UploadFileError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::upload_file_continue_and_store(caller, request).await
+}
+
+#[update]
+fn begin_upload(request: BeginUploadRequest) -> Result<UploadSession, UploadSessionError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::begin_upload(caller, request, s))
+}
+
 #[update]
-fn upload_file_atomic(request: UploadFileAtomicRequest) -> u64 {
+fn finalize_upload(request: FinalizeUploadRequest) -> Result<(), UploadSessionError> {
     let caller = ic_cdk::caller();
-    with_state_mut(|s| vtk_backend::api::upload_file_atomic(caller, request, s))
+    with_state_mut(|s| vtk_backend::api::finalize_upload(caller, request, s))
 }
 
 #[update]
-fn upload_file_continue(request: UploadFileContinueRequest) -> Result<(), UploadFileError> {
+async fn upload_chunks(file_id: u64, chunks: Vec<(u64, Vec<u8>)>) -> Result<Vec<ChunkUploadResult>, UploadFileError> {
     let caller = ic_cdk::caller();
-    with_state_mut(|s| vtk_backend::api::upload_file_continue(caller, request, s))
+    vtk_backend::api::upload_chunks_and_store(caller, file_id, chunks).await
+}
+
+#[update]
+async fn register_file(request: RegisterFileRequest) -> Result<RegisterFileResponse, RegisterFileError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::register_file(caller, request).await
+}
+
+#[query]
+fn get_sui_link_message() -> String {
+    vtk_backend::api::sui_link_message(ic_cdk::caller())
 }
 
 #[update]
-fn register_file(request: RegisterFileRequest) -> RegisterFileResponse {
+fn link_sui_address(request: LinkSuiAddressRequest) -> Result<(), SuiLinkError> {
     let caller = ic_cdk::caller();
-    vtk_backend::api::register_file(caller, request)
+    with_state_mut(|s| vtk_backend::api::link_sui_address(caller, request, s))
+}
+
+#[query]
+fn list_sui_addresses() -> Vec<String> {
+    let caller = ic_cdk::caller();
+    with_state(|s| s.sui_addresses.get(&caller).cloned().unwrap_or_default())
+}
+
+#[update]
+fn set_sui_rpc_url(url: Option<String>) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    let request = UpdateConfigRequest {
+        sui_rpc_url: Some(url),
+        ..Default::default()
+    };
+    with_state_mut(|s| vtk_backend::api::update_config(caller, is_controller, request, s)).map(|_| ())
+}
+
+#[query]
+fn transform_sui_response(args: TransformArgs) -> HttpResponse {
+    vtk_backend::api::transform_sui_response(args)
 }
 
 #[query]
@@ -31,52 +151,301 @@ fn download_file(file_id: u64, chunk_id: u64) -> FileDownloadResponse {
     with_state(|s| vtk_backend::api::download_file(s, caller, file_id, chunk_id))
 }
 
+#[query]
+fn download_chunks(file_id: u64, start_chunk: u64, max_bytes: u64) -> DownloadChunksResponse {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::download_chunks(s, caller, file_id, start_chunk, max_bytes))
+}
+
+#[query]
+fn file_manifest(file_id: u64) -> FileManifestResponse {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::file_manifest(s, caller, file_id))
+}
+
+#[query]
+fn download_file_certified(file_id: u64, chunk_id: u64) -> CertifiedDownloadResponse {
+    let caller = ic_cdk::caller();
+    let certificate = ic_cdk::api::data_certificate();
+    with_state(|s| vtk_backend::api::download_file_certified(s, caller, file_id, chunk_id, certificate))
+}
+
+#[update]
+async fn create_download_link(file_id: u64) -> Result<String, DownloadLinkError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::create_download_link(caller, file_id).await
+}
+
+#[update]
+fn revoke_download_link(link: String) -> Result<(), DownloadLinkError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::revoke_download_link(caller, link, s))
+}
+
+// Serves `/f/<token>` download links to browsers through the HTTP gateway.
+#[query]
+fn http_request(request: GatewayRequest) -> GatewayResponse {
+    let certificate = ic_cdk::api::data_certificate();
+    with_state(|s| vtk_backend::api::http_request(ic_cdk::id(), request, certificate, s))
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackResponse {
+    with_state(|s| vtk_backend::api::http_request_streaming_callback(token, s))
+}
+
+#[update]
+async fn download_walrus_chunk(file_id: u64, chunk_id: u64) -> FileDownloadResponse {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::download_walrus_chunk(caller, file_id, chunk_id).await
+}
+
+#[query]
+fn transform_walrus_response(args: TransformArgs) -> HttpResponse {
+    vtk_backend::api::transform_walrus_response(args)
+}
+
+#[update]
+fn set_walrus_aggregator_url(url: String) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    let request = UpdateConfigRequest {
+        walrus_aggregator_url: Some(url),
+        ..Default::default()
+    };
+    with_state_mut(|s| vtk_backend::api::update_config(caller, is_controller, request, s)).map(|_| ())
+}
+
+#[update]
+fn set_replication_policy(request: SetReplicationPolicyRequest) -> Result<ReplicationInfo, ReplicationError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::set_replication_policy(caller, request, s))
+}
+
+#[update]
+async fn add_walrus_replica(
+    file_id: u64,
+    blob_id: String,
+    sui_object_id: Option<String>,
+) -> Result<ReplicationInfo, ReplicationError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::add_walrus_replica(caller, file_id, blob_id, sui_object_id).await
+}
+
+#[query]
+fn get_replication_status(file_id: u64) -> Result<ReplicationInfo, ReplicationError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::get_replication_status(caller, file_id, s))
+}
+
 #[update]
 fn delete_file(file_id: u64) -> DeleteFileResult {
     let caller = ic_cdk::caller();
     with_state_mut(|s| vtk_backend::api::delete_file(s, caller, file_id))
 }
 
+#[query]
+fn list_trash() -> Vec<TrashedFile> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_trash(caller, s))
+}
+
+#[update]
+fn restore_file(file_id: u64) -> Result<(), TrashError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::restore_file(caller, file_id, s))
+}
+
+#[update]
+fn empty_trash() -> Result<(), TrashError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::empty_trash(caller, s))
+}
+
+// File versions
+#[update]
+fn begin_version_upload(file_id: u64, request: BeginUploadRequest) -> Result<UploadSession, VersionError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::begin_version_upload(caller, file_id, request, s))
+}
+
+#[query]
+fn list_versions(file_id: u64) -> Result<Vec<FileVersion>, VersionError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_versions(caller, file_id, s))
+}
+
+#[query]
+fn download_file_version(file_id: u64, version_id: u64, chunk_id: u64) -> Result<FileData, VersionError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::download_file_version(caller, file_id, version_id, chunk_id, s))
+}
+
+#[update]
+fn set_version_retention(file_id: u64, max_old_versions: u32) -> Result<(), VersionError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::set_version_retention(caller, file_id, max_old_versions, s))
+}
+
+
+// Bucket endpoints, called by the index canister through `RemoteBucketStore`.
+#[update]
+fn bucket_put_chunk(file_id: u64, chunk_id: u64, contents: Vec<u8>) -> Result<(), BucketError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::bucket_put_chunk(caller, file_id, chunk_id, contents, s))
+}
+
+#[update]
+fn bucket_delete_chunk(file_id: u64, chunk_id: u64) -> Result<(), BucketError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::bucket_delete_chunk(caller, file_id, chunk_id, s))
+}
+
+#[query]
+fn bucket_get_chunk(file_id: u64, chunk_id: u64) -> Result<Option<Vec<u8>>, BucketError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::bucket_get_chunk(caller, file_id, chunk_id, s))
+}
+
+#[update]
+fn bucket_set_readers(file_id: u64, readers: Vec<Principal>) -> Result<(), BucketError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::bucket_set_readers(caller, file_id, readers, s))
+}
+
+// Index endpoints for scaling out to bucket canisters.
+#[update]
+fn set_bucket_wasm(wasm: Vec<u8>) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::set_bucket_wasm(caller, is_controller, wasm, s))
+}
+
+#[update]
+async fn create_bucket() -> Result<Principal, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::require_admin(caller, is_controller, s))?;
+    vtk_backend::api::create_bucket().await.map_err(AdminError::CallFailed)
+}
+
+#[query]
+fn list_buckets() -> Result<Vec<BucketInfo>, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::list_buckets(caller, is_controller, s))
+}
+
+#[update]
+fn set_index_canister(index: Option<Principal>) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::set_index_canister(caller, is_controller, index, s))
+}
 
 #[query]
 fn list_files() -> Vec<PublicFileMetadata> {
     let caller = ic_cdk::caller();
-    with_state(|s| {
-        // If caller is anonymous, return empty list
-        if caller == Principal::anonymous() {
-            panic!("Not authenticated");
-        }
-
-        // Get files owned by the caller
-        let empty_vec = Vec::new();
-        let owned_files = s.file_owners.get(&caller).unwrap_or(&empty_vec);
-        
-        owned_files.iter().filter_map(|&file_id| {
-            s.file_data.get(&file_id).map(|file| {
-                let file_status = match &file.content {
-                    FileContent::Pending { alias } => FileStatus::Pending {
-                        alias: alias.clone(),
-                        requested_at: file.metadata.requested_at,
-                    },
-                    FileContent::PartiallyUploaded { .. } => FileStatus::PartiallyUploaded,
-                    FileContent::Uploaded { .. } => FileStatus::Uploaded {
-                        uploaded_at: file.metadata.uploaded_at.unwrap_or(file.metadata.requested_at),
-                    },
-                };
-
-                PublicFileMetadata {
-                    file_id,
-                    file_name: file.metadata.file_name.clone(),
-                    group_name: "".to_string(),          // Fill this if you use groups
-                    group_alias: None,                   // Or Some(...) if available
-                    file_status,
-                    shared_with: vec![],                 // Empty vector for now since we don't support sharing yet
-                }
-            })
-        }).collect()
-    })
+    with_state(|s| vtk_backend::api::list_files(caller, s))
+}
+
+#[query]
+fn list_files_certified() -> CertifiedFileList {
+    let caller = ic_cdk::caller();
+    let certificate = ic_cdk::api::data_certificate();
+    with_state(|s| vtk_backend::api::list_files_certified(caller, certificate, s))
+}
+
+#[query]
+fn list_files_v2(request: ListFilesRequest) -> Result<ListFilesPage, ListFilesError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_files_v2(caller, request, s))
+}
+
+#[query]
+fn search_files(query: String, page: u64) -> Result<SearchResults, SearchError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::search_files(caller, query, page, s))
+}
+
+// Transfers: several files sent together with a message
+#[update]
+fn create_transfer(request: CreateTransferRequest) -> Result<Transfer, TransferError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::create_transfer(caller, request, s))
+}
+
+#[query]
+fn list_transfers() -> TransferList {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_transfers(caller, s))
+}
+
+#[query]
+fn get_transfer(transfer_id: u64) -> Result<Transfer, TransferError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::get_transfer(caller, transfer_id, s))
+}
+
+#[query]
+fn download_transfer_file(transfer_id: u64, file_id: u64, chunk_id: u64) -> FileDownloadResponse {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::download_transfer_file(s, caller, transfer_id, file_id, chunk_id))
 }
 
+#[update]
+fn delete_transfer(transfer_id: u64) -> Result<(), TransferError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::delete_transfer(caller, transfer_id, s))
+}
+
+// File details: name, description and tags
+#[update]
+fn update_file_metadata(
+    file_id: u64,
+    request: UpdateFileMetadataRequest,
+) -> Result<PublicFileMetadata, UpdateFileMetadataError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::update_file_metadata(caller, file_id, request, s))
+}
+
+#[query]
+fn list_files_by_tag(tag: String) -> Vec<PublicFileMetadata> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_files_by_tag(caller, tag, s))
+}
+
+// Folders
+#[update]
+fn create_folder(name: String, parent: Option<u64>) -> Result<Folder, FolderError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::create_folder(caller, name, parent, s))
+}
+
+#[update]
+fn rename_folder(folder_id: u64, name: String) -> Result<Folder, FolderError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::rename_folder(caller, folder_id, name, s))
+}
+
+#[update]
+fn move_folder(folder_id: u64, parent: Option<u64>) -> Result<Folder, FolderError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::move_folder(caller, folder_id, parent, s))
+}
+
+#[update]
+fn delete_folder(folder_id: u64) -> Result<(), FolderError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::delete_folder(caller, folder_id, s))
+}
+
+#[update]
+fn move_file(file_id: u64, folder_id: Option<u64>) -> Result<(), FolderError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::move_file(caller, file_id, folder_id, s))
+}
+
+#[query]
+fn list_folder(locator: FolderLocator, page: u64) -> Result<FolderListing, FolderError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::list_folder(caller, locator, page, s))
+}
 
 #[query]
 fn greet(name: String) -> String {
@@ -85,9 +454,9 @@ fn greet(name: String) -> String {
 
 // User management endpoints
 #[update]
-fn create_user_profile(request: CreateUserRequest) -> UserResponse {
+async fn create_user_profile(request: CreateUserRequest) -> UserResponse {
     let caller = ic_cdk::caller();
-    with_state_mut(|s| vtk_backend::api::create_user_profile(caller, request, s))
+    vtk_backend::api::create_user_profile_with_storage(caller, request).await
 }
 
 #[query]
@@ -103,15 +472,41 @@ fn update_user_profile(request: UpdateUserRequest) -> UserResponse {
 }
 
 #[update]
-fn delete_user_profile() -> UserResponse {
+fn delete_user_profile(mode: Option<DeleteAccountMode>) -> UserResponse {
     let caller = ic_cdk::caller();
-    with_state_mut(|s| vtk_backend::api::delete_user_profile(caller, s))
+    let response = with_state_mut(|s| vtk_backend::api::delete_user_profile(caller, mode, s));
+    ic_cdk::spawn(vtk_backend::api::delete_removed_canisters());
+    response
 }
 
 #[query]
-fn list_users() -> UserListResponse {
+fn export_my_data() -> Result<DataExport, DataExportError> {
     let caller = ic_cdk::caller();
-    with_state(|s| vtk_backend::api::list_users(caller, s))
+    with_state(|s| vtk_backend::api::export_my_data(caller, s))
+}
+
+#[query]
+fn export_file_chunks(file_id: u64, start_chunk: u64) -> Result<ExportChunkPage, DataExportError> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::export_file_chunks(caller, file_id, start_chunk, s))
+}
+
+#[update]
+fn import_my_data(export: DataExport) -> Result<ImportResult, DataExportError> {
+    let caller = ic_cdk::caller();
+    with_state_mut(|s| vtk_backend::api::import_my_data(caller, export, s))
+}
+
+#[query]
+fn get_account_deletion() -> Option<AccountDeletionRecord> {
+    let caller = ic_cdk::caller();
+    with_state(|s| vtk_backend::api::get_account_deletion(caller, s))
+}
+
+#[query]
+fn list_users() -> UserListResponse {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::list_users(caller, is_controller, s))
 }
 
 #[query]
@@ -120,4 +515,96 @@ fn get_user_stats() -> UserResponse {
     with_state(|s| vtk_backend::api::get_user_stats(caller, s))
 }
 
+// Admin endpoints; each one checks `require_admin`.
+#[query]
+fn list_admins() -> Result<Vec<Principal>, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::list_admins(caller, is_controller, s))
+}
+
+#[update]
+fn add_admin(admin: Principal) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::add_admin(caller, is_controller, admin, s))
+}
+
+#[update]
+fn remove_admin(admin: Principal) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::remove_admin(caller, is_controller, admin, s))
+}
+
+#[update]
+fn suspend_user(user: Principal, reason: String) -> Result<Suspension, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::suspend_user(caller, is_controller, user, reason, s))
+}
+
+#[update]
+fn reactivate_user(user: Principal) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::reactivate_user(caller, is_controller, user, s))
+}
+
+#[query]
+fn list_suspensions() -> Result<Vec<(Principal, Suspension)>, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::list_suspensions(caller, is_controller, s))
+}
+
+#[query]
+fn get_global_stats() -> Result<GlobalStats, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::get_global_stats(caller, is_controller, s))
+}
+
+#[query]
+fn get_config() -> Result<CanisterConfig, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state(|s| vtk_backend::api::get_config(caller, is_controller, s))
+}
+
+#[update]
+fn update_config(request: UpdateConfigRequest) -> Result<CanisterConfig, AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::update_config(caller, is_controller, request, s))
+}
+
+#[update]
+fn force_delete_file(file_id: u64) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::force_delete_file(caller, is_controller, file_id, s))
+}
+
+// Dedicated storage canister endpoints
+#[update]
+fn allow_dedicated_storage(user: Principal, allowed: bool) -> Result<(), AdminError> {
+    let (caller, is_controller) = admin_caller();
+    with_state_mut(|s| vtk_backend::api::allow_dedicated_storage(caller, is_controller, user, allowed, s))
+}
+
+#[update]
+async fn provision_user_canister() -> Result<Principal, UserCanisterError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::provision_user_canister(caller).await
+}
+
+#[update]
+async fn top_up_user_canister() -> Result<u128, UserCanisterError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::top_up_user_canister(caller).await
+}
+
+#[update]
+async fn export_user_canister() -> Result<Principal, UserCanisterError> {
+    let caller = ic_cdk::caller();
+    vtk_backend::api::export_user_canister(caller).await
+}
+
+// The caller of an admin endpoint, and whether it controls this canister, for `require_admin`.
+fn admin_caller() -> (Principal, bool) {
+    let caller = ic_cdk::caller();
+    (caller, ic_cdk::api::is_controller(&caller))
+}
+
 fn main() {}
\ No newline at end of file
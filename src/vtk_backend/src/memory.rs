@@ -4,7 +4,14 @@ use std::cell::RefCell;
 
 // const UPGRADES: MemoryId = MemoryId::new(0);
 const FILE_CONTENTS: MemoryId = MemoryId::new(1);
-// const USER_CANISTERS: MemoryId = MemoryId::new(3); // Add new MemoryId
+const USER_CANISTERS: MemoryId = MemoryId::new(3);
+const CHUNK_REFS: MemoryId = MemoryId::new(4);
+const CHUNK_BLOBS: MemoryId = MemoryId::new(5);
+const CHUNK_REF_COUNTS: MemoryId = MemoryId::new(6);
+const CHUNK_STORE_MODE: MemoryId = MemoryId::new(7);
+const ADMINS: MemoryId = MemoryId::new(8);
+const BUCKETS: MemoryId = MemoryId::new(9);
+const BUCKET_RESERVATIONS: MemoryId = MemoryId::new(10);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -21,7 +28,34 @@ pub fn get_file_contents_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(FILE_CONTENTS))
 }
 
-// Add function to get memory for the user canisters map
-// pub fn get_user_canisters_memory() -> Memory {
-//     MEMORY_MANAGER.with(|m| m.borrow().get(USER_CANISTERS))
-// }
+pub fn get_user_canisters_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(USER_CANISTERS))
+}
+
+pub fn get_chunk_refs_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_REFS))
+}
+
+pub fn get_chunk_blobs_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_BLOBS))
+}
+
+pub fn get_chunk_ref_counts_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_REF_COUNTS))
+}
+
+pub fn get_chunk_store_mode_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_STORE_MODE))
+}
+
+pub fn get_admins_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ADMINS))
+}
+
+pub fn get_buckets_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(BUCKETS))
+}
+
+pub fn get_bucket_reservations_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(BUCKET_RESERVATIONS))
+}
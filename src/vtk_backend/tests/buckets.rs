@@ -0,0 +1,84 @@
+//! Scaling out to bucket canisters, run against a local PocketIC instance.
+//!
+//! Build the canister first and point the test at the wasm:
+//!
+//! ```sh
+//! cargo build --release --target wasm32-unknown-unknown -p vtk_backend
+//! VTK_BACKEND_WASM=target/wasm32-unknown-unknown/release/vtk_backend.wasm \
+//!     cargo test -p vtk_backend --test buckets -- --ignored
+//! ```
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use serde::de::DeserializeOwned;
+use vtk_backend::api::{AdminError, BucketInfo, UploadFileAtomicRequest};
+use vtk_backend::{FileDownloadResponse, InitArgs, UploadFileError};
+
+fn load_wasm() -> Vec<u8> {
+    let path = std::env::var("VTK_BACKEND_WASM").expect("VTK_BACKEND_WASM must point to the built wasm");
+    std::fs::read(path).expect("failed to read wasm")
+}
+
+fn update<T: DeserializeOwned + CandidType>(
+    pic: &PocketIc,
+    canister: Principal,
+    sender: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> T {
+    match pic.update_call(canister, sender, method, args).expect("call failed") {
+        WasmResult::Reply(bytes) => decode_one(&bytes).unwrap(),
+        WasmResult::Reject(msg) => panic!("{method} rejected: {msg}"),
+    }
+}
+
+#[test]
+#[ignore = "needs a PocketIC server and a built vtk_backend wasm"]
+fn new_files_are_placed_in_and_served_from_a_bucket() {
+    let pic = PocketIc::new();
+    let admin = Principal::from_slice(&[1; 29]);
+    let user = Principal::from_slice(&[2; 29]);
+    let wasm = load_wasm();
+
+    let index = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(index, 20_000_000_000_000);
+    pic.install_canister(index, wasm.clone(), encode_one(Some(InitArgs::default())).unwrap(), Some(admin));
+
+    let set: Result<(), AdminError> = update(&pic, index, admin, "set_bucket_wasm", encode_one(wasm).unwrap());
+    set.unwrap();
+    let bucket: Result<Principal, AdminError> = update(&pic, index, admin, "create_bucket", encode_args(()).unwrap());
+    let bucket = bucket.unwrap();
+
+    let file_id: Result<u64, UploadFileError> = update(
+        &pic,
+        index,
+        user,
+        "upload_file_atomic",
+        encode_one(UploadFileAtomicRequest {
+            name: "notes.txt".to_string(),
+            content: vec![1, 2, 3],
+            file_type: "txt".to_string(),
+            num_chunks: 1,
+        })
+        .unwrap(),
+    );
+    let file_id = file_id.unwrap();
+    // Let the one-way messages to the bucket be delivered.
+    for _ in 0..5 {
+        pic.tick();
+    }
+
+    let location: FileDownloadResponse =
+        update(&pic, index, user, "download_file", encode_args((file_id, 0u64)).unwrap());
+    match location {
+        FileDownloadResponse::StoredInBucket(location) => assert_eq!(location.canister_id, bucket),
+        other => panic!("expected the file to be in a bucket, got {other:?}"),
+    }
+
+    let chunk: Result<Option<Vec<u8>>, vtk_backend::api::BucketError> =
+        update(&pic, bucket, user, "bucket_get_chunk", encode_args((file_id, 0u64)).unwrap());
+    assert_eq!(chunk, Ok(Some(vec![1, 2, 3])));
+
+    let buckets: Result<Vec<BucketInfo>, AdminError> = update(&pic, index, admin, "list_buckets", encode_args(()).unwrap());
+    assert_eq!(buckets.unwrap()[0].file_count, 1);
+}
@@ -20,6 +20,15 @@ const CHUNK_SIZE = 2 * 1024 * 1024;
 
 // Default Walrus publisher endpoint for API upload
 const DEFAULT_PUBLISHER_API = "https://publisher.walrus-testnet.walrus.space/v1/blobs";
+
+// Backend errors are Candid variants such as `{ suspended: null }` or `{ rpc_error: "..." }`.
+function describeBackendError(err: Record<string, unknown>): string {
+  const [name, detail] = Object.entries(err)[0] ?? ["unknown_error", null];
+  if (detail !== null && typeof detail === "object") {
+    return `${name}: ${describeBackendError(detail as Record<string, unknown>)}`;
+  }
+  return detail == null ? name : `${name}: ${detail}`;
+}
 export default function FileUpload({ actor }: { actor: any }) {
 
   const fileInputRef = useRef<HTMLInputElement | null>(null);
@@ -67,7 +76,7 @@ export default function FileUpload({ actor }: { actor: any }) {
         const totalChunks = Math.ceil(file.size / CHUNK_SIZE);
         const num_chunks = BigInt(totalChunks);
 
-        let backendFileId = null;
+        let backendFileId: bigint | null = null;
         for (let i = 0; i < totalChunks; i++) {
           const start = i * CHUNK_SIZE;
           const end = Math.min(start + CHUNK_SIZE, file.size);
@@ -83,19 +92,26 @@ export default function FileUpload({ actor }: { actor: any }) {
               num_chunks,
             };
             console.log("upload_file_atomic args:", uploadArgs);
-            const backendFileId = await actor.upload_file_atomic(uploadArgs);
+            const uploaded = await actor.upload_file_atomic(uploadArgs);
+            if ("Err" in uploaded) {
+              throw new Error(`Upload failed: ${describeBackendError(uploaded.Err)}`);
+            }
+            backendFileId = uploaded.Ok;
           } else {
             if (backendFileId == null) {
               setError("No file ID from backend for chunk upload.");
               setIsUploading(false);
               return;
             }
-            await vtk_backend.upload_file_continue({
+            const continued = await vtk_backend.upload_file_continue({
               file_id: backendFileId,
               file_type: file.type || "application/octet-stream",
               num_chunks: BigInt(totalChunks),
               file_content: new Uint8Array(buffer),
             });
+            if ("Err" in continued) {
+              throw new Error(`Upload failed: ${describeBackendError(continued.Err)}`);
+            }
           }
 
           setProgress(Math.round(((i + 1) / totalChunks) * 100));
@@ -127,7 +143,7 @@ export default function FileUpload({ actor }: { actor: any }) {
           const bytes = Uint8Array.from(Buffer.from(secretKey, "base64"));
           if (bytes.length !== 32) throw new Error("Secret key must be 32 bytes");
           const keypair = Ed25519Keypair.fromSecretKey(bytes);
-          const { blobId } = await walrusClient.writeBlob({
+          const { blobId, blobObject } = await walrusClient.writeBlob({
             blob,
             deletable: false,
             epochs: 3,
@@ -136,13 +152,17 @@ export default function FileUpload({ actor }: { actor: any }) {
           console.log("Uploaded to Walrus with blobId:", blobId);
           // Register in backend
           const now = BigInt(Date.now());
-          await vtk_backend.register_file({
+          const registered = await vtk_backend.register_file({
             file_name: file.name,
             storage_provider: "walrus",
             blob_id: [blobId],
             requested_at: now,
             uploaded_at: [now],
+            sui_object_id: blobObject?.id?.id ? [blobObject.id.id] : [],
           });
+          if ("Err" in registered) {
+            throw new Error(`Registering the file failed: ${describeBackendError(registered.Err)}`);
+          }
           setProgress(100);
         } else if (method === "API") {
           // API upload logic (basic, hardcoded endpoint)
@@ -165,15 +185,21 @@ export default function FileUpload({ actor }: { actor: any }) {
           // Try to extract blobId from result
           const blobId = result?.newlyCreated?.blobObject?.blobId || result?.alreadyCertified?.blobId;
           if (!blobId) throw new Error("No blobId returned from Walrus API");
+          // Only a newly created blob comes with its Sui object ID.
+          const suiObjectId = result?.newlyCreated?.blobObject?.id;
           // Register in backend
           const now = BigInt(Date.now());
-          await vtk_backend.register_file({
+          const registered = await vtk_backend.register_file({
             file_name: file.name,
             storage_provider: "walrus",
             blob_id: [blobId],
             requested_at: now,
             uploaded_at: [now],
+            sui_object_id: suiObjectId ? [suiObjectId] : [],
           });
+          if ("Err" in registered) {
+            throw new Error(`Registering the file failed: ${describeBackendError(registered.Err)}`);
+          }
           setProgress(100);
         }
       }